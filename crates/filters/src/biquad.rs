@@ -0,0 +1,174 @@
+use std::f32::consts::PI;
+
+use anyhow::anyhow;
+use libplunder::prelude::filter::*;
+use serde::Deserialize;
+
+use crate::DEFAULT_SAMPLE_RATE;
+
+#[derive(Debug, Clone, Copy)]
+enum Kind {
+    Lowpass,
+    Highpass,
+}
+
+/// A second-order low/high-pass filter (RBJ cookbook), one transposed direct-form II per channel
+#[derive(Debug)]
+pub struct Biquad {
+    kind: Kind,
+    freq: f32,
+    q: f32,
+    sample_rate: u32,
+    /// `b0, b1, b2, a1, a2` normalized by `a0`
+    coefficients: [f32; 5],
+    /// `z1, z2` of every channel
+    state: Vec<[f32; 2]>,
+}
+
+fn default_q() -> f32 {
+    std::f32::consts::FRAC_1_SQRT_2
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BiquadArgs {
+    freq: f32,
+    #[serde(default = "default_q")]
+    q: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BiquadControls {
+    Freq(f32),
+    Q(f32),
+}
+
+impl Biquad {
+    fn update(&mut self) {
+        // Keep the cutoff below nyquist so the filter stays stable
+        let freq = self.freq.min(self.sample_rate as f32 * 0.49);
+        let w0 = 2. * PI * freq / self.sample_rate as f32;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2. * self.q);
+        let (b0, b1) = match self.kind {
+            Kind::Lowpass => ((1. - cos) / 2., 1. - cos),
+            Kind::Highpass => ((1. + cos) / 2., -(1. + cos)),
+        };
+        let a0 = 1. + alpha;
+        self.coefficients = [b0 / a0, b1 / a0, b0 / a0, -2. * cos / a0, (1. - alpha) / a0];
+    }
+}
+
+fn positive(name: &str, value: f32) -> anyhow::Result<f32> {
+    if value > 0. {
+        Ok(value)
+    } else {
+        Err(anyhow!("`{name}` must be positive, not `{value}`"))
+    }
+}
+
+impl State<BiquadArgs, BiquadControls> for Biquad {
+    type TErr = anyhow::Error;
+    type IErr = anyhow::Error;
+
+    fn transform(&mut self, event: BiquadControls) -> Result<(), anyhow::Error> {
+        match event {
+            BiquadControls::Freq(freq) => self.freq = positive("freq", freq)?,
+            BiquadControls::Q(q) => self.q = positive("q", q)?,
+        }
+        self.update();
+        Ok(())
+    }
+
+    fn initialize(route: &str, arguments: BiquadArgs) -> Result<Self, anyhow::Error> {
+        let kind = match route {
+            "lowpass" => Kind::Lowpass,
+            "highpass" => Kind::Highpass,
+            _ => {
+                return Err(anyhow!(
+                    "invalid route `{route}`. available: `lowpass` and `highpass`"
+                ))
+            }
+        };
+        let mut biquad = Biquad {
+            kind,
+            freq: positive("freq", arguments.freq)?,
+            q: positive("q", arguments.q)?,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            coefficients: [0.; 5],
+            state: Vec::new(),
+        };
+        biquad.update();
+        Ok(biquad)
+    }
+}
+
+impl Process for Biquad {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.state.clear();
+        self.update();
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        let [b0, b1, b2, a1, a2] = self.coefficients;
+        self.state.resize(frame.len(), [0.; 2]);
+        for (x, z) in frame.iter_mut().zip(self.state.iter_mut()) {
+            let y = b0 * *x + z[0];
+            z[0] = b1 * *x - a1 * y + z[1];
+            z[1] = b2 * *x - a2 * y;
+            *x = y;
+        }
+    }
+
+    fn ringing(&self) -> bool {
+        self.state.iter().flatten().any(|z| z.abs() > TAIL_FLOOR)
+    }
+}
+
+impl Filter<BiquadArgs, BiquadControls> for Biquad {
+    fn help(&self) -> String {
+        format!("{:?} at {} Hz, q: {}", self.kind, self.freq, self.q)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peak(biquad: &mut Biquad, signal: impl Fn(usize) -> f32) -> f32 {
+        (0..4410)
+            .map(|i| {
+                let mut frame = [signal(i)];
+                biquad.process(&mut frame);
+                frame[0]
+            })
+            .skip(2205)
+            .fold(0., |peak, s| peak.max(s.abs()))
+    }
+
+    #[test]
+    fn lowpass_passes_dc_and_cuts_nyquist() {
+        let args = BiquadArgs {
+            freq: 1000.,
+            q: default_q(),
+        };
+        let mut lowpass = Biquad::initialize("lowpass", args.clone()).unwrap();
+        assert!((peak(&mut lowpass, |_| 1.) - 1.).abs() < 1e-3);
+        let mut lowpass = Biquad::initialize("lowpass", args).unwrap();
+        assert!(peak(&mut lowpass, |i| if i % 2 == 0 { 1. } else { -1. }) < 1e-3);
+    }
+
+    #[test]
+    fn highpass_cuts_dc() {
+        let mut highpass = Biquad::initialize(
+            "highpass",
+            BiquadArgs {
+                freq: 1000.,
+                q: default_q(),
+            },
+        )
+        .unwrap();
+        assert!(peak(&mut highpass, |_| 1.) < 1e-3);
+    }
+}
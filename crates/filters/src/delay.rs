@@ -0,0 +1,291 @@
+use anyhow::anyhow;
+use libplunder::prelude::filter::*;
+use serde::Deserialize;
+
+use crate::{unit, DEFAULT_SAMPLE_RATE};
+
+/// A feedback delay, one delay-line per channel
+#[derive(Debug)]
+pub struct Delay {
+    /// Seconds between echoes
+    time: f32,
+    feedback: f32,
+    mix: f32,
+    sample_rate: u32,
+    lines: Vec<Vec<f32>>,
+    cursor: usize,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DelayArgs {
+    time: f32,
+    feedback: f32,
+    mix: f32,
+}
+
+impl Default for DelayArgs {
+    fn default() -> Self {
+        DelayArgs {
+            time: 0.25,
+            feedback: 0.35,
+            mix: 0.3,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DelayControls {
+    Time(f32),
+    Feedback(f32),
+    Mix(f32),
+}
+
+fn time(time: f32) -> anyhow::Result<f32> {
+    if time > 0. {
+        Ok(time)
+    } else {
+        Err(anyhow!("`time` must be a positive number of seconds"))
+    }
+}
+
+fn feedback(feedback: f32) -> anyhow::Result<f32> {
+    if (0. ..1.).contains(&feedback) {
+        Ok(feedback)
+    } else {
+        Err(anyhow!(
+            "`feedback` must be at least 0 and less than 1, not `{feedback}`"
+        ))
+    }
+}
+
+impl Delay {
+    fn length(&self) -> usize {
+        ((self.time * self.sample_rate as f32) as usize).max(1)
+    }
+}
+
+impl State<Option<DelayArgs>, DelayControls> for Delay {
+    type TErr = anyhow::Error;
+    type IErr = anyhow::Error;
+
+    fn transform(&mut self, event: DelayControls) -> Result<(), anyhow::Error> {
+        match event {
+            DelayControls::Time(to) => {
+                self.time = time(to)?;
+                // Echoes already in flight are dropped
+                self.lines.clear();
+                self.cursor = 0;
+            }
+            DelayControls::Feedback(to) => self.feedback = feedback(to)?,
+            DelayControls::Mix(to) => self.mix = unit("mix", to)?,
+        }
+        Ok(())
+    }
+
+    fn initialize(route: &str, arguments: Option<DelayArgs>) -> Result<Self, anyhow::Error> {
+        match route {
+            "new" => {
+                let arguments = arguments.unwrap_or_default();
+                Ok(Delay {
+                    time: time(arguments.time)?,
+                    feedback: feedback(arguments.feedback)?,
+                    mix: unit("mix", arguments.mix)?,
+                    sample_rate: DEFAULT_SAMPLE_RATE,
+                    lines: Vec::new(),
+                    cursor: 0,
+                })
+            }
+            _ => Err(anyhow!("invalid route `{route}`. available: `new`")),
+        }
+    }
+}
+
+impl Process for Delay {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.lines.clear();
+        self.cursor = 0;
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        let length = self.length();
+        self.lines.resize_with(frame.len(), || vec![0.; length]);
+        for (x, line) in frame.iter_mut().zip(self.lines.iter_mut()) {
+            let delayed = line[self.cursor];
+            line[self.cursor] = *x + delayed * self.feedback;
+            *x = *x * (1. - self.mix) + delayed * self.mix;
+        }
+        self.cursor = (self.cursor + 1) % length;
+    }
+
+    fn ringing(&self) -> bool {
+        self.lines.iter().flatten().any(|s| s.abs() > TAIL_FLOOR)
+    }
+}
+
+impl Filter<Option<DelayArgs>, DelayControls> for Delay {
+    fn help(&self) -> String {
+        format!(
+            "delay of {}s, feedback: {}, mix: {}",
+            self.time, self.feedback, self.mix
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+
+    use libplunder::{
+        instrument::{InstrumentError, PlunderInstrument, SourceError},
+        mixdown::Mixdown,
+        mixer::MixerOptions,
+        prelude::instrument::{PackagedInstrument, Sample, SharedPlunderInstrument},
+        tempo::TempoMap,
+    };
+
+    use super::*;
+
+    #[test]
+    fn echoes_after_time() {
+        let mut delay = Delay::initialize(
+            "new",
+            Some(DelayArgs {
+                time: 0.004,
+                feedback: 0.5,
+                mix: 1.,
+            }),
+        )
+        .unwrap();
+        delay.prepare(1000);
+        let out: Vec<f32> = (0..12)
+            .map(|i| {
+                let mut frame = [if i == 0 { 1. } else { 0. }];
+                delay.process(&mut frame);
+                frame[0]
+            })
+            .collect();
+        assert_eq!(out, vec![0., 0., 0., 0., 1., 0., 0., 0., 0.5, 0., 0., 0.]);
+    }
+
+    /// A single full-scale sample
+    #[derive(Debug, Default)]
+    struct Click(RwLock<bool>);
+
+    impl PlunderInstrument for Click {
+        fn next_sample(&self) -> Result<Option<Sample>, SourceError<String>> {
+            let done = std::mem::replace(&mut *self.0.write().unwrap(), true);
+            Ok((!done).then(|| Sample::F32(vec![1.])))
+        }
+
+        fn sample_rate(&self) -> Option<u32> {
+            None
+        }
+
+        fn prepare(&self, _: u32) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn tempo(&self, _: f64) {}
+
+        fn unit(&self, _: f64) {}
+
+        fn channels(&self) -> Option<usize> {
+            None
+        }
+
+        fn fill_buffer(&self, _: &mut [f32], _: usize) -> Result<usize, SourceError<String>> {
+            unimplemented!()
+        }
+
+        fn transform(&self, event: mlua::Value) -> Result<(), InstrumentError> {
+            // Dropping a Lua value needs a Lua state, which tests don't link
+            std::mem::forget(event);
+            unimplemented!()
+        }
+
+        fn help(&self) -> String {
+            String::new()
+        }
+    }
+
+    /// A [`Delay`](Delay) as a filter, without going through Lua
+    #[derive(Debug)]
+    struct Bus(RwLock<Delay>);
+
+    impl PlunderFilter for Bus {
+        fn prepare(&self, sample_rate: u32) {
+            self.0.write().unwrap().prepare(sample_rate)
+        }
+
+        fn process(&self, frame: &mut [f32]) {
+            self.0.write().unwrap().process(frame)
+        }
+
+        fn channels(&self, input: usize) -> usize {
+            self.0.read().unwrap().channels(input)
+        }
+
+        fn ringing(&self) -> bool {
+            self.0.read().unwrap().ringing()
+        }
+
+        fn transform(&self, event: mlua::Value) -> Result<(), InstrumentError> {
+            std::mem::forget(event);
+            unimplemented!()
+        }
+
+        fn help(&self) -> String {
+            String::new()
+        }
+    }
+
+    fn mixdown(sample_bound: usize) -> Vec<Vec<f64>> {
+        let instrument = PackagedInstrument {
+            factory: SharedPlunderInstrument(Arc::new(Click::default())),
+            manual: Arc::from(""),
+            filters: FilterChain::default(),
+        };
+        let delay = Delay::initialize(
+            "new",
+            Some(DelayArgs {
+                time: 0.004,
+                feedback: 0.5,
+                mix: 1.,
+            }),
+        )
+        .unwrap();
+        let master = FilterChain::new(vec![SharedPlunderFilter(Arc::new(Bus(RwLock::new(delay))))]);
+        let options = MixerOptions {
+            channels: Some(1),
+            ..MixerOptions::default()
+        };
+        Mixdown::new(
+            vec![instrument],
+            (master, &options),
+            std::iter::empty(),
+            1000,
+            TempoMap::from_interval(100, 1000).unwrap(),
+            sample_bound,
+        )
+        .unwrap()
+        .map(Result::unwrap)
+        .collect()
+    }
+
+    #[test]
+    fn echoes_on_the_master_bus_after_instruments_finish() {
+        // The click is a single frame, its echoes ring out on the master bus after it
+        let frames = mixdown(100);
+        assert_eq!(frames.len(), 100);
+        assert_eq!(frames[0], vec![0.]);
+        assert_eq!(frames[4], vec![1.]);
+        assert_eq!(frames[8], vec![0.5]);
+        // Until a check finds the tail has decayed
+        let frames = mixdown(10_000);
+        assert_eq!(frames.len(), 1 + 1024);
+        assert!(frames[1000][0].abs() < TAIL_FLOOR as f64);
+    }
+}
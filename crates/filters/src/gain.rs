@@ -0,0 +1,57 @@
+use anyhow::anyhow;
+use libplunder::prelude::filter::*;
+use serde::Deserialize;
+
+/// Scales every channel by a constant factor
+#[derive(Debug)]
+pub struct Gain {
+    gain: f32,
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GainControls {
+    Db(f32),
+    Linear(f32),
+}
+
+impl State<f32, GainControls> for Gain {
+    type TErr = anyhow::Error;
+    type IErr = anyhow::Error;
+
+    fn transform(&mut self, event: GainControls) -> Result<(), anyhow::Error> {
+        self.gain = match event {
+            GainControls::Db(db) => db_to_linear(db),
+            GainControls::Linear(gain) => gain,
+        };
+        Ok(())
+    }
+
+    fn initialize(route: &str, arguments: f32) -> Result<Self, anyhow::Error> {
+        match route {
+            "db" => Ok(Gain {
+                gain: db_to_linear(arguments),
+            }),
+            "linear" => Ok(Gain { gain: arguments }),
+            _ => Err(anyhow!(
+                "invalid route `{route}`. available: `db` and `linear`"
+            )),
+        }
+    }
+}
+
+impl Process for Gain {
+    fn process(&mut self, frame: &mut [f32]) {
+        frame.iter_mut().for_each(|s| *s *= self.gain);
+    }
+}
+
+impl Filter<f32, GainControls> for Gain {
+    fn help(&self) -> String {
+        format!("gain of {:.2} dB", 20. * self.gain.log10())
+    }
+}
@@ -0,0 +1,60 @@
+use anyhow::anyhow;
+use libplunder::filter::package_filter;
+
+mod biquad;
+mod delay;
+mod gain;
+mod pan;
+mod reverb;
+pub use biquad::Biquad;
+use biquad::{BiquadArgs, BiquadControls};
+pub use delay::Delay;
+use delay::{DelayArgs, DelayControls};
+pub use gain::Gain;
+use gain::GainControls;
+pub use pan::Pan;
+use pan::PanControls;
+pub use reverb::Reverb;
+use reverb::{ReverbArgs, ReverbControls};
+
+impl Gain {
+    pub fn package(lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        package_filter::<Self, f32, GainControls>(lua, "gain".to_string())
+    }
+}
+
+impl Pan {
+    pub fn package(lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        package_filter::<Self, f32, PanControls>(lua, "pan".to_string())
+    }
+}
+
+impl Biquad {
+    pub fn package(lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        package_filter::<Self, BiquadArgs, BiquadControls>(lua, "biquad".to_string())
+    }
+}
+
+impl Delay {
+    pub fn package(lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        package_filter::<Self, Option<DelayArgs>, DelayControls>(lua, "delay".to_string())
+    }
+}
+
+impl Reverb {
+    pub fn package(lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
+        package_filter::<Self, Option<ReverbArgs>, ReverbControls>(lua, "reverb".to_string())
+    }
+}
+
+/// Check that a parameter lies in `0.0..=1.0`
+fn unit(name: &str, value: f32) -> anyhow::Result<f32> {
+    if (0. ..=1.).contains(&value) {
+        Ok(value)
+    } else {
+        Err(anyhow!("`{name}` must be between 0 and 1, not `{value}`"))
+    }
+}
+
+/// Sample-rate assumed until a filter is prepared
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
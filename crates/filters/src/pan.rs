@@ -0,0 +1,84 @@
+use anyhow::anyhow;
+use libplunder::prelude::filter::*;
+use serde::Deserialize;
+
+/// Balances a stereo frame between its left (`-1`) and right (`1`) channels. Mono instruments are
+/// up-mixed to stereo to be panned, frames with more channels pass through untouched
+#[derive(Debug)]
+pub struct Pan {
+    position: f32,
+}
+
+fn position(position: f32) -> anyhow::Result<f32> {
+    if (-1. ..=1.).contains(&position) {
+        Ok(position)
+    } else {
+        Err(anyhow!(
+            "pan position must be between -1 and 1, not `{position}`"
+        ))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PanControls {
+    To(f32),
+}
+
+impl State<f32, PanControls> for Pan {
+    type TErr = anyhow::Error;
+    type IErr = anyhow::Error;
+
+    fn transform(&mut self, event: PanControls) -> Result<(), anyhow::Error> {
+        match event {
+            PanControls::To(to) => self.position = position(to)?,
+        }
+        Ok(())
+    }
+
+    fn initialize(route: &str, arguments: f32) -> Result<Self, anyhow::Error> {
+        match route {
+            "new" => Ok(Pan {
+                position: position(arguments)?,
+            }),
+            _ => Err(anyhow!("invalid route `{route}`. available: `new`")),
+        }
+    }
+}
+
+impl Process for Pan {
+    fn process(&mut self, frame: &mut [f32]) {
+        if let [left, right] = frame {
+            *left *= (1. - self.position).min(1.);
+            *right *= (1. + self.position).min(1.);
+        }
+    }
+
+    fn channels(&self, input: usize) -> usize {
+        input.max(2)
+    }
+}
+
+impl Filter<f32, PanControls> for Pan {
+    fn help(&self) -> String {
+        format!("pan at {:.2}", self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pans_stereo_and_upmixes_mono() {
+        let mut pan = Pan::initialize("new", -0.5).unwrap();
+        let mut frame = [1., 1.];
+        pan.process(&mut frame);
+        assert_eq!(frame, [1., 0.5]);
+
+        assert_eq!(pan.channels(1), 2);
+        assert_eq!(pan.channels(2), 2);
+        assert_eq!(pan.channels(6), 6);
+        assert!(Pan::initialize("new", 1.5).is_err());
+    }
+}
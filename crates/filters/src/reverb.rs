@@ -0,0 +1,245 @@
+use anyhow::anyhow;
+use libplunder::prelude::filter::*;
+use serde::Deserialize;
+
+use crate::{unit, DEFAULT_SAMPLE_RATE};
+
+/// Delay-line lengths of Freeverb's comb & all-pass filters, tuned for 44.1kHz
+const COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+/// Added to the tunings of every other channel to decorrelate them
+const STEREO_SPREAD: usize = 23;
+const INPUT_GAIN: f32 = 0.015;
+const WET_GAIN: f32 = 3.;
+
+#[derive(Debug)]
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    store: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damp: f32) -> f32 {
+        let output = self.buffer[self.index];
+        self.store = output * (1. - damp) + self.store * damp;
+        self.buffer[self.index] = input + self.store * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+#[derive(Debug)]
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let buffered = self.buffer[self.index];
+        self.buffer[self.index] = input + buffered * 0.5;
+        self.index = (self.index + 1) % self.buffer.len();
+        buffered - input
+    }
+}
+
+/// The parallel combs and serial all-passes of a single channel
+#[derive(Debug)]
+struct Tank {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl Tank {
+    fn new(sample_rate: u32, spread: usize) -> Self {
+        let scale = |tuning: usize| {
+            ((tuning + spread) as f32 * sample_rate as f32 / 44100.).max(1.) as usize
+        };
+        Tank {
+            combs: COMB_TUNINGS
+                .iter()
+                .map(|tuning| Comb {
+                    buffer: vec![0.; scale(*tuning)],
+                    index: 0,
+                    store: 0.,
+                })
+                .collect(),
+            allpasses: ALLPASS_TUNINGS
+                .iter()
+                .map(|tuning| Allpass {
+                    buffer: vec![0.; scale(*tuning)],
+                    index: 0,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A simple Schroeder-style reverb modelled on Freeverb, one tank per channel
+#[derive(Debug)]
+pub struct Reverb {
+    room: f32,
+    damp: f32,
+    mix: f32,
+    sample_rate: u32,
+    tanks: Vec<Tank>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReverbArgs {
+    room: f32,
+    damp: f32,
+    mix: f32,
+}
+
+impl Default for ReverbArgs {
+    fn default() -> Self {
+        ReverbArgs {
+            room: 0.5,
+            damp: 0.5,
+            mix: 0.25,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReverbControls {
+    Room(f32),
+    Damp(f32),
+    Mix(f32),
+}
+
+impl State<Option<ReverbArgs>, ReverbControls> for Reverb {
+    type TErr = anyhow::Error;
+    type IErr = anyhow::Error;
+
+    fn transform(&mut self, event: ReverbControls) -> Result<(), anyhow::Error> {
+        match event {
+            ReverbControls::Room(to) => self.room = unit("room", to)?,
+            ReverbControls::Damp(to) => self.damp = unit("damp", to)?,
+            ReverbControls::Mix(to) => self.mix = unit("mix", to)?,
+        }
+        Ok(())
+    }
+
+    fn initialize(route: &str, arguments: Option<ReverbArgs>) -> Result<Self, anyhow::Error> {
+        match route {
+            "new" => {
+                let arguments = arguments.unwrap_or_default();
+                Ok(Reverb {
+                    room: unit("room", arguments.room)?,
+                    damp: unit("damp", arguments.damp)?,
+                    mix: unit("mix", arguments.mix)?,
+                    sample_rate: DEFAULT_SAMPLE_RATE,
+                    tanks: Vec::new(),
+                })
+            }
+            _ => Err(anyhow!("invalid route `{route}`. available: `new`")),
+        }
+    }
+}
+
+impl Process for Reverb {
+    fn prepare(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.tanks.clear();
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        while self.tanks.len() < frame.len() {
+            let spread = STEREO_SPREAD * (self.tanks.len() % 2);
+            self.tanks.push(Tank::new(self.sample_rate, spread));
+        }
+        let feedback = self.room * 0.28 + 0.7;
+        let damp = self.damp * 0.4;
+        for (x, tank) in frame.iter_mut().zip(self.tanks.iter_mut()) {
+            let input = *x * INPUT_GAIN;
+            let wet = tank
+                .combs
+                .iter_mut()
+                .map(|comb| comb.process(input, feedback, damp))
+                .sum::<f32>();
+            let wet = tank
+                .allpasses
+                .iter_mut()
+                .fold(wet, |wet, allpass| allpass.process(wet));
+            *x = *x * (1. - self.mix) + wet * WET_GAIN * self.mix;
+        }
+    }
+
+    fn ringing(&self) -> bool {
+        self.tanks.iter().any(|tank| {
+            tank.combs
+                .iter()
+                .flat_map(|comb| comb.buffer.iter().chain([&comb.store]))
+                .chain(tank.allpasses.iter().flat_map(|allpass| &allpass.buffer))
+                .any(|s| s.abs() > TAIL_FLOOR)
+        })
+    }
+}
+
+impl Filter<Option<ReverbArgs>, ReverbControls> for Reverb {
+    fn help(&self) -> String {
+        format!(
+            "reverb with room: {}, damp: {}, mix: {}",
+            self.room, self.damp, self.mix
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reverb(mix: f32) -> Reverb {
+        let mut reverb = Reverb::initialize(
+            "new",
+            Some(ReverbArgs {
+                room: 0.5,
+                damp: 0.5,
+                mix,
+            }),
+        )
+        .unwrap();
+        reverb.prepare(44100);
+        reverb
+    }
+
+    #[test]
+    fn dry_when_unmixed() {
+        let mut reverb = reverb(0.);
+        for x in [0.5, -0.25, 0., 1.] {
+            let mut frame = [x, x];
+            reverb.process(&mut frame);
+            assert_eq!(frame, [x, x]);
+        }
+    }
+
+    #[test]
+    fn impulse_rings_out_then_decays() {
+        let mut reverb = reverb(1.);
+        let mut frame = [1., 1.];
+        reverb.process(&mut frame);
+        assert!(reverb.ringing());
+
+        // Every other channel is tuned apart so that the tail is wide
+        let tail: Vec<[f32; 2]> = (0..44100)
+            .map(|_| {
+                let mut frame = [0., 0.];
+                reverb.process(&mut frame);
+                frame
+            })
+            .collect();
+        assert!(tail.iter().any(|[left, _]| left.abs() > 0.01));
+        assert!(tail.iter().any(|[left, right]| left != right));
+
+        let decayed = (0..44100 * 10).position(|_| {
+            reverb.process(&mut [0., 0.]);
+            !reverb.ringing()
+        });
+        assert!(decayed.is_some());
+    }
+}
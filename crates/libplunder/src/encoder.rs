@@ -0,0 +1,496 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use flacenc::{
+    bitsink::ByteSink,
+    component::{BitRepr, Stream},
+    error::{Verified, Verify, VerifyError},
+    source::{self, Fill, FrameBuf},
+};
+use log::info;
+use serde::Deserialize;
+
+/// Properties of the audio stream handed to an [`Encoder`](Encoder) that are fixed for the
+/// lifetime of the encoder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncoderSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Container {
+    Wav,
+    Flac,
+}
+
+impl Container {
+    /// Guess the container from the extension of the path being written to
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        match path
+            .as_ref()
+            .extension()?
+            .to_str()?
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "wav" | "wave" => Some(Container::Wav),
+            "flac" => Some(Container::Flac),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    Int,
+    Float,
+}
+
+/// User-facing options that pick an encoder, deserialized from the options table of `render`
+///
+/// Everything is optional: the container is guessed from the path, WAV defaults to 32-bit int
+/// and FLAC to 24-bit int
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EncoderOptions {
+    pub format: Option<Container>,
+    pub bits: Option<u16>,
+    pub sample_format: Option<SampleFormat>,
+}
+
+/// An Encoder consumes frames of normalized samples (one per channel, nominally in
+/// `-1.0..=1.0`) and writes them in its own format
+///
+/// Out-of-range samples are clipped by the encoders that cannot represent them
+pub trait Encoder {
+    fn write_frame(&mut self, frame: &[f64]) -> anyhow::Result<()>;
+    fn finalize(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+/// Create the encoder described by `options` that writes to a newly created file at `path`
+pub fn create<P>(
+    path: P,
+    spec: EncoderSpec,
+    options: &EncoderOptions,
+) -> anyhow::Result<Box<dyn Encoder>>
+where
+    P: AsRef<Path>,
+{
+    let container = match options.format {
+        Some(container) => container,
+        None => Container::from_path(&path).unwrap_or(Container::Wav),
+    };
+    let sample_format = options.sample_format.unwrap_or(SampleFormat::Int);
+    info!(
+        "Creating {container:?} encoder for `{}`: {spec:?}, bits:`{:?}`, format:`{sample_format:?}`",
+        path.as_ref().display(),
+        options.bits,
+    );
+
+    let file = File::create(&path)
+        .with_context(|| format!("while creating `{}`", path.as_ref().display()))?;
+    let writer = BufWriter::new(file);
+    Ok(match container {
+        Container::Wav => Box::new(WavEncoder::new(
+            writer,
+            spec,
+            options.bits.unwrap_or(32),
+            sample_format,
+        )?),
+        Container::Flac => {
+            if sample_format == SampleFormat::Float {
+                return Err(anyhow!("FLAC can only store integer samples"));
+            }
+            Box::new(FlacEncoder::new(writer, spec, options.bits.unwrap_or(24))?)
+        }
+    })
+}
+
+/// Quantize a normalized sample to a signed integer of `bits` bits, clipping if out of range
+pub fn quantize(sample: f64, bits: u16) -> i32 {
+    let scale = (1i64 << (bits - 1)) as f64;
+    (sample * scale).round().clamp(-scale, scale - 1.) as i32
+}
+
+pub struct WavEncoder<W>
+where
+    W: Write + Seek,
+{
+    writer: hound::WavWriter<W>,
+    bits: u16,
+    sample_format: SampleFormat,
+}
+
+impl<W> WavEncoder<W>
+where
+    W: Write + Seek,
+{
+    pub fn new(
+        writer: W,
+        spec: EncoderSpec,
+        bits: u16,
+        sample_format: SampleFormat,
+    ) -> anyhow::Result<Self> {
+        match (sample_format, bits) {
+            (SampleFormat::Int, 8 | 16 | 24 | 32) | (SampleFormat::Float, 32) => (),
+            (SampleFormat::Int, _) => {
+                return Err(anyhow!(
+                    "WAV int samples can only be 8, 16, 24 or 32 bits, not `{bits}`"
+                ))
+            }
+            (SampleFormat::Float, _) => {
+                return Err(anyhow!(
+                    "WAV float samples can only be 32 bits, not `{bits}`"
+                ))
+            }
+        }
+        let writer = hound::WavWriter::new(
+            writer,
+            hound::WavSpec {
+                channels: spec.channels,
+                sample_rate: spec.sample_rate,
+                bits_per_sample: bits,
+                sample_format: match sample_format {
+                    SampleFormat::Int => hound::SampleFormat::Int,
+                    SampleFormat::Float => hound::SampleFormat::Float,
+                },
+            },
+        )?;
+        Ok(WavEncoder {
+            writer,
+            bits,
+            sample_format,
+        })
+    }
+}
+
+impl<W> fmt::Debug for WavEncoder<W>
+where
+    W: Write + Seek,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "WavEncoder( {}-bit {:?} )",
+            self.bits, self.sample_format
+        )
+    }
+}
+
+impl<W> Encoder for WavEncoder<W>
+where
+    W: Write + Seek,
+{
+    fn write_frame(&mut self, frame: &[f64]) -> anyhow::Result<()> {
+        frame.iter().try_for_each(|s| {
+            match (self.sample_format, self.bits) {
+                (SampleFormat::Float, _) => self.writer.write_sample(*s as f32),
+                (SampleFormat::Int, 8) => self.writer.write_sample(quantize(*s, 8) as i8),
+                (SampleFormat::Int, 16) => self.writer.write_sample(quantize(*s, 16) as i16),
+                (SampleFormat::Int, bits) => self.writer.write_sample(quantize(*s, bits)),
+            }
+            .context("wav write error")
+        })
+    }
+
+    fn finalize(self: Box<Self>) -> anyhow::Result<()> {
+        self.writer.finalize().context("wav finalize error")
+    }
+}
+
+/// FLAC encoder that encodes every block of frames as soon as it's filled, and goes back to
+/// the header of the stream once finalized to write its length and checksum
+pub struct FlacEncoder<W>
+where
+    W: Write + Seek,
+{
+    writer: W,
+    config: Verified<flacenc::config::Encoder>,
+    /// Header of the stream, the frames being written as they're encoded
+    stream: Stream,
+    framebuf: FrameBuf,
+    context: source::Context,
+    /// Interleaved samples of the block being filled
+    block: Vec<i32>,
+    /// Samples in a full block
+    block_len: usize,
+    channels: usize,
+    /// Smallest and largest frame written, in bytes
+    frame_sizes: Option<(usize, usize)>,
+    bits: u16,
+}
+
+impl<W> FlacEncoder<W>
+where
+    W: Write + Seek,
+{
+    pub fn new(writer: W, spec: EncoderSpec, bits: u16) -> anyhow::Result<Self> {
+        if !matches!(bits, 8 | 16 | 24) {
+            return Err(anyhow!(
+                "FLAC samples can only be 8, 16 or 24 bits, not `{bits}`"
+            ));
+        }
+        let flac_error = |err: VerifyError| anyhow!("flac config error: {err}");
+        let config = flacenc::config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, err)| flac_error(err))?;
+        let channels = spec.channels as usize;
+        let mut stream =
+            Stream::new(spec.sample_rate as usize, channels, bits as usize).map_err(flac_error)?;
+        // Every block but the last is as long, which makes this a fixed-blocksize stream
+        stream
+            .stream_info_mut()
+            .set_block_sizes(config.block_size, config.block_size)
+            .map_err(flac_error)?;
+        let mut encoder = FlacEncoder {
+            writer,
+            framebuf: FrameBuf::with_size(channels, config.block_size).map_err(flac_error)?,
+            context: source::Context::new(bits as usize, channels),
+            block: Vec::with_capacity(config.block_size * channels),
+            block_len: config.block_size * channels,
+            channels,
+            config,
+            stream,
+            frame_sizes: None,
+            bits,
+        };
+        // Written again with the length and checksum once they're known
+        encoder.write_header()?;
+        Ok(encoder)
+    }
+
+    fn write_header(&mut self) -> anyhow::Result<()> {
+        let mut sink = ByteSink::new();
+        self.stream
+            .write(&mut sink)
+            .map_err(|err| anyhow!("flac write error: {err}"))?;
+        self.writer
+            .write_all(sink.as_slice())
+            .context("flac write error")
+    }
+
+    fn write_block(&mut self) -> anyhow::Result<()> {
+        let encode_error = |err: &dyn fmt::Display| anyhow!("flac encode error: {err}");
+        self.framebuf
+            .fill_interleaved(&self.block)
+            .map_err(|err| encode_error(&err))?;
+        self.context
+            .fill_interleaved(&self.block)
+            .map_err(|err| encode_error(&err))?;
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            self.context
+                .current_frame_number()
+                .expect("a block was just filled"),
+            self.stream.stream_info(),
+        )
+        .map_err(|err| encode_error(&err))?;
+
+        let mut sink = ByteSink::new();
+        frame
+            .write(&mut sink)
+            .map_err(|err| anyhow!("flac write error: {err}"))?;
+        self.writer
+            .write_all(sink.as_slice())
+            .context("flac write error")?;
+        let size = sink.as_slice().len();
+        self.frame_sizes = Some(match self.frame_sizes {
+            Some((min, max)) => (min.min(size), max.max(size)),
+            None => (size, size),
+        });
+        self.block.clear();
+        Ok(())
+    }
+}
+
+impl<W> fmt::Debug for FlacEncoder<W>
+where
+    W: Write + Seek,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "FlacEncoder( {}-bit, {} channels, {} Hz )",
+            self.bits,
+            self.stream.stream_info().channels(),
+            self.stream.stream_info().sample_rate()
+        )
+    }
+}
+
+impl<W> Encoder for FlacEncoder<W>
+where
+    W: Write + Seek,
+{
+    fn write_frame(&mut self, frame: &[f64]) -> anyhow::Result<()> {
+        if frame.len() != self.channels {
+            return Err(anyhow!(
+                "frame has `{}` channels, encoder was opened with `{}`",
+                frame.len(),
+                self.channels
+            ));
+        }
+        self.block
+            .extend(frame.iter().map(|s| quantize(*s, self.bits)));
+        if self.block.len() >= self.block_len {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> anyhow::Result<()> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        let md5 = self.context.md5_digest();
+        let total_samples = self.context.total_samples();
+        let stream_info = self.stream.stream_info_mut();
+        stream_info.set_md5_digest(&md5);
+        stream_info.set_total_samples(total_samples);
+        if let Some((min, max)) = self.frame_sizes {
+            stream_info
+                .set_frame_sizes(min, max)
+                .map_err(|err| anyhow!("flac encode error: {err}"))?;
+        }
+        // The header is as long as before, and no frame follows it in `stream`
+        self.writer
+            .seek(SeekFrom::Start(0))
+            .context("flac write error")?;
+        self.write_header()?;
+        self.writer.flush().context("flac write error")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const SPEC: EncoderSpec = EncoderSpec {
+        channels: 2,
+        sample_rate: 44100,
+    };
+
+    #[test]
+    fn quantize_rounds_and_clips() {
+        assert_eq!(quantize(1., 16), i16::MAX as i32);
+        assert_eq!(quantize(-1., 16), i16::MIN as i32);
+        assert_eq!(quantize(2.5, 8), i8::MAX as i32);
+        assert_eq!(quantize(-3., 24), -(1 << 23));
+        assert_eq!(quantize(1., 32), i32::MAX);
+        assert_eq!(quantize(-1., 32), i32::MIN);
+        assert_eq!(quantize(0.5 / 32768., 16), 1);
+        assert_eq!(quantize(0.49 / 32768., 16), 0);
+        assert_eq!(quantize(-0.5 / 32768., 16), -1);
+        assert_eq!(quantize(0.25, 16), 8192);
+    }
+
+    #[test]
+    fn wav_of_every_bit_depth() {
+        let frames = [[0.5, -1.], [0.25, 1.5], [0., -0.125]];
+        for (bits, sample_format) in [
+            (8, SampleFormat::Int),
+            (16, SampleFormat::Int),
+            (24, SampleFormat::Int),
+            (32, SampleFormat::Int),
+            (32, SampleFormat::Float),
+        ] {
+            let mut file = Cursor::new(Vec::new());
+            let mut encoder =
+                Box::new(WavEncoder::new(&mut file, SPEC, bits, sample_format).unwrap());
+            frames
+                .iter()
+                .for_each(|frame| encoder.write_frame(frame).unwrap());
+            encoder.finalize().unwrap();
+
+            file.set_position(0);
+            let mut reader = hound::WavReader::new(file).unwrap();
+            assert_eq!(reader.spec().bits_per_sample, bits);
+            let expected = frames.iter().flatten();
+            match sample_format {
+                SampleFormat::Float => {
+                    let samples: Vec<f32> = reader.samples().map(Result::unwrap).collect();
+                    assert_eq!(samples, expected.map(|s| *s as f32).collect::<Vec<_>>());
+                }
+                SampleFormat::Int => {
+                    let samples: Vec<i32> = reader.samples().map(Result::unwrap).collect();
+                    assert_eq!(
+                        samples,
+                        expected.map(|s| quantize(*s, bits)).collect::<Vec<_>>()
+                    );
+                }
+            }
+        }
+        assert!(WavEncoder::new(Cursor::new(Vec::new()), SPEC, 12, SampleFormat::Int).is_err());
+        assert!(WavEncoder::new(Cursor::new(Vec::new()), SPEC, 16, SampleFormat::Float).is_err());
+    }
+
+    #[test]
+    fn flac_decodes_to_what_was_encoded() {
+        use symphonia::core::{
+            audio::SampleBuffer, codecs::DecoderOptions, formats::FormatOptions,
+            io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+        };
+
+        // Spans a few blocks, the last of them shorter
+        let frames: Vec<[f64; 2]> = (0..10_000)
+            .map(|i| {
+                let s = (i as f64 * 0.01).sin() * 0.8;
+                [s, -s / 2.]
+            })
+            .collect();
+        let mut file = Cursor::new(Vec::new());
+        let mut encoder = Box::new(FlacEncoder::new(&mut file, SPEC, 16).unwrap());
+        frames
+            .iter()
+            .for_each(|frame| encoder.write_frame(frame).unwrap());
+        // Frames of any other number of channels would throw the blocks out of line
+        assert!(encoder.write_frame(&[0.; 3]).is_err());
+        assert!(encoder.write_frame(&[0.]).is_err());
+        encoder.finalize().unwrap();
+
+        let mut hint = Hint::new();
+        hint.with_extension("flac");
+        let stream =
+            MediaSourceStream::new(Box::new(Cursor::new(file.into_inner())), Default::default());
+        let mut reader = symphonia::default::get_probe()
+            .format(
+                &hint,
+                stream,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )
+            .unwrap()
+            .format;
+        let track = reader.default_track().unwrap();
+        assert_eq!(track.codec_params.n_frames, Some(frames.len() as u64));
+        assert_eq!(track.codec_params.sample_rate, Some(SPEC.sample_rate));
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions { verify: true })
+            .unwrap();
+        let mut decoded = Vec::new();
+        while let Ok(packet) = reader.next_packet() {
+            let buffer = decoder.decode(&packet).unwrap();
+            let mut samples = SampleBuffer::<i16>::new(buffer.capacity() as u64, *buffer.spec());
+            samples.copy_interleaved_ref(buffer);
+            decoded.extend_from_slice(samples.samples());
+        }
+        let expected: Vec<i16> = frames
+            .iter()
+            .flatten()
+            .map(|s| quantize(*s, 16) as i16)
+            .collect();
+        assert_eq!(decoded, expected);
+        assert!(decoder.finalize().verify_ok.unwrap_or(true));
+    }
+}
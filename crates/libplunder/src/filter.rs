@@ -0,0 +1,417 @@
+use std::{
+    any,
+    fmt::{self, Display},
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use mlua::{prelude::*, serde::Deserializer};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    instrument::{EmittableUserData, InstrumentError, State},
+    instrument_and_event::{DownInstrumentDownEvent, InstrumentAndEvent},
+    layout::{ChannelLayout, Remix, DEFAULT_PAN_LAW},
+    Sample, SharedPtr,
+};
+
+/// A sample-processor that transforms frames of normalized samples (one per channel) in place
+pub trait Process {
+    /// Called before the first frame is processed with the sample-rate of the frames to come
+    fn prepare(&mut self, _sample_rate: u32) {}
+    fn process(&mut self, frame: &mut [f32]);
+    /// Number of channels of the frames the processor outputs for frames of `input` channels.
+    /// Frames of instruments are remixed to it before they're processed
+    fn channels(&self, input: usize) -> usize {
+        input
+    }
+    /// Whether the processor would still output sound if it were given nothing but silence from
+    /// now on, like the tail of a delay or reverb
+    fn ringing(&self) -> bool {
+        false
+    }
+}
+
+/// Samples quieter than this are silence to filters that ring out
+pub const TAIL_FLOOR: f32 = 1e-5;
+/// Frames of silence a chain rings out for between checks of whether its filters still ring
+const RING_CHECK: usize = 1024;
+
+/// A Filter is any pairing of a [sample-processor](Process) and a [state-machine](State)
+pub trait Filter<A, E>: State<A, E> + Process {
+    fn help(&self) -> String;
+}
+
+/// A hidden trait for Filters that is less fancier than [`Filter`](Filter) and is dyn-compatible
+///
+/// NOTE: PlunderFilter should only be implemented for shared-pointers to Filters
+pub trait PlunderFilter: fmt::Debug + any::Any + Sync + Send {
+    fn prepare(&self, sample_rate: u32);
+    fn process(&self, frame: &mut [f32]);
+    fn channels(&self, input: usize) -> usize;
+    fn ringing(&self) -> bool;
+    fn transform(&self, lua_value: LuaValue) -> Result<(), InstrumentError>;
+    fn help(&self) -> String;
+}
+
+/// A wrapper for a [`Filter`](Filter) to make it dyn-compatible
+pub struct ToPlunderFilter<A, E, T> {
+    pub(crate) filter: SharedPtr<T>,
+    p: PhantomData<(A, E)>,
+}
+
+impl<A, E, T> From<SharedPtr<T>> for ToPlunderFilter<A, E, T> {
+    fn from(filter: SharedPtr<T>) -> Self {
+        ToPlunderFilter {
+            filter,
+            p: PhantomData,
+        }
+    }
+}
+
+impl<A, E, T> fmt::Debug for ToPlunderFilter<A, E, T>
+where
+    E: DeserializeOwned,
+    T: Filter<A, E>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ToPlunderFilter( {} )", any::type_name::<T>())
+    }
+}
+
+impl<A, E, T> PlunderFilter for ToPlunderFilter<A, E, T>
+where
+    A: Send + Sync + 'static,
+    E: DeserializeOwned + Send + Sync + 'static,
+    T: Filter<A, E> + Send + Sync + 'static,
+{
+    fn prepare(&self, sample_rate: u32) {
+        self.filter.write().unwrap().prepare(sample_rate)
+    }
+
+    fn process(&self, frame: &mut [f32]) {
+        self.filter.write().unwrap().process(frame)
+    }
+
+    fn channels(&self, input: usize) -> usize {
+        self.filter.read().unwrap().channels(input)
+    }
+
+    fn ringing(&self) -> bool {
+        self.filter.read().unwrap().ringing()
+    }
+
+    fn transform(&self, lua_value: LuaValue) -> Result<(), InstrumentError> {
+        self.filter
+            .write()
+            .unwrap()
+            .transform(
+                E::deserialize(Deserializer::new(lua_value))
+                    .map_err(InstrumentError::DeserializationError)?,
+            )
+            .map_err(|err| InstrumentError::Custom(err.to_string()))
+    }
+
+    fn help(&self) -> String {
+        self.filter.read().unwrap().help()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SharedPlunderFilter(pub Arc<dyn PlunderFilter + Send + Sync>);
+
+#[derive(Debug)]
+struct Chain {
+    filters: Vec<SharedPlunderFilter>,
+    /// Pan law that frames are up-mixed at for filters that output more channels
+    pan_law: f64,
+    /// Number of channels of the last non-empty sample, so that filters can keep ringing out
+    /// (think delay & reverb tails) while their source outputs empty samples or has finished.
+    /// `None` again once they've rung out
+    channels: Option<usize>,
+    /// Frames of silence rung out since the source last had samples
+    silent: usize,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Chain {
+            filters: Vec::new(),
+            pan_law: DEFAULT_PAN_LAW,
+            channels: None,
+            silent: 0,
+        }
+    }
+}
+
+impl Chain {
+    fn process(&self, frame: &mut [f32]) {
+        self.filters
+            .iter()
+            .for_each(|filter| filter.0.process(frame));
+    }
+
+    fn channels(&self, input: usize) -> usize {
+        self.filters
+            .iter()
+            .fold(input, |channels, filter| filter.0.channels(channels))
+    }
+
+    /// Pass a frame through every filter, remixing it for the ones that output another number of
+    /// channels
+    fn process_remixed(&self, frame: &mut Vec<f32>) {
+        for filter in &self.filters {
+            let channels = filter.0.channels(frame.len());
+            if channels != frame.len() {
+                let input: Vec<f64> = frame.iter().map(|s| *s as f64).collect();
+                let mut output = vec![0.; channels];
+                Remix::new(ChannelLayout::from_channels(channels), self.pan_law)
+                    .add(&input, &mut output);
+                *frame = output.into_iter().map(|s| s as f32).collect();
+            }
+            filter.0.process(frame);
+        }
+    }
+
+    /// A frame of the chain's tail, if it's still ringing
+    fn ring(&mut self) -> Option<Vec<f32>> {
+        let channels = self.channels?;
+        if self.silent % RING_CHECK == 0 && !self.filters.iter().any(|filter| filter.0.ringing()) {
+            self.channels = None;
+            return None;
+        }
+        self.silent += 1;
+        let mut frame = vec![0.; channels];
+        self.process_remixed(&mut frame);
+        Some(frame)
+    }
+}
+
+/// An ordered chain of filters that samples pass through, shared by every clone of the instrument
+/// (or bus) it is attached to
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain(SharedPtr<Chain>);
+
+impl FilterChain {
+    pub fn new(filters: Vec<SharedPlunderFilter>) -> Self {
+        FilterChain(Arc::new(RwLock::new(Chain {
+            filters,
+            ..Chain::default()
+        })))
+    }
+
+    pub fn push(&self, filter: SharedPlunderFilter) {
+        self.0.write().unwrap().filters.push(filter);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().filters.is_empty()
+    }
+
+    /// Prepare every filter for frames at `sample_rate`, up-mixed at `pan_law` for the filters
+    /// that output more channels
+    pub fn prepare(&self, sample_rate: u32, pan_law: f64) {
+        let mut chain = self.0.write().unwrap();
+        chain.pan_law = pan_law;
+        chain
+            .filters
+            .iter()
+            .for_each(|filter| filter.0.prepare(sample_rate));
+    }
+
+    /// Number of channels of the frames the chain outputs for frames of `input` channels
+    pub fn channels(&self, input: usize) -> usize {
+        self.0.read().unwrap().channels(input)
+    }
+
+    /// Pass a frame of normalized samples through every filter in the chain, which keeps its
+    /// number of channels whatever [`channels`](Self::channels) says
+    pub fn process(&self, frame: &mut [f32]) {
+        self.0.read().unwrap().process(frame);
+    }
+
+    /// Pass every frame of `channels` interleaved samples in `buffer` through every filter in the
+    /// chain, into frames of as many channels as the filters output
+    pub fn process_frames(&self, buffer: &[f32], channels: usize) -> Vec<Vec<f32>> {
+        let mut chain = self.0.write().unwrap();
+        if !chain.filters.is_empty() && !buffer.is_empty() {
+            chain.channels = Some(channels);
+            chain.silent = 0;
+        }
+        buffer
+            .chunks_exact(channels)
+            .map(|frame| {
+                let mut frame = frame.to_vec();
+                chain.process_remixed(&mut frame);
+                frame
+            })
+            .collect()
+    }
+
+    /// A frame of the tail of the chain's filters, once its source has finished or has nothing to
+    /// play. `None` once they've rung out, or if they never had anything to ring with
+    pub fn ring(&self) -> Option<Vec<f32>> {
+        self.0.write().unwrap().ring()
+    }
+
+    /// Pass a sample through every filter in the chain, normalizing it to [`F32`](Sample::F32)
+    pub fn apply(&self, sample: Sample) -> Sample {
+        let mut chain = self.0.write().unwrap();
+        if chain.filters.is_empty() {
+            return sample;
+        }
+        match sample.to_f32() {
+            Some(mut frame) => {
+                chain.channels = Some(frame.len());
+                chain.silent = 0;
+                chain.process_remixed(&mut frame);
+                Sample::F32(frame)
+            }
+            None => chain.ring().map_or(Sample::Empty, Sample::F32),
+        }
+    }
+}
+
+/// Every instance of a filter, events on the filter are emitted to all of them
+#[derive(Debug, Clone, Default)]
+pub struct FilterInstances(pub SharedPtr<Vec<SharedPlunderFilter>>);
+
+type Spawn = Arc<dyn Fn() -> Result<SharedPlunderFilter, String> + Send + Sync>;
+
+// obj (filter type from class erased)
+#[derive(Clone)]
+pub struct PackagedFilter {
+    pub instances: FilterInstances,
+    pub manual: Arc<str>,
+    spawn: Spawn,
+    attached: Arc<AtomicUsize>,
+}
+
+impl PackagedFilter {
+    /// Get an instance of the filter to attach to an instrument or bus
+    ///
+    /// A filter keeps state between frames so it can only ever process a single stream. The
+    /// first attachment gets the instance that was initialized, every later one gets a new
+    /// instance initialized the same way
+    pub fn attach(&self) -> Result<SharedPlunderFilter, String> {
+        if self.attached.fetch_add(1, Ordering::SeqCst) == 0 {
+            return Ok(self.instances.0.read().unwrap()[0].clone());
+        }
+        let instance = (self.spawn)()?;
+        self.instances.0.write().unwrap().push(instance.clone());
+        Ok(instance)
+    }
+
+    pub fn help(&self) -> String {
+        self.instances.0.read().unwrap()[0].0.help()
+    }
+}
+
+impl fmt::Debug for PackagedFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PackagedFilter( {:?} )", self.instances)
+    }
+}
+
+impl Display for PackagedFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.help())
+    }
+}
+
+// class
+#[derive(Debug)]
+pub struct PackagedFilterFactory<A, E, T> {
+    manual: Arc<str>,
+    t: PhantomData<(A, E, T)>,
+}
+
+impl<A, E, T> LuaUserData for PackagedFilterFactory<A, E, T>
+where
+    A: DeserializeOwned + Clone + Send + Sync + 'static,
+    E: DeserializeOwned + Send + Sync + 'static,
+    T: Filter<A, E> + Send + Sync + 'static,
+{
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(LuaMetaMethod::Index, |lua, this, key: LuaValue| {
+            let manual = this.manual.clone();
+            let route = key
+                .as_string_lossy()
+                .ok_or(LuaError::runtime(
+                    "Filters can only be initialized using valid strings",
+                ))?
+                .to_string();
+            let initializer =
+                lua.create_function(move |_, arguments: LuaValue| -> LuaResult<PackagedFilter> {
+                    let arguments =
+                        A::deserialize(Deserializer::new(arguments)).map_err(|err| {
+                            LuaError::runtime(format!(
+                                "\n~~~> plunder <~~~: Error while providing this value to the \
+                                filter: {err}"
+                            ))
+                        })?;
+                    let route = route.clone();
+                    let spawn: Spawn = Arc::new(move || {
+                        T::initialize(&route, arguments.clone())
+                            .map(|filter| {
+                                SharedPlunderFilter(Arc::new(ToPlunderFilter::<A, E, T>::from(
+                                    Arc::new(RwLock::new(filter)),
+                                )))
+                            })
+                            .map_err(|err| format!("{err:#}"))
+                    });
+                    let instance = spawn().map_err(|err| LuaError::runtime(format!("\n{err}")))?;
+                    Ok(PackagedFilter {
+                        instances: FilterInstances(Arc::new(RwLock::new(vec![instance]))),
+                        manual: manual.clone(),
+                        spawn,
+                        attached: Arc::new(AtomicUsize::new(0)),
+                    })
+                })?;
+            Ok(LuaValue::Function(initializer))
+        });
+
+        methods.add_meta_method(LuaMetaMethod::ToString, |_, this, _: LuaMultiValue| {
+            Ok(this.manual.to_string())
+        });
+    }
+}
+
+type PlunderFilterAndEvent =
+    InstrumentAndEvent<FilterInstances, (), (), LuaValue, DownInstrumentDownEvent>;
+
+impl From<(&PackagedFilter, LuaValue)> for EmittableUserData {
+    // Events carrying a LuaValue never leave the thread of the Lua state they were created in
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn from((filter, event): (&PackagedFilter, LuaValue)) -> Self {
+        EmittableUserData(Arc::new(RwLock::new(PlunderFilterAndEvent::new(
+            filter.instances.clone(),
+            event,
+        ))))
+    }
+}
+
+impl LuaUserData for PackagedFilter {
+    fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
+        methods.add_meta_method(
+            LuaMetaMethod::Index,
+            |_, this, event: LuaValue| -> LuaResult<EmittableUserData> { Ok((this, event).into()) },
+        );
+    }
+}
+
+pub fn package_filter<T, A, E>(lua: &Lua, manual: String) -> LuaResult<LuaValue>
+where
+    A: DeserializeOwned + Clone + Sync + Send + 'static,
+    E: DeserializeOwned + Sync + Send + 'static,
+    T: Filter<A, E> + Sync + Send + 'static,
+{
+    PackagedFilterFactory::<A, E, T> {
+        manual: Arc::from(manual),
+        t: PhantomData,
+    }
+    .into_lua(lua)
+}
@@ -9,6 +9,7 @@ use mlua::{prelude::*, serde::Deserializer};
 use serde::de::DeserializeOwned;
 
 use crate::{
+    filter::FilterChain,
     instrument_and_event::{DownInstrumentDownEvent, InstrumentAndEvent},
     Sample, SharedPtr,
 };
@@ -49,7 +50,7 @@ where
 }
 
 impl<E> SourceError<E> {
-    pub(crate) fn to_string_error(self) -> SourceError<String>
+    pub(crate) fn into_string_error(self) -> SourceError<String>
     where
         E: Display,
     {
@@ -63,6 +64,45 @@ impl<E> SourceError<E> {
 pub trait Source {
     type Err: Display;
     fn next_sample(&mut self) -> Result<Option<Sample>, SourceError<Self::Err>>;
+    /// Native sample-rate of the samples, `None` if they are always at the rate being rendered at
+    fn sample_rate(&self) -> Option<u32> {
+        None
+    }
+    /// Called before the first sample is pulled with the sample-rate being rendered at. Sources
+    /// that can generate samples at any rate should switch to it
+    fn prepare(&mut self, _sample_rate: u32) -> Result<(), Self::Err> {
+        Ok(())
+    }
+    /// Called with the tempo being rendered at before events are emitted, whenever it changes
+    fn tempo(&mut self, _bpm: f64) {}
+    /// Called with the length in beats of the units that event positions count, before the first
+    /// event is emitted
+    fn unit(&mut self, _beats: f64) {}
+    /// Number of channels in every frame, `None` if it may change from frame to frame. Sources
+    /// that know it are rendered a block at a time with [`fill_buffer`](Self::fill_buffer)
+    fn channels(&self) -> Option<usize> {
+        None
+    }
+    /// Fill `buffer` with frames of `channels` interleaved samples normalized to `-1.0..1.0`,
+    /// returning the number of frames filled. Filling less than the whole buffer means the source
+    /// has finished. Frames with more channels are truncated and ones with fewer are padded with
+    /// silence
+    fn fill_buffer(
+        &mut self,
+        buffer: &mut [f32],
+        channels: usize,
+    ) -> Result<usize, SourceError<Self::Err>> {
+        for (filled, frame) in buffer.chunks_exact_mut(channels).enumerate() {
+            let Some(sample) = self.next_sample()? else {
+                return Ok(filled);
+            };
+            frame.fill(0.);
+            if let Some(sample) = sample.to_f32() {
+                frame.iter_mut().zip(sample).for_each(|(f, s)| *f = s);
+            }
+        }
+        Ok(buffer.len() / channels)
+    }
 }
 
 // TODO: it is ok to impl Source for SharedPtr<T: Source>, but change the Err assoc-type to a union that includes error that occurred is rwlock poisoned
@@ -161,6 +201,16 @@ impl Display for InstrumentError {
 /// This makes it less tedious to downcast as the origin instrument in [`InstrumentAndEvent`](InstrumentAndEvent)
 pub trait PlunderInstrument: fmt::Debug + Any + Sync + Send {
     fn next_sample(&self) -> Result<Option<Sample>, SourceError<String>>;
+    fn sample_rate(&self) -> Option<u32>;
+    fn prepare(&self, sample_rate: u32) -> Result<(), String>;
+    fn tempo(&self, bpm: f64);
+    fn unit(&self, beats: f64);
+    fn channels(&self) -> Option<usize>;
+    fn fill_buffer(
+        &self,
+        buffer: &mut [f32],
+        channels: usize,
+    ) -> Result<usize, SourceError<String>>;
     fn transform(&self, lua_value: LuaValue) -> Result<(), InstrumentError>;
     fn help(&self) -> String;
 }
@@ -196,6 +246,12 @@ pub struct ToPlunderInstrument<A, E, T> {
     p: PhantomData<(A, E)>,
 }
 
+impl<A, E, T> ToPlunderInstrument<A, E, T> {
+    pub fn instrument(&self) -> &SharedPtr<T> {
+        &self.instrument
+    }
+}
+
 impl<A, E, T> From<SharedPtr<T>> for ToPlunderInstrument<A, E, T> {
     fn from(instrument: SharedPtr<T>) -> Self {
         ToPlunderInstrument {
@@ -224,7 +280,7 @@ where
 //     fn next_sample(&mut self) -> Result<Option<Sample>, SourceError<String>> {
 //         self.instrument
 //             .next_sample()
-//             .map_err(|err| err.to_string_error())
+//             .map_err(|err| err.into_string_error())
 //     }
 
 //     fn transform(&mut self, lua_value: LuaValue) -> Result<(), InstrumentError> {
@@ -252,7 +308,43 @@ where
             .write()
             .unwrap()
             .next_sample()
-            .map_err(|err| err.to_string_error())
+            .map_err(|err| err.into_string_error())
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.instrument.read().unwrap().sample_rate()
+    }
+
+    fn prepare(&self, sample_rate: u32) -> Result<(), String> {
+        self.instrument
+            .write()
+            .unwrap()
+            .prepare(sample_rate)
+            .map_err(|err| err.to_string())
+    }
+
+    fn tempo(&self, bpm: f64) {
+        self.instrument.write().unwrap().tempo(bpm)
+    }
+
+    fn unit(&self, beats: f64) {
+        self.instrument.write().unwrap().unit(beats)
+    }
+
+    fn channels(&self) -> Option<usize> {
+        self.instrument.read().unwrap().channels()
+    }
+
+    fn fill_buffer(
+        &self,
+        buffer: &mut [f32],
+        channels: usize,
+    ) -> Result<usize, SourceError<String>> {
+        self.instrument
+            .write()
+            .unwrap()
+            .fill_buffer(buffer, channels)
+            .map_err(|err| err.into_string_error())
     }
 
     fn transform(&self, lua_value: LuaValue) -> Result<(), InstrumentError> {
@@ -417,6 +509,11 @@ where
 pub trait Emit {
     fn emit(&mut self) -> Result<(), String>;
     fn instrument_help(&self) -> String;
+    /// The instrument the event is for and the event, a [`LuaValue`](LuaValue) or the
+    /// instrument's own type of event, for looking into events without emitting them
+    fn parts(&self) -> Option<(&SharedPlunderInstrument, &dyn Any)> {
+        None
+    }
 }
 
 /*
@@ -598,6 +695,8 @@ pub struct SharedPlunderInstrument(pub Arc<dyn PlunderInstrument + Send + Sync>)
 pub struct PackagedInstrument {
     pub factory: SharedPlunderInstrument,
     pub manual: Arc<str>,
+    /// Filters that every sample of this instrument passes through before being mixed
+    pub filters: FilterChain,
 }
 
 impl Display for PackagedInstrument {
@@ -637,6 +736,7 @@ where
                             Ok(PackagedInstrument {
                               factory: SharedPlunderInstrument(shared_instrument),
                                manual: manual.clone(),
+                               filters: FilterChain::default(),
                                                 })
                         },
                         Err(err) => Err(LuaError::runtime(format!("\n{err:#}"))),
@@ -653,6 +753,8 @@ where
 }
 
 impl From<(&PackagedInstrument, LuaValue)> for EmittableUserData {
+    // Events carrying a LuaValue never leave the thread of the Lua state they were created in
+    #[allow(clippy::arc_with_non_send_sync)]
     fn from((instrument, event): (&PackagedInstrument, LuaValue)) -> Self {
         EmittableUserData(Arc::new(RwLock::new(PlunderInstrumentAndEvent {
             instrument: instrument.factory.clone(),
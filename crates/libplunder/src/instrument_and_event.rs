@@ -4,8 +4,9 @@ use log::info;
 use mlua::{prelude::*, serde::Deserializer};
 use serde::de::DeserializeOwned;
 
-use crate::instrument::{
-    Emit, Instrument, InstrumentError, SharedPlunderInstrument, ToPlunderInstrument,
+use crate::{
+    filter::FilterInstances,
+    instrument::{Emit, Instrument, InstrumentError, SharedPlunderInstrument, ToPlunderInstrument},
 };
 
 #[derive(Debug)]
@@ -51,6 +52,26 @@ impl Emit
     fn instrument_help(&self) -> String {
         self.instrument.0.help()
     }
+
+    fn parts(&self) -> Option<(&SharedPlunderInstrument, &dyn Any)> {
+        Some((&self.instrument, &self.event))
+    }
+}
+
+impl Emit for InstrumentAndEvent<FilterInstances, (), (), LuaValue, DownInstrumentDownEvent> {
+    fn emit(&mut self) -> Result<(), String> {
+        self.instrument
+            .0
+            .read()
+            .unwrap()
+            .iter()
+            .try_for_each(|filter| filter.0.transform(self.event.clone()))
+            .map_err(|err| err.to_string())
+    }
+
+    fn instrument_help(&self) -> String {
+        self.instrument.0.read().unwrap()[0].0.help()
+    }
 }
 
 impl<I, A, E> Emit for InstrumentAndEvent<I, A, E, E, UpInstrumentUpEvent>
@@ -131,6 +152,10 @@ where
     fn instrument_help(&self) -> String {
         self.instrument.0.help()
     }
+
+    fn parts(&self) -> Option<(&SharedPlunderInstrument, &dyn Any)> {
+        Some((&self.instrument, &self.event))
+    }
 }
 
 impl<I, A, E> Emit for InstrumentAndEvent<I, A, E, LuaValue, UpInstrumentDownEvent>
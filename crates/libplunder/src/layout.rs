@@ -0,0 +1,144 @@
+use std::f64::consts::FRAC_1_SQRT_2;
+
+/// The meaning of each channel in a frame, inferred from the number of channels
+///
+/// Channels are ordered the way WAV orders them, e.g. 5.1 is `FL, FR, C, LFE, SL, SR`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelLayout {
+    Mono,
+    Stereo,
+    Surround51,
+    /// Any other number of channels, which carry no meaning and are mapped one to one
+    Discrete(usize),
+}
+
+impl ChannelLayout {
+    pub fn from_channels(channels: usize) -> Self {
+        match channels {
+            1 => ChannelLayout::Mono,
+            2 => ChannelLayout::Stereo,
+            6 => ChannelLayout::Surround51,
+            n => ChannelLayout::Discrete(n),
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        match self {
+            ChannelLayout::Mono => 1,
+            ChannelLayout::Stereo => 2,
+            ChannelLayout::Surround51 => 6,
+            ChannelLayout::Discrete(n) => *n,
+        }
+    }
+}
+
+/// Attenuation in decibels of a mono channel in each side of stereo, unless the mix says otherwise
+pub const DEFAULT_PAN_LAW: f64 = 3.;
+
+/// Converts frames of any layout into a single output layout
+#[derive(Debug, Clone, Copy)]
+pub struct Remix {
+    to: ChannelLayout,
+    /// Gain of a mono channel in each side when up-mixed to stereo
+    center: f64,
+}
+
+impl Remix {
+    /// `pan_law` is the attenuation in decibels of a mono channel in each side of stereo, 3dB keeps
+    /// the power constant and 6dB keeps the amplitude constant
+    pub fn new(to: ChannelLayout, pan_law: f64) -> Self {
+        Remix {
+            to,
+            center: 10f64.powf(-pan_law / 20.),
+        }
+    }
+
+    pub fn layout(&self) -> ChannelLayout {
+        self.to
+    }
+
+    /// Add `frame` into `out`, which must have as many channels as the output layout
+    pub fn add(&self, frame: &[f64], out: &mut [f64]) {
+        use ChannelLayout::*;
+
+        match (ChannelLayout::from_channels(frame.len()), self.to) {
+            (from, to) if from == to => out.iter_mut().zip(frame).for_each(|(o, c)| *o += c),
+            (Mono, Stereo) => out.iter_mut().for_each(|o| *o += frame[0] * self.center),
+            (Stereo, Mono) => out[0] += (frame[0] + frame[1]) / 2.,
+            // The center channel
+            (Mono, Surround51) => out[2] += frame[0],
+            // The front channels
+            (Stereo, Surround51) => {
+                out[0] += frame[0];
+                out[1] += frame[1];
+            }
+            (Surround51, Stereo) => {
+                let [fl, fr, c, _lfe, sl, sr] = surround51(frame);
+                out[0] += fl + (c + sl) * FRAC_1_SQRT_2;
+                out[1] += fr + (c + sr) * FRAC_1_SQRT_2;
+            }
+            (Surround51, Mono) => {
+                let [fl, fr, c, _lfe, sl, sr] = surround51(frame);
+                out[0] += (fl + fr + (2. * c + sl + sr) * FRAC_1_SQRT_2) / 2.;
+            }
+            // Channels without a meaning map one to one, extra channels are dropped and missing
+            // ones are silent
+            _ => out.iter_mut().zip(frame).for_each(|(o, c)| *o += c),
+        }
+    }
+}
+
+fn surround51(frame: &[f64]) -> [f64; 6] {
+    [frame[0], frame[1], frame[2], frame[3], frame[4], frame[5]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remix(frame: &[f64], to: ChannelLayout) -> Vec<f64> {
+        let mut out = vec![0.; to.channels()];
+        Remix::new(to, 3.).add(frame, &mut out);
+        out
+    }
+
+    fn assert_close(a: &[f64], b: &[f64]) {
+        assert_eq!(a.len(), b.len());
+        a.iter()
+            .zip(b)
+            .for_each(|(a, b)| assert!((a - b).abs() < 1e-3, "{a:?} != {b:?}"));
+    }
+
+    #[test]
+    fn mono_to_stereo_follows_pan_law() {
+        assert_close(&remix(&[1.], ChannelLayout::Stereo), &[0.708, 0.708]);
+        let mut out = [0.; 2];
+        Remix::new(ChannelLayout::Stereo, 0.).add(&[0.5], &mut out);
+        assert_eq!(out, [0.5, 0.5]);
+    }
+
+    #[test]
+    fn stereo_to_mono_averages() {
+        assert_eq!(remix(&[1., 0.5], ChannelLayout::Mono), vec![0.75]);
+    }
+
+    #[test]
+    fn surround51_to_stereo_drops_lfe() {
+        assert_close(
+            &remix(&[1., 0., 1., 1., 0., 1.], ChannelLayout::Stereo),
+            &[1.707, 1.414],
+        );
+    }
+
+    #[test]
+    fn discrete_maps_one_to_one() {
+        assert_eq!(
+            remix(&[1., 2., 3.], ChannelLayout::Discrete(4)),
+            vec![1., 2., 3., 0.]
+        );
+        assert_eq!(
+            remix(&[1., 2., 3., 4.], ChannelLayout::Discrete(3)),
+            vec![1., 2., 3.]
+        );
+    }
+}
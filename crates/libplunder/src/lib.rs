@@ -1,17 +1,28 @@
 #![warn(missing_debug_implementations)]
 
 use std::{
+    cmp::Ordering,
     fmt,
     hash::Hash,
     sync::{Arc, RwLock},
 };
 
-use anyhow::anyhow;
 use instrument::{EmittableUserData, PackagedInstrument, SourceError};
-use log::{info, trace};
+use log::{info, trace, warn};
+use rayon::prelude::*;
+use resample::{Interpolation, Resampler};
+use tempo::{Position, TempoMap};
 
+pub mod encoder;
+pub mod filter;
 pub mod instrument;
 pub mod instrument_and_event;
+pub mod layout;
+pub mod mixdown;
+pub mod mixer;
+pub mod player;
+pub mod resample;
+pub mod tempo;
 
 pub mod prelude {
     pub mod instrument {
@@ -20,7 +31,7 @@ pub mod prelude {
                 package_instrument, Emit, EmittableUserData, Instrument, PackagedInstrument,
                 SharedPlunderInstrument, Source, SourceError, State, ToPlunderInstrument,
             },
-            is_event, Sample,
+            is_event, Sample, SampleType,
         };
 
         pub use crate::instrument_and_event::{
@@ -29,6 +40,16 @@ pub mod prelude {
         };
     }
 
+    pub mod filter {
+        pub use crate::{
+            filter::{
+                package_filter, Filter, FilterChain, FilterInstances, PackagedFilter,
+                PlunderFilter, Process, SharedPlunderFilter, ToPlunderFilter, TAIL_FLOOR,
+            },
+            instrument::State,
+        };
+    }
+
     pub mod parser {}
 }
 
@@ -51,107 +72,103 @@ pub enum Sample {
     Empty,
 }
 
-fn f64_to_i32(input: f64) -> i32 {
-    let f64_bits = input.to_bits();
-    let mut i32_bits: u32 = 0;
-    for i in 0..32 {
-        let odd_bit = (f64_bits >> (2 * i + 1)) & 1;
-        i32_bits |= (odd_bit as u32) << i;
-    }
-    (i32::MIN as i64 + i32_bits as i64) as i32
+/// The variant of a non-empty [`Sample`](Sample)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleType {
+    U8,
+    U16,
+    U24,
+    U32,
+    S8,
+    S16,
+    S24,
+    S32,
+    F32,
+    F64,
 }
 
-// fn f32_to_i32(input: f32) -> i32 {
-//     // Extract the bits of the f64
-//     let f32_bits = input.to_bits();
-
-//     // Collect only the odd bits from the f64
-//     let mut i32_bits: u32 = 0;
-//     for i in 0..32 {
-//         // Shift the f64 bits right by (2 * i + 1) to get the odd bit, then mask it
-//         let odd_bit = (f32_bits >> (2 * i + 1)) & 1;
-//         // Place the odd bit into the corresponding position in the f32 bits
-//         i32_bits |= (odd_bit as u32) << i;
-//     }
+/// Full-scale of a signed integer of `bits` bits, unsigned integers are offset by the same amount
+const fn full_scale(bits: u32) -> f64 {
+    (1u64 << (bits - 1)) as f64
+}
 
-//     // Convert the u32 to f32
-//     (i32::MIN as i64 + i32_bits as i64) as i32
-// }
+/// [`quantize`](encoder::quantize) offset to the range of an unsigned integer of `bits` bits
+fn quantize_unsigned(sample: f64, bits: u16) -> u32 {
+    (encoder::quantize(sample, bits) as i64 + (1i64 << (bits - 1))) as u32
+}
 
-// TODO allow samples of differing number of channels
-pub fn combine_i32(samples: &[Sample]) -> anyhow::Result<Option<Vec<i32>>> {
-    fn set_vec_i32<T, F>(
-        sum: &mut Option<Vec<i32>>,
-        samples: &Vec<T>,
-        mut f: F,
-    ) -> anyhow::Result<()>
-    where
-        F: FnMut(&T) -> i32,
-    {
-        match sum {
-            Some(sum) => {
-                samples
-                    .iter()
-                    .enumerate()
-                    .map(|(i, c)| {
-                        trace!("adding {} to channel {i}", f(c));
-                        sum.get_mut(i)
-                            .map(|si| *si = si.saturating_add(f(c)))
-                            .ok_or(anyhow!("channel inconsistency"))
-                    })
-                    .collect::<Result<(), _>>()?;
-            }
-            None => {
-                *sum = Some(
-                    samples
-                        .iter()
-                        .enumerate()
-                        .map(|(i, c)| {
-                            trace!("adding {} to channel {i}", f(c));
-                            f(c)
-                        })
-                        .collect(),
-                );
-            }
+impl Sample {
+    pub fn sample_type(&self) -> Option<SampleType> {
+        Some(match self {
+            Sample::U8(_) => SampleType::U8,
+            Sample::U16(_) => SampleType::U16,
+            Sample::U24(_) => SampleType::U24,
+            Sample::U32(_) => SampleType::U32,
+            Sample::S8(_) => SampleType::S8,
+            Sample::S16(_) => SampleType::S16,
+            Sample::S24(_) => SampleType::S24,
+            Sample::S32(_) => SampleType::S32,
+            Sample::F32(_) => SampleType::F32,
+            Sample::F64(_) => SampleType::F64,
+            Sample::Empty => return None,
+        })
+    }
+
+    /// Normalize every channel of the sample to `-1.0..1.0`, [`Empty`](Sample::Empty) has no
+    /// channels to normalize
+    pub fn to_f64(&self) -> Option<Vec<f64>> {
+        fn unsigned<T: Copy + Into<f64>>(cs: &[T], bits: u32) -> Vec<f64> {
+            let scale = full_scale(bits);
+            cs.iter().map(|c| ((*c).into() - scale) / scale).collect()
         }
-        Ok(())
+        fn signed<T: Copy + Into<f64>>(cs: &[T], bits: u32) -> Vec<f64> {
+            let scale = full_scale(bits);
+            cs.iter().map(|c| (*c).into() / scale).collect()
+        }
+
+        Some(match self {
+            Sample::U8(cs) => unsigned(cs, 8),
+            Sample::U16(cs) => unsigned(cs, 16),
+            Sample::U24(cs) => unsigned(cs, 24),
+            Sample::U32(cs) => unsigned(cs, 32),
+            Sample::S8(cs) => signed(cs, 8),
+            Sample::S16(cs) => signed(cs, 16),
+            Sample::S24(cs) => signed(cs, 24),
+            Sample::S32(cs) => signed(cs, 32),
+            Sample::F32(cs) => cs.iter().map(|c| *c as f64).collect(),
+            Sample::F64(cs) => cs.clone(),
+            Sample::Empty => return None,
+        })
     }
 
-    if samples.len() == 0 {
-        return Ok(None);
+    /// [`to_f64`](Sample::to_f64) at single precision
+    pub fn to_f32(&self) -> Option<Vec<f32>> {
+        match self {
+            Sample::F32(cs) => Some(cs.clone()),
+            sample => Some(sample.to_f64()?.into_iter().map(|c| c as f32).collect()),
+        }
     }
-    let mut sum = None;
-    for sample in samples {
-        match sample {
-            // (c / u8::MAX) * u32::MAX
-            Sample::U8(cs) => set_vec_i32(&mut sum, cs, |c| {
-                (i64::MIN + ((*c as i64) * (u32::MAX as i64 / u8::MAX as i64))) as i32
-            }),
-            Sample::U16(cs) => set_vec_i32(&mut sum, cs, |c| {
-                (i64::MIN + ((*c as i64) * (u32::MAX as i64 / u16::MAX as i64))) as i32
-            }),
-            Sample::U24(cs) => set_vec_i32(&mut sum, cs, |c| {
-                (i64::MIN + ((*c as i64) * (u32::MAX as i64 / (3 * u8::MAX as i64)))) as i32
-            }),
-            Sample::U32(cs) => set_vec_i32(&mut sum, cs, |c| (i64::MIN + *c as i64) as i32),
-            Sample::S8(cs) => set_vec_i32(&mut sum, cs, |c| {
-                (*c as i32) * (u32::MAX / u8::MAX as u32) as i32
-            }),
-            Sample::S16(cs) => set_vec_i32(&mut sum, cs, |c| {
-                (*c as i32) * (u32::MAX / u16::MAX as u32) as i32
-            }),
-            Sample::S24(cs) => set_vec_i32(&mut sum, cs, |c| {
-                (*c as i32) * (u32::MAX / (3 * u8::MAX as u32)) as i32
-            }),
-            Sample::S32(cs) => set_vec_i32(&mut sum, cs, |c| *c),
-            Sample::F32(cs) => {
-                set_vec_i32(&mut sum, cs, |c| (i64::MIN + c.to_bits() as i64) as i32)
-            }
-            Sample::F64(cs) => set_vec_i32(&mut sum, cs, |c| f64_to_i32(*c)),
-            Sample::Empty => Ok(()),
-        }?;
+
+    /// Convert a frame of normalized samples into a sample of type `sample_type`, integer types
+    /// clip samples outside `-1.0..1.0`
+    pub fn from_f64(sample_type: SampleType, frame: &[f64]) -> Sample {
+        fn convert<T, F: Fn(f64) -> T>(frame: &[f64], f: F) -> Vec<T> {
+            frame.iter().map(|c| f(*c)).collect()
+        }
+
+        match sample_type {
+            SampleType::U8 => Sample::U8(convert(frame, |c| quantize_unsigned(c, 8) as u8)),
+            SampleType::U16 => Sample::U16(convert(frame, |c| quantize_unsigned(c, 16) as u16)),
+            SampleType::U24 => Sample::U24(convert(frame, |c| quantize_unsigned(c, 24))),
+            SampleType::U32 => Sample::U32(convert(frame, |c| quantize_unsigned(c, 32))),
+            SampleType::S8 => Sample::S8(convert(frame, |c| encoder::quantize(c, 8) as i8)),
+            SampleType::S16 => Sample::S16(convert(frame, |c| encoder::quantize(c, 16) as i16)),
+            SampleType::S24 => Sample::S24(convert(frame, |c| encoder::quantize(c, 24))),
+            SampleType::S32 => Sample::S32(convert(frame, |c| encoder::quantize(c, 32))),
+            SampleType::F32 => Sample::F32(convert(frame, |c| c as f32)),
+            SampleType::F64 => Sample::F64(frame.to_vec()),
+        }
     }
-    Ok(sum)
 }
 
 // impl Add<Sample> for Sample {
@@ -241,17 +258,22 @@ pub type SharedPtr<T> = Arc<RwLock<T>>;
 #[derive(Debug)]
 pub struct Engine<I> {
     instruments: Vec<PackagedInstrument>,
+    /// Resampler of every instrument whose samples aren't at the rate being rendered at
+    resamplers: Vec<Option<Resampler>>,
     event_stream: I,
-    next_event: Option<(usize, EmittableUserData)>,
-    index: usize,
-    interval: usize,
-    unit_interval: usize,
+    next_event: Option<(Position, EmittableUserData)>,
+    tempo: TempoMap,
+    /// Tempo that instruments were last told about
+    bpm: Option<f64>,
+    /// Samples generated so far
+    sample: usize,
     duration: usize,
+    blocks: Option<Blocks>,
 }
 
 impl<I> Engine<I>
 where
-    I: Iterator<Item = (usize, EmittableUserData)>,
+    I: Iterator<Item = (Position, EmittableUserData)>,
 {
     fn _next_write(&mut self, _sample: &mut Sample) -> Result<(), EngineError> {
         todo!()
@@ -271,20 +293,35 @@ where
     /// Return<Option<_>> and just transpose it in the Iterator implementation
     /// It is the job of the caller to stop iterating when empty samples are being returned. This only returns None once all instruments have been exhausted
     fn next_inner(&mut self) -> Result<Option<Vec<Sample>>, EngineError> {
-        if (self.index as u128) * (self.unit_interval as u128) >= self.duration as u128 {
+        if self.sample >= self.duration {
             return Ok(None);
         }
-        if self.interval >= self.unit_interval - 1 {
-            // Emit all events set to the current index and then proceed generating samples
-            loop {
-                match self.next_event {
-                    Some(ref mut next_event) => {
-                        if next_event.0 == self.index {
-                            // We've reached the next unit where an event is to be emitted
-                            //
-                            // There might be more events at the same unit so don't advance to the
-                            // next unit yet
-                            trace!(">> Reached next-event at i:`{}`", self.index);
+        // Emit all events that fall on the current sample and then proceed generating it
+        loop {
+            match self.next_event {
+                Some(ref mut next_event) => {
+                    match self.tempo.sample_at(next_event.0).cmp(&self.sample) {
+                        Ordering::Equal => {
+                            // There might be more events at the same sample so don't advance to
+                            // the next sample yet
+                            trace!(
+                                ">> Reached next-event at `{:?}`, sample:`{}`",
+                                next_event.0,
+                                self.sample
+                            );
+                            let bpm = self.tempo.bpm_at_sample(self.sample);
+                            if self.bpm.is_none() {
+                                let beats = self.tempo.unit() as f64 / tempo::PPQ as f64;
+                                self.instruments
+                                    .iter()
+                                    .for_each(|instrument| instrument.factory.0.unit(beats));
+                            }
+                            if self.bpm != Some(bpm) {
+                                self.instruments
+                                    .iter()
+                                    .for_each(|instrument| instrument.factory.0.tempo(bpm));
+                                self.bpm = Some(bpm);
+                            }
                             next_event
                                 .1
                                  .0
@@ -295,70 +332,132 @@ where
                             // Empty `next_event` so another event can be popped from the
                             // `event_stream`
                             self.next_event = None;
-                        } else if next_event.0 > self.index {
-                            // Unit of `next_event` still not reached, advance 1 unit and try again
+                        }
+                        Ordering::Greater => {
+                            // Sample of `next_event` still not reached
                             break;
-                        } else {
+                        }
+                        Ordering::Less => {
                             return Err(EngineError::UnsortedEventStream);
                         }
                     }
-                    None => {
-                        // Pop the next event in `event_stream` into `next_event`
-                        self.next_event = self.event_stream.next();
-                        if self.next_event.is_none() {
-                            // `event_stream` returned None, i.e. it has been exhausted
-                            trace!(">> Event-stream exhausted");
-                            break;
-                        }
-                        trace!(
-                            ">> Popped next next-event with i:`{}`",
-                            self.next_event.as_ref().unwrap().0
-                        );
+                }
+                None => {
+                    // Pop the next event in `event_stream` into `next_event`
+                    self.next_event = self.event_stream.next();
+                    if self.next_event.is_none() {
+                        // `event_stream` returned None, i.e. it has been exhausted
+                        trace!(">> Event-stream exhausted");
+                        break;
                     }
+                    trace!(
+                        ">> Popped next next-event at `{:?}`",
+                        self.next_event.as_ref().unwrap().0
+                    );
                 }
             }
-            self.interval = 0;
-            // Advance to the next unit
-            self.index += 1;
-        } else {
-            self.interval += 1;
         }
 
-        trace!(">> At index {}", self.index);
-        let mut samples = None;
-        for instrument in &self.instruments {
-            let sample = instrument
-                .factory
-                .0
-                .next_sample()
-                .map_err(EngineError::Source)?;
-
-            samples = match (samples, sample) {
-                // only finished instruments so far and one more encountered
-                //
-                // the only way for the accumulator to result in None is if all instruments
-                // return None
-                (None, None) => None,
-                // first unfinished instrument encountered
-                (None, Some(s)) => Some(vec![s]),
-                // have some unfinished instruments so ignore an empty one encountered
-                (Some(acc), None) => Some(acc),
-                // non-first unfinished instrument encountered
-                (Some(mut acc), Some(s)) => {
-                    acc.push(s);
-                    Some(acc)
+        let samples = match &mut self.blocks {
+            None => self
+                .instruments
+                .iter()
+                .zip(self.resamplers.iter_mut())
+                .map(|(instrument, resampler)| Ok(pull(instrument, resampler, 1)?.remove(0)))
+                .collect::<Result<Vec<_>, EngineError>>()?,
+            Some(blocks) => {
+                if blocks.remaining == 0 {
+                    // Render up to the next event, after which instruments may sound different
+                    let until = self.next_event.as_ref().map_or(self.duration, |event| {
+                        self.tempo.sample_at(event.0).min(self.duration)
+                    });
+                    let frames = (until - self.sample).min(blocks.frames);
+                    trace!(">> Rendering block of `{frames}` frames");
+                    let (instruments, resamplers) = (&self.instruments, &mut self.resamplers);
+                    blocks.rendered = blocks.pool.install(|| {
+                        instruments
+                            .par_iter()
+                            .zip(resamplers.par_iter_mut())
+                            .map(|(instrument, resampler)| {
+                                pull(instrument, resampler, frames).map(Vec::into_iter)
+                            })
+                            .collect::<Result<_, _>>()
+                    })?;
+                    blocks.remaining = frames;
                 }
-            };
-        }
+                blocks.remaining -= 1;
+                blocks
+                    .rendered
+                    .iter_mut()
+                    .map(|rendered| rendered.next().expect("rendered every frame of the block"))
+                    .collect()
+            }
+        };
+        self.sample += 1;
+
+        // Finished instruments are left out, and only once all of them have finished is there
+        // nothing left to render
+        let samples: Vec<Sample> = samples.into_iter().flatten().collect();
+        Ok((!samples.is_empty()).then_some(samples))
+    }
+}
 
-        Ok(samples)
+/// Next `frames` samples of an instrument, through its resampler and filters. Sources that know
+/// their number of channels and aren't resampled fill them all at once. Once a source has
+/// finished, its filters ring out until their tails have decayed
+fn pull(
+    instrument: &PackagedInstrument,
+    resampler: &mut Option<Resampler>,
+    frames: usize,
+) -> Result<Vec<Option<Sample>>, EngineError> {
+    let source = &instrument.factory.0;
+    match (resampler, source.channels()) {
+        (None, Some(channels)) if channels > 0 => {
+            let mut buffer = vec![0.; frames * channels];
+            let filled = source
+                .fill_buffer(&mut buffer, channels)
+                .map_err(EngineError::Source)?;
+            Ok(instrument
+                .filters
+                .process_frames(&buffer[..filled * channels], channels)
+                .into_iter()
+                .map(|frame| Some(Sample::F32(frame)))
+                .chain((filled..frames).map(|_| instrument.filters.ring().map(Sample::F32)))
+                .collect())
+        }
+        (resampler, _) => (0..frames)
+            .map(|_| {
+                Ok(match resampler {
+                    Some(resampler) => resampler.next_sample(|| source.next_sample()),
+                    None => source.next_sample(),
+                }
+                .map_err(EngineError::Source)?
+                .map_or_else(
+                    || instrument.filters.ring().map(Sample::F32),
+                    |sample| Some(instrument.filters.apply(sample)),
+                ))
+            })
+            .collect(),
     }
 }
 
+/// Instruments rendered a block at a time on a thread-pool, see [`Engine::in_blocks`](Engine::in_blocks)
+#[derive(Debug)]
+struct Blocks {
+    frames: usize,
+    pool: rayon::ThreadPool,
+    /// Samples of every instrument rendered for the current block
+    rendered: Vec<std::vec::IntoIter<Option<Sample>>>,
+    /// Frames of the current block not yet yielded
+    remaining: usize,
+}
+
 #[derive(Debug)]
 pub enum EngineError {
     Emit(String),
+    Prepare(String),
     Source(SourceError<String>),
+    ThreadPool(String),
     UnsortedEventStream,
 }
 
@@ -366,7 +465,9 @@ impl fmt::Display for EngineError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             EngineError::Emit(err) => write!(f, "emit error: {err}"),
+            EngineError::Prepare(err) => write!(f, "prepare error: {err}"),
             EngineError::Source(err) => write!(f, "source error: {err}"),
+            EngineError::ThreadPool(err) => write!(f, "thread-pool error: {err}"),
             EngineError::UnsortedEventStream => write!(f, "unsorted-event-stream received"),
         }
     }
@@ -374,7 +475,7 @@ impl fmt::Display for EngineError {
 
 impl<I> Iterator for Engine<I>
 where
-    I: Iterator<Item = (usize, EmittableUserData)>,
+    I: Iterator<Item = (Position, EmittableUserData)>,
 {
     type Item = Result<Vec<Sample>, EngineError>;
 
@@ -385,138 +486,350 @@ where
 
 impl<I> Engine<I>
 where
-    I: Iterator<Item = (usize, EmittableUserData)>,
+    I: Iterator<Item = (Position, EmittableUserData)>,
 {
     pub fn new(
         instruments: Vec<PackagedInstrument>,
         event_stream: I,
-        unit_interval: usize,
+        tempo: TempoMap,
         sample_bound: usize,
     ) -> Self {
         info!(
-            "Engine created: interval:`{unit_interval}`, instruments:`{num_instruments}`, sample_bound:`{sample_bound}`",
+            "Engine created: tempo:`{tempo:?}`, instruments:`{num_instruments}`, sample_bound:`{sample_bound}`",
             num_instruments = instruments.len(),
         );
         Engine {
+            resamplers: instruments.iter().map(|_| None).collect(),
             instruments,
             event_stream,
             next_event: None,
-            index: 0,
-            interval: unit_interval,
-            unit_interval,
+            tempo,
+            bpm: None,
+            sample: 0,
             duration: sample_bound,
+            blocks: None,
         }
     }
 }
 
+impl<I> Engine<I> {
+    /// Prepare every instrument to be rendered at `sample_rate`, resampling the ones that can't
+    pub fn at_sample_rate(
+        mut self,
+        sample_rate: u32,
+        interpolation: Interpolation,
+    ) -> Result<Self, EngineError> {
+        self.resamplers = self
+            .instruments
+            .iter()
+            .map(|instrument| {
+                let source = &instrument.factory.0;
+                source.prepare(sample_rate).map_err(EngineError::Prepare)?;
+                Ok(match source.sample_rate() {
+                    Some(rate) if rate != sample_rate => {
+                        info!("Resampling `{source:?}` from {rate}Hz to {sample_rate}Hz");
+                        Some(Resampler::new(rate, sample_rate, interpolation))
+                    }
+                    _ => None,
+                })
+            })
+            .collect::<Result<_, EngineError>>()?;
+        Ok(self)
+    }
+
+    /// Render instruments up to `frames` at a time, or up to the next event, in parallel on
+    /// `threads` threads (default: one per core). The output is identical to rendering them one
+    /// sample at a time
+    pub fn in_blocks(mut self, frames: usize, threads: Option<usize>) -> Result<Self, EngineError> {
+        // Instruments sharing a source would pull its samples in a different order
+        let shared = self.instruments.iter().enumerate().any(|(i, a)| {
+            self.instruments[..i]
+                .iter()
+                .any(|b| Arc::ptr_eq(&a.factory.0, &b.factory.0))
+        });
+        if shared {
+            warn!("Some instruments are the same instrument, rendering one sample at a time");
+            return Ok(self);
+        }
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.unwrap_or(0))
+            .build()
+            .map_err(|err| EngineError::ThreadPool(err.to_string()))?;
+        info!(
+            "Rendering blocks of `{frames}` frames on `{}` threads",
+            pool.current_num_threads()
+        );
+        self.blocks = Some(Blocks {
+            frames: frames.max(1),
+            pool,
+            rendered: Vec::new(),
+            remaining: 0,
+        });
+        Ok(self)
+    }
+}
+
 pub fn is_event(value: &mlua::Value) -> bool {
     // TODO checks whether a LuaValue is an event *by convention* instead of just checking that its
     // userdata
     matches!(value, mlua::Value::UserData(_))
 }
 
-mod encoder {
-    // use crate::Sample;
-    // use hound::SampleFormat;
-    // use std::io::{Seek, Write};
-
-    // use hound::Sample;
-
-    // fn encode(samples: &[Vec<Sample>]) {
-    //     let spec = hound::WavSpec {
-    //         channels: samples[0].len() as u16,
-    //         sample_rate: 44100,
-    //         bits_per_sample: 16,
-    //         sample_format: hound::SampleFormat::Int,
-    //     };
-    //     let mut writer = hound::WavWriter::create("sine.wav", spec).unwrap();
-    //     samples
-    //         .iter()
-    //         .map(|sample| writer.write_sample(sample).unwrap());
-    // }
-
-    // impl hound::Sample for Sample {
-    //     fn write<W: std::io::Write>(self, writer: &mut W, bits: u16) -> hound::Result<()> {
-    //         match self {
-    //             Sample::U8(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::U16(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i16).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::U24(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::U32(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::S8(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::S16(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::S24(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::S32(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::F32(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::F64(samples) => samples
-    //                 .iter()
-    //                 .map(|sample| (*sample as i8).write(writer, bits))
-    //                 .collect::<hound::Result<_>>(),
-    //             Sample::Empty => {
-    //                 todo!();
-    //                 Ok(())
-    //             }
-    //         }
-    //     }
-
-    //     fn write_padded<W: std::io::Write>(
-    //         self,
-    //         writer: &mut W,
-    //         bits: u16,
-    //         byte_width: u16,
-    //     ) -> hound::Result<()> {
-    //         todo!()
-    //     }
-
-    //     fn read<R: std::io::Read>(
-    //         reader: &mut R,
-    //         fmt: SampleFormat,
-    //         bytes: u16,
-    //         bits: u16,
-    //     ) -> hound::Result<Self> {
-    //         todo!()
-    //     }
-
-    //     fn as_i16(self) -> i16 {
-    //         todo!()
-    //     }
-    // }
-
-    /* trait Encoder<S: Sample, W: Write + Seek> {
-        fn write_samples(&mut self, writer: W, samples: &[S]);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        filter::{FilterChain, PlunderFilter, SharedPlunderFilter, TAIL_FLOOR},
+        instrument::{Emit, InstrumentError, PlunderInstrument, SharedPlunderInstrument},
+    };
+
+    /// Every sample survives normalization and back, and normalizes into `-1.0..1.0`
+    fn round_trip(sample: Sample) {
+        let normalized = sample.to_f64().unwrap();
+        assert!(normalized.iter().all(|c| (-1. ..1.).contains(c)));
+        let back = Sample::from_f64(sample.sample_type().unwrap(), &normalized);
+        assert_eq!(format!("{back:?}"), format!("{sample:?}"));
     }
 
-    struct WavEncoder;
+    #[test]
+    fn unsigned_round_trip() {
+        round_trip(Sample::U8(vec![0, 1, 127, 128, 129, u8::MAX]));
+        round_trip(Sample::U16(vec![0, 1, 32767, 32768, u16::MAX]));
+        round_trip(Sample::U24(vec![
+            0,
+            1,
+            (1 << 23) - 1,
+            1 << 23,
+            (1 << 24) - 1,
+        ]));
+        round_trip(Sample::U32(vec![0, 1, (1 << 31) - 1, 1 << 31, u32::MAX]));
+    }
 
-    impl<S: Sample, W: Write + Seek> Encoder<S, W> for WavEncoder {
-        fn write_samples(&mut self, writer: W, samples: &[S]) {
-            // let wav_writer()
+    #[test]
+    fn signed_round_trip() {
+        round_trip(Sample::S8(vec![i8::MIN, -1, 0, 1, i8::MAX]));
+        round_trip(Sample::S16(vec![i16::MIN, -1, 0, 1, i16::MAX]));
+        round_trip(Sample::S24(vec![-(1 << 23), -1, 0, 1, (1 << 23) - 1]));
+        round_trip(Sample::S32(vec![i32::MIN, -1, 0, 1, i32::MAX]));
+    }
+
+    #[test]
+    fn float_round_trip() {
+        round_trip(Sample::F32(vec![-1., -0.5, 0., 0.25, 0.999]));
+        round_trip(Sample::F64(vec![-1., -0.5, 0., 0.25, 0.999_999]));
+    }
+
+    #[test]
+    fn formats_agree_on_amplitude() {
+        let half = [
+            Sample::U8(vec![192]),
+            Sample::U16(vec![49152]),
+            Sample::U24(vec![3 << 22]),
+            Sample::U32(vec![3 << 30]),
+            Sample::S8(vec![64]),
+            Sample::S16(vec![16384]),
+            Sample::S24(vec![1 << 22]),
+            Sample::S32(vec![1 << 30]),
+            Sample::F32(vec![0.5]),
+            Sample::F64(vec![0.5]),
+        ];
+        half.iter()
+            .for_each(|sample| assert_eq!(sample.to_f64(), Some(vec![0.5]), "{sample:?}"));
+        assert_eq!(Sample::Empty.to_f64(), None);
+    }
+
+    /// A sawtooth whose pitch is set by events, that stops after a number of samples. Only
+    /// `native` ones fill buffers themselves
+    #[derive(Debug)]
+    struct Saw(RwLock<(f64, f64, usize)>, bool);
+
+    impl PlunderInstrument for Saw {
+        fn next_sample(&self) -> Result<Option<Sample>, SourceError<String>> {
+            let (phase, step, left) = &mut *self.0.write().unwrap();
+            if *left == 0 {
+                return Ok(None);
+            }
+            *left -= 1;
+            *phase = (*phase + *step).fract();
+            Ok(Some(Sample::F32(vec![(*phase * 2. - 1.) as f32])))
+        }
+
+        fn tempo(&self, _: f64) {}
+
+        fn unit(&self, _: f64) {}
+
+        fn channels(&self) -> Option<usize> {
+            self.1.then_some(1)
+        }
+
+        fn fill_buffer(&self, buffer: &mut [f32], _: usize) -> Result<usize, SourceError<String>> {
+            let (phase, step, left) = &mut *self.0.write().unwrap();
+            let filled = buffer.len().min(*left);
+            *left -= filled;
+            buffer[..filled].iter_mut().for_each(|s| {
+                *phase = (*phase + *step).fract();
+                *s = (*phase * 2. - 1.) as f32;
+            });
+            Ok(filled)
+        }
+
+        fn sample_rate(&self) -> Option<u32> {
+            None
+        }
+
+        fn prepare(&self, _: u32) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn transform(&self, event: mlua::Value) -> Result<(), InstrumentError> {
+            // Dropping a Lua value needs a Lua state, which tests don't link
+            std::mem::forget(event);
+            unimplemented!()
+        }
+
+        fn help(&self) -> String {
+            String::new()
+        }
+    }
+
+    /// Sets the step of a [`Saw`](Saw)
+    struct SetStep(Arc<Saw>, f64);
+
+    impl Emit for SetStep {
+        fn emit(&mut self) -> Result<(), String> {
+            self.0 .0.write().unwrap().1 = self.1;
+            Ok(())
+        }
+
+        fn instrument_help(&self) -> String {
+            String::new()
+        }
+    }
+
+    fn render(blocks: Option<usize>) -> Vec<Vec<f64>> {
+        let saws: Vec<_> = [(700, true), (1000, false), (300, true)]
+            .into_iter()
+            .map(|(left, native)| Arc::new(Saw(RwLock::new((0., 0.01, left)), native)))
+            .collect();
+        let instruments = saws
+            .iter()
+            .map(|saw| PackagedInstrument {
+                factory: SharedPlunderInstrument(saw.clone()),
+                manual: Arc::from(""),
+                filters: FilterChain::default(),
+            })
+            .collect();
+        let events = (0..12).map(|i| {
+            let event = SetStep(saws[i % 3].clone(), 0.001 * i as f64);
+            (
+                Position::Sample(i * 77 + i % 2),
+                EmittableUserData::from(event),
+            )
+        });
+        let engine = Engine::new(
+            instruments,
+            events,
+            TempoMap::from_interval(100, 44100).unwrap(),
+            900,
+        );
+        let engine = match blocks {
+            Some(frames) => engine.in_blocks(frames, Some(3)).unwrap(),
+            None => engine,
+        };
+        engine
+            .map(|samples| {
+                samples
+                    .unwrap()
+                    .iter()
+                    .flat_map(|sample| sample.to_f64().unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Feeds half of each output back into the next, up-mixing to stereo
+    #[derive(Debug, Default)]
+    struct Decay(RwLock<Vec<f32>>);
+
+    impl PlunderFilter for Decay {
+        fn prepare(&self, _: u32) {}
+
+        fn process(&self, frame: &mut [f32]) {
+            let mut states = self.0.write().unwrap();
+            states.resize(frame.len(), 0.);
+            frame
+                .iter_mut()
+                .zip(states.iter_mut())
+                .for_each(|(x, state)| {
+                    *state = *x + *state * 0.5;
+                    *x = *state;
+                });
         }
-    } */
+
+        fn channels(&self, input: usize) -> usize {
+            input.max(2)
+        }
+
+        fn ringing(&self) -> bool {
+            self.0
+                .read()
+                .unwrap()
+                .iter()
+                .any(|state| state.abs() > TAIL_FLOOR)
+        }
+
+        fn transform(&self, event: mlua::Value) -> Result<(), InstrumentError> {
+            std::mem::forget(event);
+            unimplemented!()
+        }
+
+        fn help(&self) -> String {
+            String::new()
+        }
+    }
+
+    #[test]
+    fn filters_ring_out_after_sources_finish() {
+        for (native, blocks) in [(true, None), (false, None), (true, Some(256))] {
+            let saw = Arc::new(Saw(RwLock::new((0., 0.25, 4)), native));
+            let decay = SharedPlunderFilter(Arc::new(Decay::default()));
+            let instrument = PackagedInstrument {
+                factory: SharedPlunderInstrument(saw),
+                manual: Arc::from(""),
+                filters: FilterChain::new(vec![decay]),
+            };
+            let engine = Engine::new(
+                vec![instrument],
+                std::iter::empty(),
+                TempoMap::from_interval(100, 44100).unwrap(),
+                10_000,
+            );
+            let engine = match blocks {
+                Some(frames) => engine.in_blocks(frames, Some(2)).unwrap(),
+                None => engine,
+            };
+            let frames: Vec<Vec<f64>> = engine
+                .map(|samples| samples.unwrap()[0].to_f64().unwrap())
+                .collect();
+            // Mono up-mixed to stereo, ringing after the 4 frames of the saw until a check finds
+            // the tail has decayed
+            assert!(frames.iter().all(|frame| frame.len() == 2));
+            assert_eq!(
+                frames.len(),
+                4 + 1024,
+                "native: {native}, blocks: {blocks:?}"
+            );
+            assert!(frames[4][0].abs() > 0.1);
+            assert!(frames[1000][0].abs() < TAIL_FLOOR as f64);
+        }
+    }
+
+    #[test]
+    fn blocks_render_like_samples() {
+        let serial = render(None);
+        assert_eq!(serial.len(), 900);
+        assert_eq!(serial, render(Some(64)));
+        assert_eq!(serial, render(Some(1)));
+        assert_eq!(serial, render(Some(4096)));
+    }
 }
@@ -0,0 +1,127 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use anyhow::anyhow;
+use log::{info, trace};
+
+use crate::{
+    filter::FilterChain,
+    instrument::{EmittableUserData, PackagedInstrument},
+    mixer::{Mixer, MixerOptions},
+    tempo::{Position, TempoMap},
+    Engine, Sample,
+};
+
+/// The output of an [`Engine`](Engine) mixed down into frames of normalized samples
+#[derive(Debug)]
+pub struct Mixdown<I> {
+    /// `None` once every instrument has finished
+    engine: Option<Engine<I>>,
+    mixer: Mixer,
+    master: FilterChain,
+    hasher: DefaultHasher,
+    /// Frames mixed so far, and the most there can be
+    frames: usize,
+    sample_bound: usize,
+}
+
+impl<I> Mixdown<I>
+where
+    I: Iterator<Item = (Position, EmittableUserData)>,
+{
+    pub fn new(
+        instruments: Vec<PackagedInstrument>,
+        (master, mixer): (FilterChain, &MixerOptions),
+        sorted_event_stream: I,
+        bitrate: u32,
+        tempo: TempoMap,
+        sample_bound: usize,
+    ) -> anyhow::Result<Self> {
+        let interpolation = mixer.resampler.unwrap_or_default();
+        let mixer = Mixer::new(mixer, bitrate)?;
+        let channels = mixer.layout().channels();
+        if master.channels(channels) != channels {
+            return Err(anyhow!(
+                "the master filters need {} channels but the mix has {channels}, set the \
+                `channels` option to mix to as many",
+                master.channels(channels)
+            ));
+        }
+        instruments
+            .iter()
+            .for_each(|instrument| instrument.filters.prepare(bitrate, mixer.pan_law()));
+        master.prepare(bitrate, mixer.pan_law());
+
+        let engine = Engine::new(instruments, sorted_event_stream, tempo, sample_bound)
+            .at_sample_rate(bitrate, interpolation)
+            .map_err(|err| anyhow!("engine error: {err}"))?;
+
+        info!("Mixing down to {:?}", mixer.layout());
+        Ok(Mixdown {
+            engine: Some(engine),
+            mixer,
+            master,
+            hasher: DefaultHasher::new(),
+            frames: 0,
+            sample_bound,
+        })
+    }
+
+    /// Render instruments a block at a time on a thread-pool, see
+    /// [`Engine::in_blocks`](Engine::in_blocks)
+    pub fn in_blocks(mut self, frames: usize, threads: Option<usize>) -> anyhow::Result<Self> {
+        self.engine = self
+            .engine
+            .map(|engine| engine.in_blocks(frames, threads))
+            .transpose()
+            .map_err(|err| anyhow!("engine error: {err}"))?;
+        Ok(self)
+    }
+
+    pub fn num_channels(&self) -> usize {
+        self.mixer.layout().channels()
+    }
+
+    /// Hash of every frame mixed so far
+    pub fn hash(&self) -> u64 {
+        self.hasher.finish()
+    }
+}
+
+impl<I> Iterator for Mixdown<I>
+where
+    I: Iterator<Item = (Position, EmittableUserData)>,
+{
+    type Item = anyhow::Result<Vec<f64>>;
+
+    fn next(&mut self) -> Option<anyhow::Result<Vec<f64>>> {
+        let next = self.engine.as_mut().and_then(Engine::next);
+        let mut frame = match next {
+            Some(Ok(samples)) => {
+                let mut frame = self.mixer.sum(&samples);
+                trace!("mixed samples into `{frame:?}`");
+                if !self.master.is_empty() {
+                    let master = Sample::F32(frame.iter().map(|s| *s as f32).collect());
+                    if let Sample::F32(master) = self.master.apply(master) {
+                        frame = master.into_iter().map(f64::from).collect();
+                    }
+                }
+                frame
+            }
+            Some(Err(err)) => return Some(Err(anyhow!("engine error: {err}"))),
+            // The master filters ring out once every instrument has, up to the duration
+            None => {
+                self.engine = None;
+                if self.frames >= self.sample_bound {
+                    return None;
+                }
+                self.master.ring()?.into_iter().map(f64::from).collect()
+            }
+        };
+        self.frames += 1;
+        self.mixer.clip(&mut frame);
+        frame
+            .iter()
+            .for_each(|s| s.to_bits().hash(&mut self.hasher));
+        Some(Ok(frame))
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use crate::{
+    layout::{ChannelLayout, Remix, DEFAULT_PAN_LAW},
+    resample::Interpolation,
+    Sample,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Clip {
+    /// Saturate smoothly towards full-scale
+    Soft,
+    /// Duck the whole frame just enough to keep its loudest channel at full-scale, recovering
+    /// over `release` seconds
+    Limit,
+}
+
+/// User-facing options of the mixing bus, deserialized from the options table of `render` & `play`
+///
+/// Without `clip`, samples beyond full-scale are left for the encoder or sink to clip
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MixerOptions {
+    /// Number of channels of the mix, every instrument is up/down-mixed to it
+    pub channels: Option<u16>,
+    /// Decibels a mono instrument is attenuated by in each side of a stereo mix
+    pub pan_law: Option<f64>,
+    /// Decibels the sum of all instruments is attenuated by
+    pub headroom: Option<f64>,
+    pub clip: Option<Clip>,
+    /// Seconds the limiter takes to recover from ducking
+    pub release: Option<f64>,
+    /// How instruments that aren't at the rate being rendered at are resampled
+    pub resampler: Option<Interpolation>,
+}
+
+/// Sums samples of any type into frames of normalized samples
+#[derive(Debug)]
+pub struct Mixer {
+    remix: Remix,
+    pan_law: f64,
+    gain: f64,
+    clip: Option<Clip>,
+    /// Per-frame recovery of the limiter's gain
+    release: f64,
+    /// Gain the limiter is currently applying
+    envelope: f64,
+}
+
+impl Mixer {
+    pub fn new(options: &MixerOptions, sample_rate: u32) -> anyhow::Result<Self> {
+        let headroom = options.headroom.unwrap_or(0.);
+        if headroom < 0. {
+            return Err(anyhow!("headroom must not be negative, not `{headroom}`"));
+        }
+        let channels = options.channels.unwrap_or(2);
+        if channels == 0 {
+            return Err(anyhow!("cannot mix into 0 channels"));
+        }
+        let release = options.release.unwrap_or(0.05);
+        if release <= 0. {
+            return Err(anyhow!("release must be a positive number of seconds"));
+        }
+        let pan_law = options.pan_law.unwrap_or(DEFAULT_PAN_LAW);
+        Ok(Mixer {
+            remix: Remix::new(ChannelLayout::from_channels(channels as usize), pan_law),
+            pan_law,
+            gain: 10f64.powf(-headroom / 20.),
+            clip: options.clip,
+            release: 1. - (-1. / (release * sample_rate as f64)).exp(),
+            envelope: 1.,
+        })
+    }
+
+    pub fn layout(&self) -> ChannelLayout {
+        self.remix.layout()
+    }
+
+    /// Decibels that mono is attenuated by in each side when up-mixed to stereo
+    pub fn pan_law(&self) -> f64 {
+        self.pan_law
+    }
+
+    /// Sum the samples of all instruments in the layout of the mix, attenuated by the headroom
+    pub fn sum(&self, samples: &[Sample]) -> Vec<f64> {
+        let mut sum = vec![0.; self.remix.layout().channels()];
+        samples
+            .iter()
+            .filter_map(Sample::to_f64)
+            .for_each(|frame| self.remix.add(&frame, &mut sum));
+        sum.iter_mut().for_each(|s| *s *= self.gain);
+        sum
+    }
+
+    /// Apply the configured clipping to a mixed frame, this should be the last stage before the
+    /// frame is encoded or played
+    pub fn clip(&mut self, frame: &mut [f64]) {
+        match self.clip {
+            None => (),
+            Some(Clip::Soft) => frame.iter_mut().for_each(|s| *s = s.tanh()),
+            Some(Clip::Limit) => {
+                let peak = frame.iter().fold(0f64, |peak, s| peak.max(s.abs()));
+                let target = if peak > 1. { 1. / peak } else { 1. };
+                self.envelope = if target < self.envelope {
+                    target
+                } else {
+                    self.envelope + (target - self.envelope) * self.release
+                };
+                frame.iter_mut().for_each(|s| *s *= self.envelope);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mixer(options: MixerOptions) -> Mixer {
+        Mixer::new(&options, 1000).unwrap()
+    }
+
+    #[test]
+    fn sums_any_sample_types() {
+        let mixer = mixer(MixerOptions::default());
+        let samples = [
+            Sample::S16(vec![8192, -8192]),
+            Sample::F32(vec![0.25, 0.5]),
+            Sample::Empty,
+            Sample::U8(vec![128, 192]),
+        ];
+        assert_eq!(mixer.sum(&samples), vec![0.5, 0.75]);
+        assert_eq!(mixer.sum(&[Sample::Empty]), vec![0., 0.]);
+    }
+
+    #[test]
+    fn mixes_differing_channel_counts() {
+        let stereo = mixer(MixerOptions {
+            pan_law: Some(6.),
+            ..Default::default()
+        });
+        let sum = stereo.sum(&[Sample::F32(vec![0.5]), Sample::F32(vec![0.25, 0.])]);
+        assert!((sum[0] - 0.5).abs() < 1e-3 && (sum[1] - 0.25).abs() < 1e-3);
+
+        let mono = mixer(MixerOptions {
+            channels: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(mono.sum(&[Sample::F32(vec![0.5, 0.25])]), vec![0.375]);
+    }
+
+    #[test]
+    fn headroom_attenuates() {
+        let mixer = mixer(MixerOptions {
+            headroom: Some(20.),
+            ..Default::default()
+        });
+        let sum = mixer.sum(&[Sample::F64(vec![1., 1.])]);
+        assert!((sum[0] - 0.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn soft_clip_stays_in_range() {
+        let mut mixer = mixer(MixerOptions {
+            clip: Some(Clip::Soft),
+            ..Default::default()
+        });
+        let mut frame = [4., -4., 0.];
+        mixer.clip(&mut frame);
+        assert!(frame.iter().all(|s| s.abs() < 1.));
+        assert_eq!(frame[2], 0.);
+    }
+
+    #[test]
+    fn limiter_ducks_then_recovers() {
+        let mut mixer = mixer(MixerOptions {
+            clip: Some(Clip::Limit),
+            ..Default::default()
+        });
+        let mut frame = [2., -1.];
+        mixer.clip(&mut frame);
+        assert_eq!(frame, [1., -0.5]);
+
+        let mut frame = [0.5, 0.5];
+        mixer.clip(&mut frame);
+        assert!(frame[0] < 0.5);
+        (0..1000).for_each(|_| mixer.clip(&mut [0.5]));
+        let mut frame = [0.5];
+        mixer.clip(&mut frame);
+        assert!((frame[0] - 0.5).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,321 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::encoder::EncoderSpec;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// The default output device of the system
+    Device,
+    /// Discards everything, optionally at the pace of a real device
+    Null,
+}
+
+impl Default for Backend {
+    /// The device when plunder is built with the `device` feature, or else the null backend, so
+    /// that `play` works in a default build too
+    fn default() -> Self {
+        if cfg!(feature = "device") {
+            Backend::Device
+        } else {
+            Backend::Null
+        }
+    }
+}
+
+/// User-facing options that pick an audio sink, deserialized from the options table of `play`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PlayerOptions {
+    pub backend: Option<Backend>,
+    /// Pace the null backend as if it were a device playing at the stream's sample rate
+    pub realtime: bool,
+    /// Seconds of audio that may be queued ahead of the device
+    pub latency: Option<f64>,
+}
+
+/// An AudioSink consumes frames of normalized samples (one per channel, nominally in
+/// `-1.0..=1.0`) and plays them
+///
+/// Sinks that play in real-time block in `write_frame` while they are too far ahead of playback,
+/// so the engine never renders further ahead than the sink's latency
+pub trait AudioSink {
+    fn write_frame(&mut self, frame: &[f64]) -> anyhow::Result<()>;
+    /// Block until every frame written so far has been played
+    fn drain(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+/// Open the sink described by `options`
+pub fn open(spec: EncoderSpec, options: &PlayerOptions) -> anyhow::Result<Box<dyn AudioSink>> {
+    let backend = options.backend.unwrap_or_default();
+    if options.backend.is_none() && backend == Backend::Null {
+        warn!("plunder was built without the `device` feature, playing to the `null` backend");
+    }
+    info!("Opening {backend:?} audio sink: {spec:?}");
+
+    Ok(match backend {
+        #[cfg(feature = "device")]
+        Backend::Device => Box::new(device::DeviceSink::open(
+            spec,
+            options.latency.unwrap_or(0.1),
+        )?),
+        #[cfg(not(feature = "device"))]
+        Backend::Device => {
+            return Err(anyhow!(
+                "plunder was built without audio device support, rebuild it with the `device` \
+                feature or play to the `null` backend"
+            ))
+        }
+        Backend::Null => Box::new(NullSink::new(spec, options.realtime)),
+    })
+}
+
+/// Sink that discards every frame
+#[derive(Debug)]
+pub struct NullSink {
+    spec: EncoderSpec,
+    frames: u64,
+    started: Option<Instant>,
+}
+
+impl NullSink {
+    pub fn new(spec: EncoderSpec, realtime: bool) -> Self {
+        NullSink {
+            spec,
+            frames: 0,
+            started: realtime.then(Instant::now),
+        }
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    fn played_at(&self, frames: u64) -> Duration {
+        Duration::from_secs_f64(frames as f64 / self.spec.sample_rate as f64)
+    }
+}
+
+impl AudioSink for NullSink {
+    fn write_frame(&mut self, frame: &[f64]) -> anyhow::Result<()> {
+        if frame.len() != self.spec.channels as usize {
+            return Err(anyhow!(
+                "frame has `{}` channels, sink was opened with `{}`",
+                frame.len(),
+                self.spec.channels
+            ));
+        }
+        self.frames += 1;
+        if let Some(started) = self.started {
+            if let Some(ahead) = self.played_at(self.frames).checked_sub(started.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+        Ok(())
+    }
+
+    fn drain(self: Box<Self>) -> anyhow::Result<()> {
+        info!("Null sink drained after `{}` frames", self.frames);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "device")]
+mod device {
+    use std::{
+        collections::VecDeque,
+        fmt,
+        sync::{Arc, Condvar, Mutex, MutexGuard},
+        thread,
+        time::Duration,
+    };
+
+    use anyhow::{anyhow, Context};
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use log::{info, warn};
+
+    use super::AudioSink;
+    use crate::encoder::EncoderSpec;
+
+    /// Time the device may go without taking samples from a full queue before it's given up on
+    const STALL: Duration = Duration::from_secs(2);
+
+    /// Samples queued for the device, shared with its callbacks
+    type Queue = Arc<(Mutex<Queued>, Condvar)>;
+
+    struct Queued {
+        /// Interleaved samples yet to be played
+        samples: VecDeque<f32>,
+        /// Error the device stopped playing with, if it did
+        failed: Option<String>,
+    }
+
+    /// Sink that plays to the default output device of the system's default host
+    pub struct DeviceSink {
+        stream: cpal::Stream,
+        queue: Queue,
+        channels: usize,
+        capacity: usize,
+        latency: Duration,
+    }
+
+    impl DeviceSink {
+        pub fn open(spec: EncoderSpec, latency: f64) -> anyhow::Result<Self> {
+            let host = cpal::default_host();
+            let device = host
+                .default_output_device()
+                .context("no default output device")?;
+            info!(
+                "Playing to `{}` on host `{}`",
+                device.name().unwrap_or_else(|_| "<unnamed>".to_string()),
+                host.id().name()
+            );
+
+            let capacity =
+                ((latency * spec.sample_rate as f64) as usize).max(1) * spec.channels as usize;
+            let queue: Queue = Arc::new((
+                Mutex::new(Queued {
+                    samples: VecDeque::with_capacity(capacity),
+                    failed: None,
+                }),
+                Condvar::new(),
+            ));
+
+            let callback_queue = queue.clone();
+            let error_queue = queue.clone();
+            let stream = device
+                .build_output_stream(
+                    &cpal::StreamConfig {
+                        channels: spec.channels,
+                        sample_rate: cpal::SampleRate(spec.sample_rate),
+                        buffer_size: cpal::BufferSize::Default,
+                    },
+                    move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                        let (lock, condvar) = &*callback_queue;
+                        let mut queue = lock.lock().unwrap();
+                        for out in data.iter_mut() {
+                            // Underruns are played as silence
+                            *out = queue.samples.pop_front().unwrap_or(0.);
+                        }
+                        condvar.notify_all();
+                    },
+                    move |err| {
+                        warn!("audio device error: {err}");
+                        // Whatever is waiting on the device would otherwise wait forever
+                        let (lock, condvar) = &*error_queue;
+                        if let Ok(mut queue) = lock.lock() {
+                            queue.failed = Some(err.to_string());
+                        }
+                        condvar.notify_all();
+                    },
+                    None,
+                )
+                .context("while opening audio device")?;
+            stream.play().context("while starting audio device")?;
+
+            Ok(DeviceSink {
+                stream,
+                queue,
+                channels: spec.channels as usize,
+                capacity,
+                latency: Duration::from_secs_f64(latency),
+            })
+        }
+    }
+
+    impl fmt::Debug for DeviceSink {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "DeviceSink( capacity: {} )", self.capacity)
+        }
+    }
+
+    impl DeviceSink {
+        /// Wait for the device to play samples off the queue until `waiting` no longer holds,
+        /// failing if the device stopped playing instead
+        fn wait<F>(&self, mut waiting: F) -> anyhow::Result<MutexGuard<'_, Queued>>
+        where
+            F: FnMut(&VecDeque<f32>) -> bool,
+        {
+            let (lock, condvar) = &*self.queue;
+            let (queue, timeout) = condvar
+                .wait_timeout_while(
+                    lock.lock().map_err(|_| anyhow!("device queue poisoned"))?,
+                    self.latency + STALL,
+                    |queue| queue.failed.is_none() && waiting(&queue.samples),
+                )
+                .map_err(|_| anyhow!("device queue poisoned"))?;
+            if let Some(err) = &queue.failed {
+                return Err(anyhow!("audio device stopped playing: {err}"));
+            }
+            if timeout.timed_out() {
+                return Err(anyhow!(
+                    "audio device stopped playing, it took no samples for {:?}",
+                    self.latency + STALL
+                ));
+            }
+            Ok(queue)
+        }
+    }
+
+    impl AudioSink for DeviceSink {
+        fn write_frame(&mut self, frame: &[f64]) -> anyhow::Result<()> {
+            if frame.len() != self.channels {
+                return Err(anyhow!(
+                    "frame has `{}` channels, sink was opened with `{}`",
+                    frame.len(),
+                    self.channels
+                ));
+            }
+            let mut queue = self.wait(|samples| samples.len() + frame.len() > self.capacity)?;
+            queue.samples.extend(frame.iter().map(|s| *s as f32));
+            Ok(())
+        }
+
+        fn drain(self: Box<Self>) -> anyhow::Result<()> {
+            drop(self.wait(|samples| !samples.is_empty())?);
+            // The last callback's buffer is still being played by the device
+            thread::sleep(self.latency);
+            self.stream.pause().context("while stopping audio device")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: EncoderSpec = EncoderSpec {
+        channels: 2,
+        sample_rate: 1000,
+    };
+
+    #[test]
+    fn opens_the_default_backend() {
+        #[cfg(not(feature = "device"))]
+        {
+            assert_eq!(Backend::default(), Backend::Null);
+            let mut sink = open(SPEC, &PlayerOptions::default()).unwrap();
+            sink.write_frame(&[0.5, -0.5]).unwrap();
+            assert!(sink.write_frame(&[1.]).is_err());
+            sink.drain().unwrap();
+        }
+        #[cfg(feature = "device")]
+        assert_eq!(Backend::default(), Backend::Device);
+    }
+
+    #[test]
+    fn null_sink_paces_in_realtime() {
+        let mut sink = NullSink::new(SPEC, true);
+        let started = Instant::now();
+        (0..100).for_each(|_| sink.write_frame(&[0., 0.]).unwrap());
+        assert_eq!(sink.frames(), 100);
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+}
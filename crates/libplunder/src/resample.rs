@@ -0,0 +1,193 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use serde::Deserialize;
+
+use crate::Sample;
+
+/// Zero-crossings of the sinc kernel on either side of its center, at the input's rate
+const ZERO_CROSSINGS: f64 = 16.;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    /// Band-limited interpolation with a Blackman-windowed sinc kernel
+    #[default]
+    Sinc,
+    /// Straight lines between neighbouring samples, cheap but aliases
+    Linear,
+}
+
+/// Pulls samples from a source at one rate and produces them at another
+///
+/// The sinc kernel looks ahead of the frame being produced by a few milliseconds, so events on a
+/// resampled source take effect that much later
+#[derive(Debug)]
+pub struct Resampler {
+    interpolation: Interpolation,
+    /// Input frames per output frame
+    step: f64,
+    /// Cutoff relative to the input's nyquist, below 1 when downsampling to avoid aliasing
+    cutoff: f64,
+    /// Input frames on either side of an output frame that contribute to it
+    half_width: i64,
+    /// Normalized input frames, `None` for empty samples
+    frames: VecDeque<Option<Vec<f64>>>,
+    /// Index in the input of `frames[0]`
+    first: i64,
+    /// Output frames produced so far
+    produced: u64,
+    exhausted: bool,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32, interpolation: Interpolation) -> Self {
+        let step = from as f64 / to as f64;
+        let cutoff = (1. / step).min(1.);
+        Resampler {
+            interpolation,
+            step,
+            cutoff,
+            half_width: match interpolation {
+                Interpolation::Sinc => (ZERO_CROSSINGS / cutoff).ceil() as i64,
+                Interpolation::Linear => 1,
+            },
+            frames: VecDeque::new(),
+            first: 0,
+            produced: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Weight of the input frame `x` input frames away from the output frame
+    fn weight(&self, x: f64) -> f64 {
+        match self.interpolation {
+            Interpolation::Linear => (1. - x.abs()).max(0.),
+            Interpolation::Sinc => {
+                let t = x / self.half_width as f64;
+                if t.abs() >= 1. {
+                    return 0.;
+                }
+                let window = 0.42 + 0.5 * (PI * t).cos() + 0.08 * (2. * PI * t).cos();
+                let x = self.cutoff * x;
+                let sinc = if x == 0. {
+                    1.
+                } else {
+                    (PI * x).sin() / (PI * x)
+                };
+                self.cutoff * sinc * window
+            }
+        }
+    }
+
+    fn frame(&self, index: i64) -> Option<&Vec<f64>> {
+        let offset = usize::try_from(index - self.first).ok()?;
+        self.frames.get(offset)?.as_ref()
+    }
+
+    /// Produce the next sample, pulling as many samples from `pull` as needed. Returns `None` once
+    /// `pull` has and every frame pulled has been resampled
+    pub fn next_sample<E, F>(&mut self, mut pull: F) -> Result<Option<Sample>, E>
+    where
+        F: FnMut() -> Result<Option<Sample>, E>,
+    {
+        let time = self.produced as f64 * self.step;
+        let index = time.floor() as i64;
+        let (low, high) = (index - self.half_width + 1, index + self.half_width);
+
+        while !self.exhausted && self.first + (self.frames.len() as i64) <= high {
+            match pull()? {
+                Some(sample) => self.frames.push_back(sample.to_f64()),
+                None => self.exhausted = true,
+            }
+        }
+        if self.exhausted && index >= self.first + self.frames.len() as i64 {
+            return Ok(None);
+        }
+        while self.first < low && self.frames.pop_front().is_some() {
+            self.first += 1;
+        }
+
+        let mut out: Option<Vec<f64>> = None;
+        let mut total = 0.;
+        for i in low..=high {
+            let weight = self.weight(i as f64 - time);
+            total += weight;
+            if let Some(frame) = self.frame(i) {
+                let out = out.get_or_insert_with(|| vec![0.; frame.len()]);
+                if out.len() < frame.len() {
+                    out.resize(frame.len(), 0.);
+                }
+                out.iter_mut()
+                    .zip(frame)
+                    .for_each(|(o, c)| *o += c * weight);
+            }
+        }
+        self.produced += 1;
+
+        Ok(Some(match out {
+            // Normalize the kernel so that it has no gain at DC
+            Some(mut out) => {
+                out.iter_mut().for_each(|o| *o /= total);
+                Sample::F64(out)
+            }
+            None => Sample::Empty,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resample(input: Vec<f64>, from: u32, to: u32, interpolation: Interpolation) -> Vec<f64> {
+        let mut input = input.into_iter();
+        let mut resampler = Resampler::new(from, to, interpolation);
+        let mut output = Vec::new();
+        while let Some(sample) = resampler
+            .next_sample(|| Ok::<_, ()>(input.next().map(|s| Sample::F64(vec![s]))))
+            .unwrap()
+        {
+            output.push(sample.to_f64().map_or(0., |frame| frame[0]));
+        }
+        output
+    }
+
+    #[test]
+    fn linear_interpolates_between_samples() {
+        let output = resample(vec![0., 1., 0.], 1, 2, Interpolation::Linear);
+        assert_eq!(output, vec![0., 0.5, 1., 0.5, 0., 0.]);
+    }
+
+    #[test]
+    fn keeps_duration() {
+        let output = resample(vec![0.; 48000], 48000, 44100, Interpolation::Sinc);
+        assert_eq!(output.len(), 44100);
+        let output = resample(vec![0.; 22050], 22050, 44100, Interpolation::Linear);
+        assert_eq!(output.len(), 44100);
+    }
+
+    #[test]
+    fn sinc_preserves_tone() {
+        let tone = |rate: u32, frames: usize| {
+            (0..frames)
+                .map(|i| (2. * PI * 1000. * i as f64 / rate as f64).sin() * 0.5)
+                .collect::<Vec<_>>()
+        };
+        let output = resample(tone(48000, 4800), 48000, 44100, Interpolation::Sinc);
+        let expected = tone(44100, 4410);
+        // Away from the edges, where the kernel runs out of input
+        output[100..4300]
+            .iter()
+            .zip(&expected[100..4300])
+            .for_each(|(o, e)| assert!((o - e).abs() < 1e-3, "{o} != {e}"));
+    }
+
+    #[test]
+    fn empty_samples_stay_empty() {
+        let mut resampler = Resampler::new(48000, 44100, Interpolation::Sinc);
+        let sample = resampler
+            .next_sample(|| Ok::<_, ()>(Some(Sample::Empty)))
+            .unwrap();
+        assert!(matches!(sample, Some(Sample::Empty)));
+    }
+}
@@ -0,0 +1,603 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+/// Positions of events in the musical timeline, [`PPQ`](PPQ) to a quarter note
+pub type Tick = u64;
+
+/// Ticks per quarter note
+pub const PPQ: Tick = 960;
+
+/// A fraction of a whole note, e.g. `1/16` for a sixteenth note or `3/8` for three eighths
+fn parse_fraction(s: &str) -> anyhow::Result<(u32, u32)> {
+    let (numerator, denominator) = s
+        .split_once('/')
+        .with_context(|| format!("`{s}` is not a fraction like `1/4`"))?;
+    let numerator = numerator.trim().parse()?;
+    let denominator = denominator.trim().parse()?;
+    if numerator == 0 || denominator == 0 {
+        return Err(anyhow!("`{s}` must not have a zero in it"));
+    }
+    Ok((numerator, denominator))
+}
+
+/// Ticks in `numerator/denominator` of a whole note
+fn fraction_ticks((numerator, denominator): (u32, u32)) -> anyhow::Result<Tick> {
+    let whole = PPQ * 4 * numerator as Tick;
+    if whole % denominator as Tick != 0 {
+        return Err(anyhow!(
+            "`{numerator}/{denominator}` is not a whole number of ticks"
+        ));
+    }
+    Ok(whole / denominator as Tick)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub beats: u32,
+    /// The note value of a beat, e.g. 4 for a quarter note
+    pub value: u32,
+}
+
+impl Signature {
+    pub fn beat_ticks(&self) -> Tick {
+        PPQ * 4 / self.value as Tick
+    }
+
+    pub fn bar_ticks(&self) -> Tick {
+        self.beat_ticks() * self.beats as Tick
+    }
+}
+
+impl Default for Signature {
+    fn default() -> Self {
+        Signature { beats: 4, value: 4 }
+    }
+}
+
+impl FromStr for Signature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (beats, value) = parse_fraction(s)?;
+        fraction_ticks((1, value)).context("beat value too short")?;
+        Ok(Signature { beats, value })
+    }
+}
+
+impl fmt::Display for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.beats, self.value)
+    }
+}
+
+/// A position in bars, beats and ticks, where bars and beats count from 1 like they do in DAWs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarBeatTick {
+    pub bar: u64,
+    pub beat: u64,
+    pub tick: Tick,
+}
+
+impl FromStr for BarBeatTick {
+    type Err = anyhow::Error;
+
+    /// Parse `bar`, `bar:beat` or `bar:beat:tick`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':').map(|part| part.trim().parse::<u64>());
+        let mut next = |default| parts.next().unwrap_or(Ok(default));
+        let position = BarBeatTick {
+            bar: next(1).with_context(|| format!("invalid bar in `{s}`"))?,
+            beat: next(1).with_context(|| format!("invalid beat in `{s}`"))?,
+            tick: next(0).with_context(|| format!("invalid tick in `{s}`"))?,
+        };
+        if position.bar == 0 || position.beat == 0 {
+            return Err(anyhow!("bars and beats count from 1 in `{s}`"));
+        }
+        if s.split(':').count() > 3 {
+            return Err(anyhow!("`{s}` is not a position like `bar:beat:tick`"));
+        }
+        Ok(position)
+    }
+}
+
+/// Where an event or a tempo or signature change happens: a number of units, which may be
+/// fractional, a `bar:beat:tick` position or `{ sample = n }`, an explicit sample offset
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum At {
+    Unit(f64),
+    Position(String),
+    Sample { sample: usize },
+}
+
+/// A resolved [`At`](At), in ticks that may fall between two ticks, or in samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Tick(f64),
+    Sample(usize),
+}
+
+impl From<Tick> for Position {
+    fn from(tick: Tick) -> Self {
+        Position::Tick(tick as f64)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChangeOptions {
+    pub at: At,
+    pub bpm: Option<f64>,
+    /// Ramp the tempo linearly from the previous change, arriving at `bpm` at `at`
+    #[serde(default)]
+    pub ramp: bool,
+    /// Must be at the start of a bar
+    pub signature: Option<String>,
+}
+
+/// User-facing description of a [`TempoMap`](TempoMap), deserialized from the table passed to
+/// `render` & `play` in place of an interval
+#[derive(Debug, Clone, Deserialize)]
+pub struct TempoOptions {
+    pub bpm: f64,
+    /// Defaults to `4/4`
+    pub signature: Option<String>,
+    /// The note value that integer event positions count, defaults to a beat
+    pub unit: Option<String>,
+    #[serde(default)]
+    pub changes: Vec<ChangeOptions>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TempoPoint {
+    tick: Tick,
+    bpm: f64,
+    /// Whether the tempo ramps from the previous point to this one
+    ramp: bool,
+    /// Seconds from the start of the timeline to this point
+    seconds: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SignaturePoint {
+    /// Bars before this one, counting from 0
+    bar: u64,
+    tick: Tick,
+    signature: Signature,
+}
+
+/// Converts positions in the musical timeline to sample offsets
+#[derive(Debug, Clone)]
+pub struct TempoMap {
+    sample_rate: u32,
+    unit: Tick,
+    tempos: Vec<TempoPoint>,
+    signatures: Vec<SignaturePoint>,
+}
+
+fn check_bpm(bpm: f64) -> anyhow::Result<f64> {
+    if bpm.is_finite() && bpm > 0. {
+        Ok(bpm)
+    } else {
+        Err(anyhow!("bpm must be positive, not `{bpm}`"))
+    }
+}
+
+impl TempoMap {
+    /// A constant tempo where every unit is `interval` samples long
+    pub fn from_interval(interval: usize, sample_rate: u32) -> anyhow::Result<Self> {
+        if interval == 0 {
+            return Err(anyhow!("interval must be at least 1 sample"));
+        }
+        Ok(TempoMap {
+            sample_rate,
+            unit: PPQ,
+            tempos: vec![TempoPoint {
+                tick: 0,
+                bpm: 60. * sample_rate as f64 / interval as f64,
+                ramp: false,
+                seconds: 0.,
+            }],
+            signatures: vec![SignaturePoint {
+                bar: 0,
+                tick: 0,
+                signature: Signature::default(),
+            }],
+        })
+    }
+
+    pub fn new(options: &TempoOptions, sample_rate: u32) -> anyhow::Result<Self> {
+        let signature = match &options.signature {
+            Some(signature) => signature.parse()?,
+            None => Signature::default(),
+        };
+        let mut map = TempoMap {
+            sample_rate,
+            unit: match &options.unit {
+                Some(unit) => fraction_ticks(parse_fraction(unit)?)?,
+                None => signature.beat_ticks(),
+            },
+            tempos: vec![TempoPoint {
+                tick: 0,
+                bpm: check_bpm(options.bpm)?,
+                ramp: false,
+                seconds: 0.,
+            }],
+            signatures: vec![SignaturePoint {
+                bar: 0,
+                tick: 0,
+                signature,
+            }],
+        };
+
+        // Signatures first, as they decide where the bars of every other change are
+        for change in &options.changes {
+            let Some(signature) = &change.signature else {
+                continue;
+            };
+            let signature: Signature = signature.parse()?;
+            let (bar, tick) = match &change.at {
+                At::Position(position) => {
+                    let position: BarBeatTick = position.parse()?;
+                    if position.beat != 1 || position.tick != 0 {
+                        return Err(anyhow!(
+                            "signature change at `{position:?}` is not at the start of a bar"
+                        ));
+                    }
+                    (position.bar - 1, map.tick_at_bar(position.bar - 1))
+                }
+                At::Unit(_) | At::Sample { .. } => {
+                    return Err(anyhow!(
+                        "signature changes must be at a `bar:beat:tick` position"
+                    ))
+                }
+            };
+            let last = map.signatures.last().expect("has initial signature");
+            if bar <= last.bar {
+                return Err(anyhow!("signature changes must be in order of their bars"));
+            }
+            map.signatures.push(SignaturePoint {
+                bar,
+                tick,
+                signature,
+            });
+        }
+
+        for change in &options.changes {
+            let Some(bpm) = change.bpm else {
+                continue;
+            };
+            let tick = map.tick_at(&change.at)?;
+            let last = map.tempos.last().expect("has initial tempo");
+            if tick <= last.tick {
+                return Err(anyhow!("tempo changes must be in order of their positions"));
+            }
+            let point = TempoPoint {
+                tick,
+                bpm: check_bpm(bpm)?,
+                ramp: change.ramp,
+                seconds: 0.,
+            };
+            let seconds = last.seconds + Self::seconds_between(last, &point, tick as f64);
+            map.tempos.push(TempoPoint { seconds, ..point });
+        }
+        Ok(map)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Ticks in a unit that integer event positions count
+    pub fn unit(&self) -> Tick {
+        self.unit
+    }
+
+    fn tick_at_bar(&self, bar: u64) -> Tick {
+        let point = self
+            .signatures
+            .iter()
+            .rev()
+            .find(|point| point.bar <= bar)
+            .expect("has initial signature");
+        point.tick + (bar - point.bar) * point.signature.bar_ticks()
+    }
+
+    pub fn tick_at_position(&self, position: BarBeatTick) -> anyhow::Result<Tick> {
+        let bar = position.bar - 1;
+        let signature = self
+            .signatures
+            .iter()
+            .rev()
+            .find(|point| point.bar <= bar)
+            .expect("has initial signature")
+            .signature;
+        if position.beat > signature.beats as u64 {
+            return Err(anyhow!(
+                "bar {} in {signature} has no beat {}",
+                position.bar,
+                position.beat
+            ));
+        }
+        Ok(self.tick_at_bar(bar) + (position.beat - 1) * signature.beat_ticks() + position.tick)
+    }
+
+    pub fn position(&self, at: &At) -> anyhow::Result<Position> {
+        match at {
+            At::Unit(units) if units.is_finite() && *units >= 0. => {
+                Ok(Position::Tick(units * self.unit as f64))
+            }
+            At::Unit(units) => Err(anyhow!("`{units}` is not a positive number of units")),
+            At::Position(position) => self.tick_at_position(position.parse()?).map(Position::from),
+            At::Sample { sample } => Ok(Position::Sample(*sample)),
+        }
+    }
+
+    /// Like [`position`](Self::position), but only for positions on a whole tick
+    pub fn tick_at(&self, at: &At) -> anyhow::Result<Tick> {
+        match self.position(at)? {
+            Position::Tick(tick) if tick.fract() == 0. => Ok(tick as Tick),
+            _ => Err(anyhow!("`{at:?}` does not fall on a tick")),
+        }
+    }
+
+    /// Seconds from `from` to `tick`, where `tick` lies between `from` and `to`
+    fn seconds_between(from: &TempoPoint, to: &TempoPoint, tick: f64) -> f64 {
+        let ticks = tick - from.tick as f64;
+        let per_tick = |bpm: f64| 60. / (bpm * PPQ as f64);
+        if !to.ramp || to.bpm == from.bpm {
+            return ticks * per_tick(from.bpm);
+        }
+        // The tempo is linear in ticks, so the time spent is the integral of its reciprocal
+        let length = (to.tick - from.tick) as f64;
+        let bpm = from.bpm + (to.bpm - from.bpm) * ticks / length;
+        60. * length / (PPQ as f64 * (to.bpm - from.bpm)) * (bpm / from.bpm).ln()
+    }
+
+    pub fn seconds_at(&self, tick: f64) -> f64 {
+        let index = self
+            .tempos
+            .partition_point(|point| point.tick as f64 <= tick)
+            - 1;
+        let from = &self.tempos[index];
+        let to = self.tempos.get(index + 1).unwrap_or(from);
+        from.seconds + Self::seconds_between(from, to, tick)
+    }
+
+    /// Beats per minute `seconds` into the timeline
+    pub fn bpm_at_seconds(&self, seconds: f64) -> f64 {
+        let index = self
+            .tempos
+            .partition_point(|point| point.seconds <= seconds)
+            .max(1)
+            - 1;
+        let from = &self.tempos[index];
+        match self.tempos.get(index + 1) {
+            // Inverse of the time spent in a ramp, see `seconds_between`
+            Some(to) if to.ramp && to.bpm != from.bpm => {
+                let length = (to.tick - from.tick) as f64;
+                let elapsed = seconds - from.seconds;
+                from.bpm * (elapsed * PPQ as f64 * (to.bpm - from.bpm) / (60. * length)).exp()
+            }
+            _ => from.bpm,
+        }
+    }
+
+    /// Beats per minute at a sample offset from the start of the timeline
+    pub fn bpm_at_sample(&self, sample: usize) -> f64 {
+        self.bpm_at_seconds(sample as f64 / self.sample_rate as f64)
+    }
+
+    /// Sample offset of a position from the start of the timeline
+    pub fn sample_at(&self, position: impl Into<Position>) -> usize {
+        match position.into() {
+            Position::Tick(tick) => {
+                (self.seconds_at(tick) * self.sample_rate as f64).round() as usize
+            }
+            Position::Sample(sample) => sample,
+        }
+    }
+
+    /// Tick, which may fall between two ticks, that `seconds` into the timeline is at
+    pub fn tick_at_seconds(&self, seconds: f64) -> f64 {
+        let index = self
+            .tempos
+            .partition_point(|point| point.seconds <= seconds)
+            .max(1)
+            - 1;
+        let from = &self.tempos[index];
+        match self.tempos.get(index + 1) {
+            // The tempo is linear in ticks, so the tick is as far along as the tempo is
+            Some(to) if to.ramp && to.bpm != from.bpm => {
+                let length = (to.tick - from.tick) as f64;
+                let bpm = self.bpm_at_seconds(seconds);
+                from.tick as f64 + length * (bpm - from.bpm) / (to.bpm - from.bpm)
+            }
+            _ => from.tick as f64 + (seconds - from.seconds) * from.bpm * PPQ as f64 / 60.,
+        }
+    }
+
+    /// Tick of a position, which may fall between two ticks
+    pub fn ticks_at(&self, position: Position) -> f64 {
+        match position {
+            Position::Tick(tick) => tick,
+            Position::Sample(sample) => {
+                self.tick_at_seconds(sample as f64 / self.sample_rate as f64)
+            }
+        }
+    }
+
+    /// Every tempo as the tick it starts at and its beats per minute, with ramps broken into
+    /// steps of `step` ticks that each last as long as they do in the ramp
+    pub fn tempo_steps(&self, step: Tick) -> Vec<(Tick, f64)> {
+        let mut steps = vec![(0, self.tempos[0].bpm)];
+        for pair in self.tempos.windows(2) {
+            let (from, to) = (&pair[0], &pair[1]);
+            if to.ramp && to.bpm != from.bpm {
+                // The first step takes over from the tempo ramped from
+                steps.pop_if(|(tick, _)| *tick == from.tick);
+                for tick in (from.tick..to.tick).step_by(step.max(1) as usize) {
+                    let end = (tick + step).min(to.tick);
+                    let seconds = self.seconds_at(end as f64) - self.seconds_at(tick as f64);
+                    steps.push((tick, 60. * (end - tick) as f64 / (PPQ as f64 * seconds)));
+                }
+            }
+            steps.push((to.tick, to.bpm));
+        }
+        steps
+    }
+
+    /// Every signature as the tick its first bar starts at
+    pub fn signatures(&self) -> impl Iterator<Item = (Tick, Signature)> + '_ {
+        self.signatures
+            .iter()
+            .map(|point| (point.tick, point.signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(bpm: f64) -> TempoOptions {
+        TempoOptions {
+            bpm,
+            signature: None,
+            unit: None,
+            changes: Vec::new(),
+        }
+    }
+
+    fn change(at: &str, bpm: Option<f64>, ramp: bool, signature: Option<&str>) -> ChangeOptions {
+        ChangeOptions {
+            at: At::Position(at.to_string()),
+            bpm,
+            ramp,
+            signature: signature.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn interval_matches_unit_grid() {
+        let map = TempoMap::from_interval(11025, 44100).unwrap();
+        (0..1000)
+            .for_each(|unit| assert_eq!(map.sample_at(unit * map.unit()), unit as usize * 11025));
+    }
+
+    #[test]
+    fn units_and_positions() {
+        let map = TempoMap::new(
+            &TempoOptions {
+                unit: Some("1/16".to_string()),
+                ..options(120.)
+            },
+            48000,
+        )
+        .unwrap();
+        // A sixteenth at 120bpm is an eighth of a second
+        assert_eq!(map.sample_at(map.tick_at(&At::Unit(1.)).unwrap()), 6000);
+        let bar_two = map.tick_at(&At::Position("2".to_string())).unwrap();
+        assert_eq!(map.sample_at(bar_two), 96000);
+        let position = "2:3:480".parse().unwrap();
+        assert_eq!(
+            map.sample_at(map.tick_at_position(position).unwrap()),
+            96000 + 48000 + 12000
+        );
+        assert!(map.tick_at_position("1:5".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn signature_changes_move_bars() {
+        let map = TempoMap::new(
+            &TempoOptions {
+                changes: vec![
+                    change("3", None, false, Some("3/4")),
+                    change("5", None, false, Some("6/8")),
+                ],
+                ..options(60.)
+            },
+            1000,
+        )
+        .unwrap();
+        let seconds =
+            |at: &str| map.seconds_at(map.tick_at(&At::Position(at.to_string())).unwrap() as f64);
+        assert_eq!(seconds("3"), 8.);
+        assert_eq!(seconds("4"), 11.);
+        assert_eq!(seconds("5"), 14.);
+        assert_eq!(seconds("5:6"), 16.5);
+        assert!(map.tick_at_position("4:4".parse().unwrap()).is_err());
+    }
+
+    #[test]
+    fn tempo_changes_and_ramps() {
+        let map = TempoMap::new(
+            &TempoOptions {
+                changes: vec![
+                    change("2", Some(120.), false, None),
+                    change("3", Some(60.), true, None),
+                ],
+                ..options(60.)
+            },
+            1000,
+        )
+        .unwrap();
+        let seconds =
+            |at: &str| map.seconds_at(map.tick_at(&At::Position(at.to_string())).unwrap() as f64);
+        assert_eq!(seconds("2"), 4.);
+        // Ramping from 120 down to 60bpm over 4 beats takes 4 * ln(2) seconds
+        assert!((seconds("3") - (4. + 4. * 2f64.ln())).abs() < 1e-9);
+        assert!((seconds("4") - (8. + 4. * 2f64.ln())).abs() < 1e-9);
+        // The ramp slows down as it goes
+        assert!(seconds("2:3") - seconds("2") < seconds("3") - seconds("2:3"));
+        assert_eq!(map.bpm_at_seconds(3.), 60.);
+        assert_eq!(map.bpm_at_seconds(4.), 120.);
+        assert!((map.bpm_at_seconds(seconds("2:3")) - 90.).abs() < 1e-9);
+        assert_eq!(map.bpm_at_seconds(100.), 60.);
+    }
+
+    #[test]
+    fn ticks_and_steps_of_ramps() {
+        let map = TempoMap::new(
+            &TempoOptions {
+                changes: vec![change("2", Some(120.), true, None)],
+                ..options(60.)
+            },
+            1000,
+        )
+        .unwrap();
+        assert_eq!(map.ticks_at(Position::Tick(2.5)), 2.5);
+        let end = map.sample_at(2 * 4 * PPQ);
+        assert!((map.ticks_at(Position::Sample(end)) - (2 * 4 * PPQ) as f64).abs() < 1.);
+        for tick in [0., 1000., 2500., 3839., 3840., 5000.] {
+            let sample = map.seconds_at(tick) * 1000.;
+            assert!((map.tick_at_seconds(sample / 1000.) - tick).abs() < 1e-6);
+        }
+
+        let steps = map.tempo_steps(PPQ);
+        assert_eq!(steps.len(), 5);
+        assert_eq!(steps[4], (4 * PPQ, 120.));
+        // Each step of the ramp lasts as long as that beat of it does
+        for (tick, bpm) in &steps[..4] {
+            let seconds = map.seconds_at((tick + PPQ) as f64) - map.seconds_at(*tick as f64);
+            assert!((seconds - 60. / bpm).abs() < 1e-9);
+        }
+        assert!(steps.windows(2).all(|pair| pair[0].1 < pair[1].1));
+        assert_eq!(
+            map.signatures().collect::<Vec<_>>(),
+            vec![(0, Signature::default())]
+        );
+    }
+
+    #[test]
+    fn positions_between_ticks() {
+        let map = TempoMap::from_interval(1000, 48000).unwrap();
+        let sample = |at: At| map.sample_at(map.position(&at).unwrap());
+        assert_eq!(sample(At::Unit(2.5)), 2500);
+        // A third of a unit lands between ticks and samples, and rounds to the nearest sample
+        assert_eq!(sample(At::Unit(1. / 3.)), 333);
+        assert_eq!(sample(At::Sample { sample: 1234 }), 1234);
+        assert!(map.tick_at(&At::Unit(0.0001)).is_err());
+        assert!(map.position(&At::Unit(-1.)).is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use crate::{
+    instrument::Note,
+    smf::{FromMessage, Message},
+};
+
+/// Velocity of notes that don't give one
+pub const DEFAULT_VELOCITY: u8 = 100;
+
+/// An event of the synth
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SynthEvent {
+    /// Start playing notes, on top of any already playing
+    On(NoteOn),
+    /// Release notes
+    Off(NoteOff),
+    /// Release every note of every channel
+    Release,
+    /// Release every note and play just this one, the way patterns play melodies
+    Play(Note),
+    /// Switch a channel to a preset of the SoundFont
+    Program(Program),
+    /// Select the bank that the next program change of a channel picks from
+    Bank(Control<u16>),
+    /// Bend the pitch of a channel, from `-1` to `1` of the bend range
+    Bend(Control<f64>),
+    /// Hold notes of a channel after they're released while the pedal is down
+    Sustain(Control<bool>),
+    Modulation(Control<u8>),
+    Volume(Control<u8>),
+    /// Pan of a channel, from `0` on the left to `127` on the right, `64` being the center
+    Pan(Control<u8>),
+}
+
+impl From<Note> for SynthEvent {
+    fn from(note: Note) -> Self {
+        SynthEvent::Play(note)
+    }
+}
+
+impl FromMessage for SynthEvent {
+    fn from_message(message: Message) -> Option<Self> {
+        Some(match message {
+            Message::NoteOn {
+                channel,
+                key,
+                velocity,
+            } => SynthEvent::On(NoteOn {
+                notes: Notes::One(Pitch::Number(key as i32)),
+                velocity,
+                channel,
+                duration: None,
+            }),
+            Message::NoteOff { channel, key } => SynthEvent::Off(NoteOff {
+                notes: Notes::One(Pitch::Number(key as i32)),
+                channel,
+            }),
+            Message::Program { channel, program } => SynthEvent::Program(Program {
+                preset: Preset::Program(program),
+                channel,
+            }),
+            Message::Control {
+                channel,
+                controller,
+                value,
+            } => match controller {
+                BANK => SynthEvent::Bank(Control::on(channel, value as u16)),
+                MODULATION => SynthEvent::Modulation(Control::on(channel, value)),
+                VOLUME => SynthEvent::Volume(Control::on(channel, value)),
+                PAN => SynthEvent::Pan(Control::on(channel, value)),
+                SUSTAIN => SynthEvent::Sustain(Control::on(channel, value >= 64)),
+                _ => return None,
+            },
+            Message::Bend { channel, value } => {
+                SynthEvent::Bend(Control::on(channel, value as f64 / 8192. - 1.))
+            }
+        })
+    }
+}
+
+/// Controllers of the synth's events, by their MIDI numbers
+pub const BANK: u8 = 0;
+pub const MODULATION: u8 = 1;
+pub const VOLUME: u8 = 7;
+pub const PAN: u8 = 10;
+pub const SUSTAIN: u8 = 64;
+
+/// A bend from `-1` to `1` as the 14 bits of a MIDI pitch bend, `8192` being no bend at all
+pub fn bend_value(bend: f64) -> anyhow::Result<u16> {
+    match bend {
+        -1.0..=1.0 => Ok(((bend + 1.) * 8192.).round().min(16383.) as u16),
+        _ => Err(anyhow!("bend must be between -1 and 1, not {bend}")),
+    }
+}
+
+/// A note as given in an event: a MIDI number, or a name like `"C4"` or `"F#2"`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Pitch {
+    Number(i32),
+    Name(String),
+}
+
+impl Pitch {
+    pub fn number(&self) -> anyhow::Result<i32> {
+        let number = match self {
+            Pitch::Number(number) => *number,
+            Pitch::Name(name) => name
+                .parse::<Note>()
+                .map_err(|err| anyhow!("invalid note `{name}`: {err}"))?
+                .number(),
+        };
+        match number {
+            0..=127 => Ok(number),
+            _ => Err(anyhow!(
+                "note {number} is out of the MIDI range of 0 to 127"
+            )),
+        }
+    }
+}
+
+/// A single note, or the notes of a chord
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Notes {
+    One(Pitch),
+    Chord(Vec<Pitch>),
+}
+
+impl Notes {
+    pub fn numbers(&self) -> anyhow::Result<Vec<i32>> {
+        match self {
+            Notes::One(pitch) => Ok(vec![pitch.number()?]),
+            Notes::Chord(pitches) => pitches.iter().map(Pitch::number).collect(),
+        }
+    }
+}
+
+/// Notes to start playing as given in an `on` event: just the notes, or the notes with how to
+/// play them
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "NoteOnOptions")]
+pub struct NoteOn {
+    pub notes: Notes,
+    pub velocity: u8,
+    /// MIDI channel, counting from 1
+    pub channel: u8,
+    /// Units that the notes last before they're released, until an `off` event if left out
+    pub duration: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NoteOnOptions {
+    Notes(Notes),
+    Options {
+        notes: Notes,
+        velocity: Option<u8>,
+        channel: Option<u8>,
+        duration: Option<f64>,
+    },
+}
+
+impl From<NoteOnOptions> for NoteOn {
+    fn from(options: NoteOnOptions) -> Self {
+        match options {
+            NoteOnOptions::Notes(notes) => NoteOn {
+                notes,
+                velocity: DEFAULT_VELOCITY,
+                channel: 1,
+                duration: None,
+            },
+            NoteOnOptions::Options {
+                notes,
+                velocity,
+                channel,
+                duration,
+            } => NoteOn {
+                notes,
+                velocity: velocity.unwrap_or(DEFAULT_VELOCITY),
+                channel: channel.unwrap_or(1),
+                duration,
+            },
+        }
+    }
+}
+
+/// Notes to release as given in an `off` event: just the notes, or the notes and their channel
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "NoteOffOptions")]
+pub struct NoteOff {
+    pub notes: Notes,
+    pub channel: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NoteOffOptions {
+    Notes(Notes),
+    Options { notes: Notes, channel: Option<u8> },
+}
+
+impl From<NoteOffOptions> for NoteOff {
+    fn from(options: NoteOffOptions) -> Self {
+        match options {
+            NoteOffOptions::Notes(notes) => NoteOff { notes, channel: 1 },
+            NoteOffOptions::Options { notes, channel } => NoteOff {
+                notes,
+                channel: channel.unwrap_or(1),
+            },
+        }
+    }
+}
+
+/// A preset of the SoundFont: its program number in the bank the channel is on, its program
+/// number and bank, or its name
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Preset {
+    Program(u8),
+    Bank { bank: u16, program: u8 },
+    Name(String),
+}
+
+/// A preset to switch to as given in a `program` event: just the preset, or the preset and its
+/// channel
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(from = "ProgramOptions")]
+pub struct Program {
+    pub preset: Preset,
+    pub channel: u8,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProgramOptions {
+    Preset(Preset),
+    Options { preset: Preset, channel: Option<u8> },
+}
+
+impl From<ProgramOptions> for Program {
+    fn from(options: ProgramOptions) -> Self {
+        match options {
+            ProgramOptions::Preset(preset) => Program { preset, channel: 1 },
+            ProgramOptions::Options { preset, channel } => Program {
+                preset,
+                channel: channel.unwrap_or(1),
+            },
+        }
+    }
+}
+
+/// A value to set a controller of a channel to: just the value, or the value and its channel
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "ControlOptions<T>")]
+#[serde(bound = "T: Deserialize<'de>")]
+pub struct Control<T> {
+    pub value: T,
+    pub channel: u8,
+}
+
+impl<T> Control<T> {
+    pub(crate) fn on(channel: u8, value: T) -> Self {
+        Control { value, channel }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ControlOptions<T> {
+    Value(T),
+    Options { value: T, channel: Option<u8> },
+}
+
+impl<T> From<ControlOptions<T>> for Control<T> {
+    fn from(options: ControlOptions<T>) -> Self {
+        match options {
+            ControlOptions::Value(value) => Control { value, channel: 1 },
+            ControlOptions::Options { value, channel } => Control {
+                value,
+                channel: channel.unwrap_or(1),
+            },
+        }
+    }
+}
+
+/// Index of the synthesizer's channel that MIDI channel `channel` is
+pub fn channel_index(channel: u8) -> anyhow::Result<i32> {
+    match channel {
+        1..=16 => Ok(channel as i32 - 1),
+        _ => Err(anyhow!("channel must be between 1 and 16, not {channel}")),
+    }
+}
+
+/// A note to release once a frame is reached
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Release {
+    pub frame: u64,
+    pub channel: i32,
+    pub key: i32,
+}
+
+/// Notes that are to be released, counting the frames rendered until they are
+#[derive(Debug, Default)]
+pub struct Schedule {
+    /// Frames rendered so far
+    pub frame: u64,
+    releases: Vec<Release>,
+}
+
+impl Schedule {
+    pub fn release_in(&mut self, frames: u64, channel: i32, key: i32) {
+        self.releases.push(Release {
+            frame: self.frame + frames,
+            channel,
+            key,
+        });
+    }
+
+    /// Forget every release, for when every note is released at once
+    pub fn clear(&mut self) {
+        self.releases.clear();
+    }
+
+    /// Take the releases that are due by now
+    pub fn due(&mut self) -> Vec<Release> {
+        let frame = self.frame;
+        let (due, later) = self
+            .releases
+            .iter()
+            .partition(|release| release.frame <= frame);
+        self.releases = later;
+        due
+    }
+
+    /// Frames that can be rendered before the next release is due, at most `frames`
+    pub fn until_next(&self, frames: usize) -> usize {
+        self.releases
+            .iter()
+            .map(|release| release.frame.saturating_sub(self.frame) as usize)
+            .fold(frames, usize::min)
+            .max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notes_chords_and_channels() {
+        let on = NoteOn::from(NoteOnOptions::Notes(Notes::One(Pitch::Name("C4".into()))));
+        assert_eq!(on.notes.numbers().unwrap(), vec![48]);
+        assert_eq!((on.velocity, on.channel, on.duration), (100, 1, None));
+
+        let chord = Notes::Chord(vec![
+            Pitch::Name("C4".into()),
+            Pitch::Name("Eb4".into()),
+            Pitch::Number(67),
+        ]);
+        assert_eq!(chord.numbers().unwrap(), vec![48, 51, 67]);
+        assert!(Pitch::Number(128).number().is_err());
+        assert!(Pitch::Name("H4".into()).number().is_err());
+        assert_eq!(channel_index(10).unwrap(), 9);
+        assert!(channel_index(0).is_err() && channel_index(17).is_err());
+    }
+
+    #[test]
+    fn controllers_of_midi_messages() {
+        let event = |controller, value| {
+            SynthEvent::from_message(Message::Control {
+                channel: 3,
+                controller,
+                value,
+            })
+        };
+        assert!(matches!(
+            event(SUSTAIN, 100),
+            Some(SynthEvent::Sustain(Control {
+                value: true,
+                channel: 3
+            }))
+        ));
+        assert!(matches!(
+            event(PAN, 20),
+            Some(SynthEvent::Pan(Control { value: 20, .. }))
+        ));
+        assert!(event(91, 40).is_none());
+        assert!(matches!(
+            SynthEvent::from_message(Message::Program {
+                channel: 10,
+                program: 25
+            }),
+            Some(SynthEvent::Program(Program {
+                preset: Preset::Program(25),
+                channel: 10
+            }))
+        ));
+        let Some(SynthEvent::Bend(bend)) = SynthEvent::from_message(Message::Bend {
+            channel: 1,
+            value: 12288,
+        }) else {
+            panic!("pitch bends are bends");
+        };
+        assert_eq!(bend.value, 0.5);
+        assert_eq!(bend_value(bend.value).unwrap(), 12288);
+        assert_eq!(bend_value(1.).unwrap(), 16383);
+        assert_eq!(bend_value(-1.).unwrap(), 0);
+        assert!(bend_value(1.5).is_err());
+    }
+
+    #[test]
+    fn releases_when_due() {
+        let mut schedule = Schedule::default();
+        schedule.release_in(100, 0, 48);
+        schedule.release_in(40, 0, 52);
+        assert_eq!(schedule.until_next(512), 40);
+        assert!(schedule.due().is_empty());
+        schedule.frame += 40;
+        assert_eq!(
+            schedule.due(),
+            vec![Release {
+                frame: 40,
+                channel: 0,
+                key: 52
+            }]
+        );
+        assert_eq!(schedule.until_next(512), 60);
+        schedule.frame += 60;
+        assert_eq!(schedule.due().len(), 1);
+        assert_eq!(schedule.until_next(512), 512);
+    }
+}
@@ -0,0 +1,467 @@
+use std::{any::Any, sync::Arc};
+
+use anyhow::{anyhow, Context};
+use libplunder::{
+    prelude::instrument::*,
+    tempo::{TempoMap, Tick, PPQ},
+};
+use mlua::{prelude::*, serde::Deserializer};
+use serde::Deserialize;
+
+use crate::{
+    event::{
+        bend_value, channel_index, Control, NoteOn, Notes, Preset, Program, SynthEvent, BANK,
+        DEFAULT_VELOCITY, MODULATION, PAN, SUSTAIN, VOLUME,
+    },
+    smf::{Event, Message, Smf},
+    Synth, SynthArguments,
+};
+
+/// Ticks that tempo ramps are stepped by, as MIDI files can only jump from one tempo to the next
+const RAMP_STEP: Tick = PPQ / 4;
+
+/// Events of a synth, and the notes they leave playing
+#[derive(Debug, Default)]
+struct Track {
+    events: Vec<(Tick, Event)>,
+    /// Channel and key of every note playing
+    playing: Vec<(u8, u8)>,
+    /// Notes to be released once their durations are up
+    releases: Vec<(Tick, u8, u8)>,
+}
+
+impl Track {
+    fn on(&mut self, tick: Tick, channel: u8, key: u8, velocity: u8) {
+        self.events.push((
+            tick,
+            Event::Message(Message::NoteOn {
+                channel,
+                key,
+                velocity,
+            }),
+        ));
+        self.playing.push((channel, key));
+    }
+
+    fn off(&mut self, tick: Tick, channel: u8, key: u8) {
+        if let Some(index) = self.playing.iter().position(|note| *note == (channel, key)) {
+            self.playing.remove(index);
+            self.events
+                .push((tick, Event::Message(Message::NoteOff { channel, key })));
+        }
+    }
+
+    /// Release every note, the way the synth does
+    fn release(&mut self, tick: Tick) {
+        self.releases.clear();
+        for (channel, key) in std::mem::take(&mut self.playing) {
+            self.events
+                .push((tick, Event::Message(Message::NoteOff { channel, key })));
+        }
+    }
+
+    fn message(&mut self, tick: Tick, message: Message) {
+        self.events.push((tick, Event::Message(message)));
+    }
+
+    fn control<T>(&mut self, tick: Tick, controller: u8, control: &Control<T>, value: u8) {
+        self.message(
+            tick,
+            Message::Control {
+                channel: control.channel,
+                controller,
+                value,
+            },
+        );
+    }
+
+    /// Release the notes whose durations are up by `tick`
+    fn release_due(&mut self, tick: Tick) {
+        self.releases.sort_by_key(|(at, ..)| *at);
+        let due = self.releases.partition_point(|(at, ..)| *at <= tick);
+        for (at, channel, key) in self.releases.drain(..due).collect::<Vec<_>>() {
+            self.off(at, channel, key);
+        }
+    }
+}
+
+/// Value of a controller that goes from 0 to 127
+fn value(control: &Control<u8>) -> anyhow::Result<u8> {
+    channel_index(control.channel)?;
+    match control.value {
+        0..=127 => Ok(control.value),
+        value => Err(anyhow!("value must be between 0 and 127, not {value}")),
+    }
+}
+
+/// Bank select of `channel`, whose banks count from 128 on the percussion channel like the
+/// synth's do
+fn bank(channel: u8, bank: u16) -> anyhow::Result<u8> {
+    let bank = match channel {
+        10 => bank.saturating_sub(128),
+        _ => bank,
+    };
+    u8::try_from(bank)
+        .ok()
+        .filter(|bank| *bank <= 127)
+        .with_context(|| {
+            format!("bank {bank} of channel {channel} can't be written to a MIDI file")
+        })
+}
+
+/// Notes of `notes` as keys, with `channel` checked
+fn keys(notes: &Notes, channel: u8) -> anyhow::Result<Vec<u8>> {
+    channel_index(channel)?;
+    Ok(notes
+        .numbers()?
+        .into_iter()
+        .map(|number| number as u8)
+        .collect())
+}
+
+/// Events of synths turned into the tracks of a MIDI file, a track for every synth
+pub struct Export {
+    /// Ticks in a unit that durations count
+    unit: Tick,
+    /// Synths by where they're shared from, in the order they were first played
+    tracks: Vec<(usize, Track)>,
+}
+
+impl Export {
+    pub fn new(unit: Tick) -> Self {
+        Export {
+            unit,
+            tracks: Vec::new(),
+        }
+    }
+
+    /// Add `event` at `tick` if it's an event of a synth, ignoring it otherwise. Events must be
+    /// added in order of their ticks
+    pub fn add(&mut self, tick: Tick, event: &EmittableUserData) -> anyhow::Result<()> {
+        let Some((synth, event, presets)) = synth_event(event)? else {
+            return Ok(());
+        };
+        // Channels start on the presets the synth was opened with
+        if !self.tracks.iter().any(|(id, _)| *id == synth) {
+            for program in presets {
+                self.play(synth, 0, SynthEvent::Program(program))?;
+            }
+        }
+        self.play(synth, tick, event)
+    }
+
+    /// Play `event` on the track of `synth` at `tick`
+    fn play(&mut self, synth: usize, tick: Tick, event: SynthEvent) -> anyhow::Result<()> {
+        let track = match self.tracks.iter().position(|(id, _)| *id == synth) {
+            Some(index) => &mut self.tracks[index].1,
+            None => {
+                self.tracks.push((synth, Track::default()));
+                &mut self.tracks.last_mut().expect("just pushed").1
+            }
+        };
+        track.release_due(tick);
+        match event {
+            SynthEvent::On(NoteOn {
+                notes,
+                velocity,
+                channel,
+                duration,
+            }) => {
+                for key in keys(&notes, channel)? {
+                    track.on(tick, channel, key, velocity);
+                    if let Some(duration) = duration {
+                        let ticks = (duration.max(0.) * self.unit as f64).round() as Tick;
+                        track.releases.push((tick + ticks, channel, key));
+                    }
+                }
+            }
+            SynthEvent::Off(off) => {
+                for key in keys(&off.notes, off.channel)? {
+                    track.off(tick, off.channel, key);
+                }
+            }
+            SynthEvent::Release => track.release(tick),
+            SynthEvent::Play(note) => {
+                track.release(tick);
+                let number = note.number();
+                if !(0..=127).contains(&number) {
+                    return Err(anyhow!(
+                        "note {number} is out of the MIDI range of 0 to 127"
+                    ));
+                }
+                track.on(tick, 1, number as u8, DEFAULT_VELOCITY);
+            }
+            SynthEvent::Program(Program { preset, channel }) => {
+                channel_index(channel)?;
+                let program = match preset {
+                    Preset::Program(program) => program,
+                    Preset::Bank { bank, program } => {
+                        let value = self::bank(channel, bank)?;
+                        track.message(
+                            tick,
+                            Message::Control {
+                                channel,
+                                controller: BANK,
+                                value,
+                            },
+                        );
+                        program
+                    }
+                    Preset::Name(name) => {
+                        return Err(anyhow!("preset `{name}` wasn't looked up in the SoundFont"))
+                    }
+                };
+                if program > 127 {
+                    return Err(anyhow!("program must be at most 127, not {program}"));
+                }
+                track.message(tick, Message::Program { channel, program });
+            }
+            SynthEvent::Bank(control) => {
+                channel_index(control.channel)?;
+                let value = bank(control.channel, control.value)?;
+                track.control(tick, BANK, &control, value);
+            }
+            SynthEvent::Bend(control) => {
+                channel_index(control.channel)?;
+                let value = bend_value(control.value)?;
+                track.message(
+                    tick,
+                    Message::Bend {
+                        channel: control.channel,
+                        value,
+                    },
+                );
+            }
+            SynthEvent::Sustain(control) => {
+                channel_index(control.channel)?;
+                let value = if control.value { 127 } else { 0 };
+                track.control(tick, SUSTAIN, &control, value);
+            }
+            SynthEvent::Modulation(control) => {
+                track.control(tick, MODULATION, &control, value(&control)?)
+            }
+            SynthEvent::Volume(control) => track.control(tick, VOLUME, &control, value(&control)?),
+            SynthEvent::Pan(control) => track.control(tick, PAN, &control, value(&control)?),
+        }
+        Ok(())
+    }
+
+    /// The MIDI file of every event added, with the tempo of `tempo` and every note released by
+    /// `end`
+    pub fn finish(mut self, tempo: &TempoMap, end: Tick) -> anyhow::Result<Smf> {
+        let mut timing = Vec::new();
+        for (tick, signature) in tempo.signatures() {
+            // MIDI files store the beat value as the power of two it is
+            if !signature.value.is_power_of_two() {
+                return Err(anyhow!(
+                    "time signature {signature} can't be written to a MIDI file, its beat value \
+                        must be a power of two"
+                ));
+            }
+            let beats = u8::try_from(signature.beats).map_err(|_| {
+                anyhow!(
+                    "time signature {signature} can't be written to a MIDI file, it must have at \
+                        most 255 beats"
+                )
+            })?;
+            let power = signature.value.trailing_zeros() as u8;
+            timing.push((tick, Event::Signature(beats, power)));
+        }
+        for (tick, bpm) in tempo.tempo_steps(RAMP_STEP) {
+            timing.push((tick, Event::Tempo((60_000_000. / bpm).round() as u32)));
+        }
+        timing.retain(|(tick, _)| *tick < end.max(1));
+        timing.sort_by_key(|(tick, _)| *tick);
+
+        // One track for the tempo, and one for every synth
+        if self.tracks.len() >= u16::MAX as usize {
+            return Err(anyhow!(
+                "a MIDI file holds at most {} synths, not {}",
+                u16::MAX - 1,
+                self.tracks.len()
+            ));
+        }
+        let mut tracks = vec![timing];
+        for (_, track) in &mut self.tracks {
+            track.release_due(end);
+            track.release(end);
+            track.events.sort_by_key(|(tick, _)| *tick);
+            tracks.push(std::mem::take(&mut track.events));
+        }
+        Ok(Smf {
+            division: PPQ as u16,
+            tracks,
+        })
+    }
+}
+
+/// The synth that `event` is for, by where it's shared from, the event with the presets it names
+/// looked up, and the presets the synth's channels start on, if it's for a synth
+fn synth_event(
+    event: &EmittableUserData,
+) -> anyhow::Result<Option<(usize, SynthEvent, Vec<Program>)>> {
+    let emit = event.0.read().unwrap();
+    let Some((instrument, event)) = emit.parts() else {
+        return Ok(None);
+    };
+    let any: Arc<dyn Any + Send + Sync> = instrument.0.clone();
+    let Some(shared) = any.downcast_ref::<ToPlunderInstrument<SynthArguments, SynthEvent, Synth>>()
+    else {
+        return Ok(None);
+    };
+    let synth = Arc::as_ptr(&any) as *const () as usize;
+    let mut event = match (
+        event.downcast_ref::<SynthEvent>(),
+        event.downcast_ref::<LuaValue>(),
+    ) {
+        (Some(event), _) => event.clone(),
+        (None, Some(value)) => SynthEvent::deserialize(Deserializer::new(value.clone()))
+            .map_err(|err| anyhow!("invalid synth event: {err}"))?,
+        (None, None) => return Ok(None),
+    };
+    let instrument = shared.instrument().read().unwrap();
+    if let SynthEvent::Program(program) = &mut event {
+        program.preset = instrument.resolve(&program.preset)?;
+    }
+    Ok(Some((synth, event, instrument.presets().to_vec())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::{NoteOff, Pitch};
+    use libplunder::tempo::TempoOptions;
+
+    fn note_on(key: i32, duration: Option<f64>) -> SynthEvent {
+        SynthEvent::On(NoteOn {
+            notes: Notes::One(Pitch::Number(key)),
+            velocity: 90,
+            channel: 2,
+            duration,
+        })
+    }
+
+    fn messages(track: &[(Tick, Event)]) -> Vec<(Tick, u8, bool)> {
+        track
+            .iter()
+            .filter_map(|(tick, event)| match event {
+                Event::Message(Message::NoteOn { key, .. }) => Some((*tick, *key, true)),
+                Event::Message(Message::NoteOff { key, .. }) => Some((*tick, *key, false)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rejects_signatures_midi_files_cannot_hold() {
+        let tempo = |signature: &str| {
+            let options = TempoOptions {
+                bpm: 120.,
+                signature: Some(signature.to_string()),
+                unit: None,
+                changes: Vec::new(),
+            };
+            TempoMap::new(&options, 44100).unwrap()
+        };
+        let finish = |signature| Export::new(480).finish(&tempo(signature), 960);
+        let smf = finish("6/8").unwrap();
+        assert_eq!(smf.tracks[0][0], (0, Event::Signature(6, 3)));
+        assert!(finish("3/5").is_err());
+        assert!(finish("256/4").is_err());
+    }
+
+    #[test]
+    fn releases_notes_like_the_synth() {
+        let mut export = Export::new(480);
+        let mut add = |tick, event| export.play(7, tick, event).unwrap();
+        add(0, note_on(48, Some(2.)));
+        add(0, note_on(52, None));
+        add(
+            480,
+            SynthEvent::Off(NoteOff {
+                notes: Notes::One(Pitch::Number(52)),
+                channel: 2,
+            }),
+        );
+        add(1440, note_on(55, None));
+        add(1920, SynthEvent::Release);
+        add(2400, note_on(60, Some(1.)));
+        add(2400, SynthEvent::Play("C4".parse().unwrap()));
+        add(
+            2880,
+            SynthEvent::Program(Program {
+                preset: Preset::Bank {
+                    bank: 128,
+                    program: 8,
+                },
+                channel: 10,
+            }),
+        );
+        add(2880, SynthEvent::Volume(Control::on(2, 90)));
+        assert!(export
+            .play(7, 2880, SynthEvent::Pan(Control::on(2, 128)))
+            .is_err());
+
+        let tempo = TempoMap::from_interval(11025, 44100).unwrap();
+        let smf = export.finish(&tempo, 3840).unwrap();
+        assert_eq!(smf.tracks.len(), 2);
+        assert_eq!(
+            smf.tracks[0],
+            vec![(0, Event::Signature(4, 2)), (0, Event::Tempo(250_000))]
+        );
+        assert_eq!(
+            messages(&smf.tracks[1]),
+            vec![
+                (0, 48, true),
+                (0, 52, true),
+                (480, 52, false),
+                (960, 48, false),
+                (1440, 55, true),
+                (1920, 55, false),
+                (2400, 60, true),
+                (2400, 60, false),
+                (2400, 48, true),
+                (3840, 48, false),
+            ]
+        );
+        let controls: Vec<_> = smf.tracks[1]
+            .iter()
+            .filter(|(_, event)| {
+                !matches!(
+                    event,
+                    Event::Message(Message::NoteOn { .. } | Message::NoteOff { .. })
+                )
+            })
+            .collect();
+        assert_eq!(
+            controls,
+            [
+                (
+                    2880,
+                    Event::Message(Message::Control {
+                        channel: 10,
+                        controller: BANK,
+                        value: 0
+                    })
+                ),
+                (
+                    2880,
+                    Event::Message(Message::Program {
+                        channel: 10,
+                        program: 8
+                    })
+                ),
+                (
+                    2880,
+                    Event::Message(Message::Control {
+                        channel: 2,
+                        controller: VOLUME,
+                        value: 90
+                    })
+                ),
+            ]
+            .iter()
+            .collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+use std::{fs::File, io::BufReader, path::Path, str::FromStr, sync::Arc};
 
 use anyhow::{anyhow, Context};
 use log::trace;
@@ -7,7 +7,54 @@ use rustysynth::{SoundFont, Synthesizer, SynthesizerError, SynthesizerSettings};
 use libplunder::prelude::instrument::*;
 use serde::{Deserialize, Serialize};
 
-pub struct Synth(Synthesizer);
+use crate::event::{
+    bend_value, channel_index, Control, Preset, Program, Schedule, SynthEvent, BANK,
+    DEFAULT_VELOCITY, MODULATION, PAN, SUSTAIN, VOLUME,
+};
+
+/// Tempo that durations are timed at until the synth is told what it's rendered at
+const DEFAULT_BPM: f64 = 120.;
+
+/// Channel that General MIDI plays drums on, whose banks rustysynth counts from 128
+const PERCUSSION: i32 = 9;
+
+/// Arguments of `Synth.open`: the SoundFont's path, or the path and the presets that channels
+/// start on
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SynthArguments {
+    Path(String),
+    Options {
+        file: String,
+        #[serde(default)]
+        presets: Vec<ChannelPreset>,
+    },
+}
+
+/// A preset that a channel starts on: just the preset, for the channel that its place in the list
+/// is, or the preset and its channel. Lua tables with gaps between their indexes don't make it
+/// through as lists, so channels further along are given explicitly
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ChannelPreset {
+    Preset(Preset),
+    Channel { preset: Preset, channel: u8 },
+}
+
+pub struct Synth {
+    synthesizer: Synthesizer,
+    sound_font: Arc<SoundFont>,
+    /// Scratch buffers the synthesizer renders each channel into before they're interleaved
+    left: Vec<f32>,
+    right: Vec<f32>,
+    /// Notes to release once they've lasted as long as they were played for
+    schedule: Schedule,
+    /// Tempo being rendered at, and the length in beats of the units that durations count
+    bpm: f64,
+    unit: f64,
+    /// Presets that channels start on, as programs and banks
+    presets: Vec<Program>,
+}
 
 impl Synth {
     pub fn load_sf2<P>(path: P) -> anyhow::Result<Self>
@@ -16,10 +63,119 @@ impl Synth {
     {
         let file = File::open(path.as_ref())?;
         let mut reader = BufReader::new(file);
-        Ok(Self(Synthesizer::new(
-            &Arc::new(SoundFont::new(&mut reader)?),
-            &SynthesizerSettings::new(44100),
-        )?))
+        let sound_font = Arc::new(SoundFont::new(&mut reader)?);
+        Ok(Self {
+            // Rebuilt at the rate being rendered at once the synth is prepared
+            synthesizer: Synthesizer::new(&sound_font, &SynthesizerSettings::new(44100))?,
+            sound_font,
+            left: Vec::new(),
+            right: Vec::new(),
+            schedule: Schedule::default(),
+            bpm: DEFAULT_BPM,
+            unit: 1.,
+            presets: Vec::new(),
+        })
+    }
+
+    fn open(arguments: SynthArguments) -> anyhow::Result<Self> {
+        let (file, presets) = match arguments {
+            SynthArguments::Path(file) => (file, Vec::new()),
+            SynthArguments::Options { file, presets } => (file, presets),
+        };
+        let mut synth = Self::load_sf2(&file).context(format!("while opening `{file}`"))?;
+        for (index, preset) in presets.into_iter().enumerate() {
+            let (preset, channel) = match preset {
+                ChannelPreset::Preset(preset) => (preset, index as u8 + 1),
+                ChannelPreset::Channel { preset, channel } => (preset, channel),
+            };
+            let preset = synth.resolve(&preset)?;
+            synth.presets.push(Program { preset, channel });
+        }
+        synth.reset_presets()?;
+        Ok(synth)
+    }
+
+    /// Presets that channels start on, as programs and banks
+    pub fn presets(&self) -> &[Program] {
+        &self.presets
+    }
+
+    /// `preset` as a program and a bank if it's a name, as it is otherwise
+    pub fn resolve(&self, preset: &Preset) -> anyhow::Result<Preset> {
+        let Preset::Name(name) = preset else {
+            return Ok(preset.clone());
+        };
+        self.sound_font
+            .get_presets()
+            .iter()
+            .find(|preset| preset.get_name().trim().eq_ignore_ascii_case(name.trim()))
+            .map(|preset| Preset::Bank {
+                bank: preset.get_bank_number() as u16,
+                program: preset.get_patch_number() as u8,
+            })
+            .with_context(|| format!("the SoundFont has no preset named `{name}`"))
+    }
+
+    /// Switch every channel to the preset it starts on
+    fn reset_presets(&mut self) -> anyhow::Result<()> {
+        for program in self.presets.clone() {
+            self.program(program)?;
+        }
+        Ok(())
+    }
+
+    fn program(&mut self, program: Program) -> anyhow::Result<()> {
+        let channel = channel_index(program.channel)?;
+        let program = match self.resolve(&program.preset)? {
+            Preset::Bank { bank, program } => {
+                self.bank(channel, bank);
+                program
+            }
+            Preset::Program(program) => program,
+            Preset::Name(_) => unreachable!("names are resolved to banks"),
+        };
+        if program > 127 {
+            return Err(anyhow!("program must be at most 127, not {program}"));
+        }
+        self.synthesizer
+            .process_midi_message(channel, 0xc0, program as i32, 0);
+        Ok(())
+    }
+
+    fn bank(&mut self, channel: i32, bank: u16) {
+        // Banks of the percussion channel are selected counting from 128
+        let bank = match channel {
+            PERCUSSION => bank.saturating_sub(128),
+            _ => bank,
+        };
+        self.control(channel, BANK, bank as i32);
+    }
+
+    fn control(&mut self, channel: i32, controller: u8, value: i32) {
+        self.synthesizer
+            .process_midi_message(channel, 0xb0, controller as i32, value);
+    }
+
+    /// `control` with its channel as an index, and its value checked to be at most 127
+    fn checked<T: Into<i32> + Copy>(control: &Control<T>) -> anyhow::Result<(i32, i32)> {
+        let value = control.value.into();
+        if !(0..=127).contains(&value) {
+            return Err(anyhow!("value must be between 0 and 127, not {value}"));
+        }
+        Ok((channel_index(control.channel)?, value))
+    }
+
+    /// Release the notes whose duration is up
+    fn release_due(&mut self) {
+        for release in self.schedule.due() {
+            self.synthesizer.note_off(release.channel, release.key);
+        }
+    }
+
+    /// Frames that `units` last at the tempo and sample rate being rendered at
+    fn frames_of(&self, units: f64) -> u64 {
+        let seconds = units * self.unit * 60. / self.bpm;
+        (seconds * self.synthesizer.get_sample_rate() as f64).round() as u64
     }
 }
 
@@ -28,10 +184,74 @@ impl Source for Synth {
     fn next_sample(&mut self) -> Result<Option<Sample>, SourceError<Self::Err>> {
         let mut left = [0f32];
         let mut right = [0f32];
-        self.0.render(&mut left, &mut right);
+        self.release_due();
+        self.synthesizer.render(&mut left, &mut right);
+        self.schedule.frame += 1;
         trace!("rendered two channels");
         Ok(Some(Sample::F32(vec![left[0], right[0]])))
     }
+
+    fn sample_rate(&self) -> Option<u32> {
+        Some(self.synthesizer.get_sample_rate() as u32)
+    }
+
+    fn prepare(&mut self, sample_rate: u32) -> Result<(), SynthesizerError> {
+        if self.synthesizer.get_sample_rate() as u32 != sample_rate {
+            self.synthesizer = Synthesizer::new(
+                &self.sound_font,
+                &SynthesizerSettings::new(sample_rate as i32),
+            )?;
+            // Presets were checked once the synth was opened
+            _ = self.reset_presets();
+        }
+        Ok(())
+    }
+
+    fn tempo(&mut self, bpm: f64) {
+        self.bpm = bpm;
+    }
+
+    fn unit(&mut self, beats: f64) {
+        self.unit = beats;
+    }
+
+    fn channels(&self) -> Option<usize> {
+        Some(2)
+    }
+
+    fn fill_buffer(
+        &mut self,
+        buffer: &mut [f32],
+        channels: usize,
+    ) -> Result<usize, SourceError<Self::Err>> {
+        let frames = buffer.len() / channels;
+        self.left.resize(frames, 0.);
+        self.right.resize(frames, 0.);
+        // Rendered in parts between the frames that notes are released at
+        let mut rendered = 0;
+        while rendered < frames {
+            self.release_due();
+            let part = rendered + self.schedule.until_next(frames - rendered);
+            self.synthesizer.render(
+                &mut self.left[rendered..part],
+                &mut self.right[rendered..part],
+            );
+            self.schedule.frame += (part - rendered) as u64;
+            rendered = part;
+        }
+        for ((frame, left), right) in buffer
+            .chunks_exact_mut(channels)
+            .zip(&self.left)
+            .zip(&self.right)
+        {
+            frame.fill(0.);
+            frame
+                .iter_mut()
+                .zip([left, right])
+                .for_each(|(f, s)| *f = *s);
+        }
+        Ok(frames)
+    }
 }
 
 #[rustfmt::skip]
@@ -96,7 +316,8 @@ impl Note {
         }))
     }
 
-    fn number(&self) -> i32 {
+    /// MIDI number of the note, counting from C0 as 0
+    pub fn number(&self) -> i32 {
         let num_c_o: i32 = match self.octave {
             octave @ 0..=9 => octave * 12,
             _ => unreachable!(),
@@ -121,7 +342,7 @@ impl Note {
     }
 
     fn _freq(&self) -> f32 {
-        const INCR: f32 = 1.0594630943592953;
+        const INCR: f32 = 1.059_463_1;
         let (freq_ao, freq_ao_1) = match self.octave {
             octave @ 0..=9 => (
                 27.5 * 2f32.powf(octave as f32),
@@ -147,31 +368,112 @@ impl Note {
     }
 }
 
-impl State<String, Note> for Synth {
-    type TErr = u8;
+impl FromStr for Note {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.chars().enumerate().collect::<Vec<_>>();
+        Note::from_spanned_str(&s)?.ok_or_else(|| "empty note".to_string())
+    }
+}
+
+impl State<SynthArguments, SynthEvent> for Synth {
+    type TErr = anyhow::Error;
     type IErr = anyhow::Error;
 
-    fn transform(&mut self, note: Note) -> Result<(), u8> {
-        self.0.note_off_all(false);
-        // println!("note on:`{}`", note.freq());
-        self.0.note_on(1, note.number(), 100);
+    fn transform(&mut self, event: SynthEvent) -> anyhow::Result<()> {
+        match event {
+            SynthEvent::On(on) => {
+                let channel = channel_index(on.channel)?;
+                if on.velocity > 127 {
+                    return Err(anyhow!("velocity must be at most 127, not {}", on.velocity));
+                }
+                let duration = match on.duration {
+                    Some(units) if units.is_finite() && units > 0. => Some(self.frames_of(units)),
+                    Some(units) => return Err(anyhow!("`{units}` is not a positive duration")),
+                    None => None,
+                };
+                for key in on.notes.numbers()? {
+                    self.synthesizer.note_on(channel, key, on.velocity as i32);
+                    if let Some(frames) = duration {
+                        self.schedule.release_in(frames, channel, key);
+                    }
+                }
+            }
+            SynthEvent::Off(off) => {
+                let channel = channel_index(off.channel)?;
+                for key in off.notes.numbers()? {
+                    self.synthesizer.note_off(channel, key);
+                }
+            }
+            SynthEvent::Release => {
+                self.synthesizer.note_off_all(false);
+                self.schedule.clear();
+            }
+            SynthEvent::Play(note) => {
+                self.synthesizer.note_off_all(false);
+                self.schedule.clear();
+                self.synthesizer
+                    .note_on(0, note.number(), DEFAULT_VELOCITY as i32);
+            }
+            SynthEvent::Program(program) => self.program(program)?,
+            SynthEvent::Bank(bank) => {
+                let channel = channel_index(bank.channel)?;
+                self.bank(channel, bank.value);
+            }
+            SynthEvent::Bend(bend) => {
+                let channel = channel_index(bend.channel)?;
+                let value = bend_value(bend.value)? as i32;
+                self.synthesizer
+                    .process_midi_message(channel, 0xe0, value & 0x7f, value >> 7);
+            }
+            SynthEvent::Sustain(sustain) => {
+                let channel = channel_index(sustain.channel)?;
+                self.control(channel, SUSTAIN, if sustain.value { 127 } else { 0 });
+            }
+            SynthEvent::Modulation(modulation) => {
+                let (channel, value) = Self::checked(&modulation)?;
+                self.control(channel, MODULATION, value);
+            }
+            SynthEvent::Volume(volume) => {
+                let (channel, value) = Self::checked(&volume)?;
+                self.control(channel, VOLUME, value);
+            }
+            SynthEvent::Pan(pan) => {
+                let (channel, value) = Self::checked(&pan)?;
+                self.control(channel, PAN, value);
+            }
+        }
         Ok(())
     }
 
-    fn initialize(route: &str, arguments: String) -> Result<Self, Self::IErr>
+    fn initialize(route: &str, arguments: SynthArguments) -> Result<Self, Self::IErr>
     where
         Self: Sized,
     {
         match route {
-            "open" => Self::load_sf2(&arguments).context(format!("while opening `{arguments}`")),
+            "open" => Self::open(arguments),
             _ => Err(anyhow!("invalid route `{route}`. available: `open`")),
         }
     }
 }
 
-impl Instrument<String, Note> for Synth {
+impl Instrument<SynthArguments, SynthEvent> for Synth {
     fn help(&self) -> String {
-        "MIDI synthesizer".into()
+        "MIDI synthesizer\n\
+        Open: `Synth.open '<file.sf2>'` or \
+        `Synth.open { file, presets = { <preset> | { preset, channel }, .. } }`, presets \
+        being for channels 1, 2 and so on unless they give a channel\n\
+        Events: `{ on = <note> | { <note>, .. } }` or \
+        `{ on = { notes, velocity = <0..127>, channel = <1..16>, duration = <units> } }`, \
+        `{ off = <note> | { <note>, .. } | { notes, channel } }` and `release`. Notes are MIDI \
+        numbers or names like \"C4\" and \"F#2\"\n\
+        Presets: `{ program = <preset> | { preset, channel } }`, a preset being a program \
+        number, `{ bank, program }` or the name of one in the SoundFont\n\
+        Controllers: `bank`, `modulation`, `volume` and `pan` (0..127), `bend` (-1..1) and \
+        `sustain` (true or false), each as `{ <controller> = <value> }` or \
+        `{ <controller> = { value, channel } }`"
+            .into()
     }
 }
 
@@ -1,13 +1,19 @@
 use libplunder::instrument::package_instrument;
 
+mod event;
+mod export;
 mod instrument;
-use instrument::Note;
-pub use instrument::Synth;
+pub use event::{NoteOn, Notes, Pitch, SynthEvent, DEFAULT_VELOCITY};
+pub use export::Export;
+pub use instrument::{ChannelPreset, Key, Note, Synth, SynthArguments};
 impl Synth {
     pub fn package(lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
-        package_instrument::<Self, String, Note>(lua, "synth".to_string())
+        package_instrument::<Self, SynthArguments, SynthEvent>(lua, "synth".to_string())
     }
 }
 
 mod parser;
+mod pattern;
+mod smf;
 pub use parser::MidiParser;
+pub use smf::{FromMessage, Message, Smf};
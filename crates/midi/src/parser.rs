@@ -1,13 +1,50 @@
 // TODO move parser to different crate than synth
-use std::sync::{Arc, RwLock};
+use std::{
+    path::Path,
+    sync::{Arc, RwLock},
+};
 
 use libplunder::prelude::instrument::*;
-use log::{info, trace};
+use log::trace;
 use mlua::prelude::*;
+use serde::de::DeserializeOwned;
 
-use crate::{instrument::Note, Synth};
+use crate::{
+    event::SynthEvent,
+    pattern,
+    smf::{Event, FromMessage, Message, Smf, Timing},
+    Synth, SynthArguments,
+};
 
-pub struct MidiParser(PackagedInstrument);
+pub struct MidiParser {
+    instrument: PackagedInstrument,
+    /// Event of the instrument that plays a message of a MIDI file or pattern, if it plays it at all
+    message: fn(SharedPlunderInstrument, Message) -> Option<EmittableUserData>,
+}
+
+/// Events of a track of a MIDI file, at the units they happen at
+pub type Track = Vec<(f64, EmittableUserData)>;
+
+/// Event `event` of the instrument, ready to emit
+fn emittable<I, A, E>(instrument: SharedPlunderInstrument, event: E) -> EmittableUserData
+where
+    A: Send + Sync + 'static,
+    E: DeserializeOwned + Clone + Send + Sync + 'static,
+    I: Instrument<A, E> + Send + Sync + 'static,
+{
+    let instrument_and_event: InstrumentAndEvent<
+        SharedPlunderInstrument,
+        (I, A),
+        E,
+        E,
+        DownInstrumentUpEvent,
+    > = InstrumentAndEvent::new(instrument, event);
+    trace!(
+        "instrument-and-event's help: `{}`",
+        instrument_and_event.instrument_help()
+    );
+    EmittableUserData(Arc::new(RwLock::new(instrument_and_event)))
+}
 
 impl MidiParser {
     pub fn new(synth: PackagedInstrument) -> Self {
@@ -20,61 +57,81 @@ impl MidiParser {
         // .unwrap()
         // .0
         // .transform(LuaValue::Nil);
-        Self(synth)
+        Self::with_events::<Synth, SynthArguments, SynthEvent>(synth)
     }
 
-    pub fn parse(
-        &self,
-        pattern_str: &str,
-        _lua: &Lua,
-    ) -> LuaResult<Vec<(usize, EmittableUserData)>> {
-        self.parse_notes(pattern_str.chars().collect::<Vec<_>>().as_slice())
-            .map_err(LuaError::runtime)?
-            .into_iter()
-            .enumerate()
-            .map(|(id, note)| {
-                info!("generate next event with id: `{id}`");
-                let instrument_and_event: InstrumentAndEvent<
-                    SharedPlunderInstrument,
-                    (Synth, String),
-                    Note,
-                    Note,
-                    DownInstrumentUpEvent,
-                > = InstrumentAndEvent::new(self.0.factory.clone(), note);
-                trace!(
-                    "instrument-and-event's help: `{}`",
-                    instrument_and_event.instrument_help()
-                );
+    /// Parser of notes for an instrument of type `I`, that plays them as events it makes of their
+    /// MIDI messages
+    pub fn with_events<I, A, E>(instrument: PackagedInstrument) -> Self
+    where
+        A: Send + Sync + 'static,
+        E: FromMessage + DeserializeOwned + Clone + Send + Sync + 'static,
+        I: Instrument<A, E> + Send + Sync + 'static,
+    {
+        Self {
+            instrument,
+            message: |instrument, message| {
+                E::from_message(message).map(|event| emittable::<I, A, E>(instrument, event))
+            },
+        }
+    }
 
-                // let any: &dyn Any = &instrument_and_event.instrument.0;
-                // warn!("type-id at parse: `{:?}`", any.type_id());
-                Ok((
-                    id,
-                    EmittableUserData(Arc::new(RwLock::new(instrument_and_event))),
-                ))
+    /// The tempo of the MIDI file at `path`, and every track of it as events at the units they
+    /// happen at, quarter notes being the unit. Tracks keep their place even when they have
+    /// nothing the instrument plays
+    pub fn file(&self, path: &Path) -> anyhow::Result<(Timing, Vec<Track>)> {
+        let smf = Smf::load(path)?;
+        let tracks = smf
+            .tracks
+            .iter()
+            .map(|track| {
+                track
+                    .iter()
+                    .filter_map(|(tick, event)| match event {
+                        Event::Message(message) => {
+                            (self.message)(self.instrument.factory.clone(), *message)
+                                .map(|event| (smf.units(*tick), event))
+                        }
+                        _ => None,
+                    })
+                    .collect()
             })
-            .collect::<LuaResult<Vec<_>>>()
+            .collect();
+        Ok((smf.timing()?, tracks))
     }
 
-    fn parse_notes(&self, pattern_str: &[char]) -> Result<Vec<Note>, String> {
-        let mut notes = Vec::new();
-        let pattern_str = pattern_str.iter().copied().enumerate().collect::<Vec<_>>();
-
-        for note_str in pattern_str.split(|(_, c)| c.is_whitespace()) {
-            trace!(
-                "got note-string: `{}`",
-                note_str.iter().fold(String::new(), |mut a, (_, c)| {
-                    a.push(*c);
-                    a
-                })
-            );
-            let Some(note) = Note::from_spanned_str(note_str)? else {
-                continue;
-            };
-            trace!("parsed as: `{:?}`", note);
-            notes.push(note);
+    /// Events that play `pattern`, at the units they happen at. Notes end as the next ones start
+    /// unless they're given a duration, see [`pattern`] for the rest of the language
+    pub fn parse(&self, pattern: &str) -> Result<Vec<(f64, EmittableUserData)>, String> {
+        let steps = pattern::parse(pattern).map_err(|err| err.show(pattern))?;
+        // Notes end before the next ones start, even at the same unit
+        let mut messages = Vec::new();
+        for step in steps {
+            trace!("parsed step: `{step:?}`");
+            for &key in &step.keys {
+                let (channel, velocity) = (1, step.velocity);
+                messages.push((
+                    step.at,
+                    1,
+                    Message::NoteOn {
+                        channel,
+                        key,
+                        velocity,
+                    },
+                ));
+                let end = step.at + step.duration;
+                messages.push((end, 0, Message::NoteOff { channel, key }));
+            }
         }
-        Ok(notes)
+        messages.sort_by(|(at, order, _), (other, other_order, _)| {
+            at.total_cmp(other).then(order.cmp(other_order))
+        });
+        Ok(messages
+            .into_iter()
+            .filter_map(|(at, _, message)| {
+                (self.message)(self.instrument.factory.clone(), message).map(|event| (at, event))
+            })
+            .collect())
     }
 }
 
@@ -82,14 +139,37 @@ impl LuaUserData for MidiParser {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_method("parse", |lua, this: &Self, pattern: String| {
             let table = lua.create_table()?;
-            this.parse(&pattern, lua)?.into_iter().try_for_each(
-                |(id, event)| -> LuaResult<()> {
+            this.parse(&pattern)
+                .map_err(LuaError::runtime)?
+                .into_iter()
+                .try_for_each(|(units, event)| -> LuaResult<()> {
                     let elem = lua.create_table()?;
-                    elem.push(id)?;
+                    elem.push(units)?;
                     elem.push(event)?;
                     table.push(elem)
-                },
-            )?;
+                })?;
+            Ok(table)
+        });
+        methods.add_method("file", |lua, this: &Self, path: String| {
+            let (timing, tracks) = this
+                .file(Path::new(&path))
+                .map_err(|err| LuaError::runtime(format!("error importing `{path}`: {err:#}")))?;
+            let table = lua.create_table()?;
+            table.set("tempo", lua.to_value(&timing)?)?;
+            let tracks_table = lua.create_table()?;
+            for track in tracks {
+                let track_table = lua.create_table()?;
+                track
+                    .into_iter()
+                    .try_for_each(|(units, event)| -> LuaResult<()> {
+                        let elem = lua.create_table()?;
+                        elem.push(units)?;
+                        elem.push(event)?;
+                        track_table.push(elem)
+                    })?;
+                tracks_table.push(track_table)?;
+            }
+            table.set("tracks", tracks_table)?;
             Ok(table)
         });
     }
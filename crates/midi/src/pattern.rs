@@ -0,0 +1,545 @@
+//! The note language of `Midi(...):parse`. Items are separated by whitespace and each lasts a
+//! unit unless given a duration:
+//!
+//! - `C4`, `f#3`, `Eb5`: a note. Notes without an octave are in the octave of the note before
+//!   them, the 4th to begin with, and every `'` or `,` after a note raises or lowers it an octave
+//! - `-` or `.`: a rest
+//! - `~`: holds the notes before it for as long as it lasts, tying them over
+//! - `[C4 E4 G4]`: a chord
+//! - `(C4 E4)`: a group, to repeat together
+//! - `:2`, `:0.5` or `:1/3` after an item: the units it lasts
+//! - `!80` after a note or chord: its velocity. On its own, the velocity of every note after it
+//! - `*4` after an item: plays it that many times, at most 65536, and patterns may expand to at
+//!   most 1048576 items
+use std::ops::Range;
+
+use crate::event::DEFAULT_VELOCITY;
+
+/// Octave of notes that don't give one until a note does
+const DEFAULT_OCTAVE: i32 = 4;
+/// Times an item can be repeated
+const MAX_REPEAT: usize = 1 << 16;
+/// Items a pattern, or any group of it, can expand to once repeated
+const MAX_ITEMS: usize = 1 << 20;
+
+/// Notes that start together in a pattern
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    /// Units into the pattern that the notes start at
+    pub at: f64,
+    pub duration: f64,
+    pub keys: Vec<u8>,
+    pub velocity: u8,
+}
+
+/// A mistake in a pattern, and the characters it's at
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternError {
+    pub span: Range<usize>,
+    pub message: String,
+}
+
+impl PatternError {
+    fn new(span: Range<usize>, message: impl Into<String>) -> Self {
+        PatternError {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// The error, with the line of `pattern` it's at and its characters underlined
+    pub fn show(&self, pattern: &str) -> String {
+        let chars: Vec<char> = pattern.chars().collect();
+        let start = self.span.start.min(chars.len());
+        let line_start = chars[..start]
+            .iter()
+            .rposition(|c| *c == '\n')
+            .map_or(0, |newline| newline + 1);
+        let line_end = chars[start..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(chars.len(), |newline| start + newline);
+        let line: String = chars[line_start..line_end].iter().collect();
+        let width = self.span.end.min(line_end).saturating_sub(start).max(1);
+        format!(
+            "at {}: {}\n  {line}\n  {}{}",
+            self.span.start,
+            self.message,
+            " ".repeat(start - line_start),
+            "^".repeat(width)
+        )
+    }
+}
+
+/// An item of a pattern, with its group expanded and repeats repeated
+#[derive(Debug, Clone)]
+enum Item {
+    Notes {
+        keys: Vec<u8>,
+        velocity: u8,
+        duration: f64,
+    },
+    Rest(f64),
+    Tie {
+        duration: f64,
+        span: Range<usize>,
+    },
+}
+
+impl Item {
+    fn set_duration(&mut self, units: f64) {
+        match self {
+            Item::Notes { duration, .. } | Item::Tie { duration, .. } => *duration = units,
+            Item::Rest(duration) => *duration = units,
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: &'a [char],
+    at: usize,
+    /// Octave of notes that don't give one
+    octave: i32,
+    /// Velocity of notes that don't give one
+    velocity: u8,
+}
+
+/// Steps of `pattern` in the order they start
+pub fn parse(pattern: &str) -> Result<Vec<Step>, PatternError> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut parser = Parser {
+        chars: &chars,
+        at: 0,
+        octave: DEFAULT_OCTAVE,
+        velocity: DEFAULT_VELOCITY,
+    };
+    let items = parser.items(None)?;
+
+    let mut steps: Vec<Step> = Vec::new();
+    let (mut at, mut resting) = (0., true);
+    for item in items {
+        match item {
+            Item::Notes {
+                keys,
+                velocity,
+                duration,
+            } => {
+                steps.push(Step {
+                    at,
+                    duration,
+                    keys,
+                    velocity,
+                });
+                at += duration;
+                resting = false;
+            }
+            Item::Rest(duration) => {
+                at += duration;
+                resting = true;
+            }
+            Item::Tie { duration, span } => {
+                match steps.last_mut() {
+                    Some(step) if !resting => step.duration += duration,
+                    // Holding a rest rests for longer
+                    _ if at > 0. => (),
+                    _ => return Err(PatternError::new(span, "nothing to tie over")),
+                }
+                at += duration;
+            }
+        }
+    }
+    Ok(steps)
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.at).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.at += 1;
+        }
+    }
+
+    /// Items up to the end of the pattern, or up to and including `close` if they're in brackets
+    /// opened at `open`
+    fn items(&mut self, close: Option<(char, usize)>) -> Result<Vec<Item>, PatternError> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match (self.peek(), close) {
+                (None, None) => return Ok(items),
+                (None, Some((close, open))) => {
+                    return Err(PatternError::new(
+                        open..open + 1,
+                        format!("unclosed, expected a `{close}` to close it"),
+                    ))
+                }
+                (Some(c), Some((close, _))) if c == close => {
+                    self.at += 1;
+                    return Ok(items);
+                }
+                _ => {
+                    let start = self.at;
+                    items.extend(self.item()?);
+                    if items.len() > MAX_ITEMS {
+                        return Err(PatternError::new(
+                            start..self.at,
+                            format!("pattern expands to more than {MAX_ITEMS} items"),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    fn item(&mut self) -> Result<Vec<Item>, PatternError> {
+        let start = self.at;
+        let c = self.peek().expect("not at the end");
+        let (mut items, grouped) = match c {
+            '!' => {
+                self.at += 1;
+                self.velocity = self.velocity_mark(start)?;
+                return Ok(Vec::new());
+            }
+            '-' | '.' => {
+                self.at += 1;
+                (vec![Item::Rest(1.)], false)
+            }
+            '~' => {
+                self.at += 1;
+                let span = start..self.at;
+                (vec![Item::Tie { duration: 1., span }], false)
+            }
+            '[' => {
+                self.at += 1;
+                (vec![self.chord(start)?], false)
+            }
+            '(' => {
+                self.at += 1;
+                (self.items(Some((')', start)))?, true)
+            }
+            'A'..='G' | 'a'..='g' => {
+                let key = self.note()?;
+                (
+                    vec![Item::Notes {
+                        keys: vec![key],
+                        velocity: self.velocity,
+                        duration: 1.,
+                    }],
+                    false,
+                )
+            }
+            ']' | ')' => {
+                return Err(PatternError::new(
+                    start..start + 1,
+                    format!("unmatched `{c}`"),
+                ))
+            }
+            _ => {
+                return Err(PatternError::new(
+                    start..start + 1,
+                    format!("unexpected `{c}`, expected a note, rest, tie, chord or group"),
+                ))
+            }
+        };
+
+        let (mut duration, mut velocity, mut repeat) = (None, None, None);
+        loop {
+            let suffix = self.at;
+            match self.peek() {
+                Some(':') => {
+                    self.at += 1;
+                    let units = self.duration(suffix)?;
+                    if grouped {
+                        return Err(PatternError::new(
+                            suffix..self.at,
+                            "groups can't be given a duration, only the items in them",
+                        ));
+                    }
+                    Self::once(&mut duration, units, suffix..self.at, "duration")?;
+                }
+                Some('!') => {
+                    self.at += 1;
+                    let value = self.velocity_mark(suffix)?;
+                    if !matches!(items[..], [Item::Notes { .. }]) {
+                        return Err(PatternError::new(
+                            suffix..self.at,
+                            "only notes and chords can be given a velocity",
+                        ));
+                    }
+                    Self::once(&mut velocity, value, suffix..self.at, "velocity")?;
+                }
+                Some('*') => {
+                    self.at += 1;
+                    let times = self.integer(suffix)?;
+                    let span = suffix..self.at;
+                    if times == 0 {
+                        return Err(PatternError::new(
+                            span,
+                            "items must be repeated at least once",
+                        ));
+                    }
+                    if times > MAX_REPEAT {
+                        return Err(PatternError::new(
+                            span,
+                            format!("items can be repeated at most {MAX_REPEAT} times"),
+                        ));
+                    }
+                    Self::once(&mut repeat, (times, span.clone()), span, "repeat")?;
+                }
+                _ => break,
+            }
+        }
+
+        for item in &mut items {
+            if let Some(units) = duration {
+                item.set_duration(units);
+            }
+            if let (Some(value), Item::Notes { velocity, .. }) = (velocity, &mut *item) {
+                *velocity = value;
+            }
+        }
+        let Some((times, span)) = repeat else {
+            return Ok(items);
+        };
+        let total = items
+            .len()
+            .checked_mul(times)
+            .filter(|total| *total <= MAX_ITEMS)
+            .ok_or_else(|| {
+                PatternError::new(span, format!("repeats to more than {MAX_ITEMS} items"))
+            })?;
+        Ok(items.iter().cycle().take(total).cloned().collect())
+    }
+
+    /// Set a suffix, unless the item already has it
+    fn once<T>(
+        suffix: &mut Option<T>,
+        value: T,
+        span: Range<usize>,
+        name: &str,
+    ) -> Result<(), PatternError> {
+        if suffix.replace(value).is_some() {
+            return Err(PatternError::new(span, format!("{name} given twice")));
+        }
+        Ok(())
+    }
+
+    /// Notes of a chord up to the `]` that closes the one opened at `open`
+    fn chord(&mut self, open: usize) -> Result<Item, PatternError> {
+        let mut keys = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(']') => {
+                    self.at += 1;
+                    break;
+                }
+                Some('A'..='G' | 'a'..='g') => keys.push(self.note()?),
+                Some(c) => {
+                    return Err(PatternError::new(
+                        self.at..self.at + 1,
+                        format!("unexpected `{c}` in a chord, which only has notes in it"),
+                    ))
+                }
+                None => {
+                    return Err(PatternError::new(
+                        open..open + 1,
+                        "unclosed chord, expected a `]` to close it",
+                    ))
+                }
+            }
+        }
+        if keys.is_empty() {
+            return Err(PatternError::new(open..self.at, "chord without notes"));
+        }
+        Ok(Item::Notes {
+            keys,
+            velocity: self.velocity,
+            duration: 1.,
+        })
+    }
+
+    /// MIDI number of a note, counting C0 as 0, moving the octave of notes after it to its own
+    fn note(&mut self) -> Result<u8, PatternError> {
+        let start = self.at;
+        let semitone = match self.peek().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => unreachable!("notes start with their key"),
+        };
+        self.at += 1;
+        let accidental = match self.peek() {
+            Some('#') => 1,
+            Some('b') => -1,
+            _ => 0,
+        };
+        if accidental != 0 {
+            self.at += 1;
+        }
+        let mut octave = match self.peek().and_then(|c| c.to_digit(10)) {
+            Some(octave) => {
+                self.at += 1;
+                octave as i32
+            }
+            None => self.octave,
+        };
+        loop {
+            match self.peek() {
+                Some('\'') => octave += 1,
+                Some(',') => octave -= 1,
+                _ => break,
+            }
+            self.at += 1;
+        }
+        let number = octave * 12 + semitone + accidental;
+        if !(0..=127).contains(&number) || octave < 0 {
+            let note: String = self.chars[start..self.at].iter().collect();
+            return Err(PatternError::new(
+                start..self.at,
+                format!("note `{note}` is out of the MIDI range of C0 to G10"),
+            ));
+        }
+        self.octave = octave;
+        Ok(number as u8)
+    }
+
+    /// Digits at the current character, which `what` needs at least one of
+    fn digits(&mut self, start: usize, what: &str) -> Result<String, PatternError> {
+        let from = self.at;
+        while self.peek().is_some_and(|c| c.is_ascii_digit() || c == '.') {
+            self.at += 1;
+        }
+        if from == self.at {
+            return Err(PatternError::new(
+                start..self.at.max(start + 1),
+                format!("expected {what}"),
+            ));
+        }
+        Ok(self.chars[from..self.at].iter().collect())
+    }
+
+    fn integer(&mut self, start: usize) -> Result<usize, PatternError> {
+        self.digits(start, "a number")?
+            .parse()
+            .map_err(|_| PatternError::new(start..self.at, "expected a whole number"))
+    }
+
+    fn velocity_mark(&mut self, start: usize) -> Result<u8, PatternError> {
+        match self.integer(start)? {
+            velocity @ 0..=127 => Ok(velocity as u8),
+            velocity => Err(PatternError::new(
+                start..self.at,
+                format!("velocity must be at most 127, not {velocity}"),
+            )),
+        }
+    }
+
+    /// Units like `2`, `0.5` or `1/3`
+    fn duration(&mut self, start: usize) -> Result<f64, PatternError> {
+        let invalid = |parser: &Self| PatternError::new(start..parser.at, "invalid duration");
+        let mut units: f64 = self
+            .digits(start, "a duration")?
+            .parse()
+            .map_err(|_| invalid(self))?;
+        if self.peek() == Some('/') {
+            self.at += 1;
+            let denominator: f64 = self
+                .digits(start, "a denominator")?
+                .parse()
+                .map_err(|_| invalid(self))?;
+            units /= denominator;
+        }
+        if !units.is_finite() || units <= 0. {
+            return Err(PatternError::new(
+                start..self.at,
+                "durations must be more than 0 units",
+            ));
+        }
+        Ok(units)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(at: f64, duration: f64, keys: &[u8], velocity: u8) -> Step {
+        Step {
+            at,
+            duration,
+            keys: keys.to_vec(),
+            velocity,
+        }
+    }
+
+    #[test]
+    fn notes_rests_ties_and_durations() {
+        assert_eq!(
+            parse("A5 c#5 - Eb4:2 ~ . G4:1/2 ~:1.5").unwrap(),
+            vec![
+                step(0., 1., &[69], 100),
+                step(1., 1., &[61], 100),
+                step(3., 3., &[51], 100),
+                step(7., 2., &[55], 100),
+            ]
+        );
+        // Octaves carry over from note to note, and marks move them
+        let keys: Vec<_> = parse("C3 E G C' B, D,, c")
+            .unwrap()
+            .into_iter()
+            .flat_map(|step| step.keys)
+            .collect();
+        assert_eq!(keys, vec![36, 40, 43, 48, 47, 14, 12]);
+    }
+
+    #[test]
+    fn chords_velocities_and_repeats() {
+        assert_eq!(
+            parse("[C4 E G]:2!80 !60 (D4 -)*2 F4!127*2").unwrap(),
+            vec![
+                step(0., 2., &[48, 52, 55], 80),
+                step(2., 1., &[50], 60),
+                step(4., 1., &[50], 60),
+                step(6., 1., &[53], 127),
+                step(7., 1., &[53], 127),
+            ]
+        );
+        assert_eq!(parse("  ").unwrap(), vec![]);
+    }
+
+    #[test]
+    fn errors_point_at_their_span() {
+        let error = |pattern| parse(pattern).unwrap_err();
+        assert_eq!(error("C4 H4").span, 3..4);
+        assert_eq!(error("C4 [E4 G4").span, 3..4);
+        assert_eq!(error("C4 E4)").span, 5..6);
+        assert_eq!(error("C4 G9''").span, 3..7);
+        assert_eq!(error("C1 D,,").span, 3..6);
+        assert_eq!(error("C4:0").span, 2..4);
+        assert_eq!(error("C4!200").span, 2..6);
+        assert_eq!(error("C4:2:3").span, 4..6);
+        assert_eq!(error("-!80").span, 1..4);
+        assert_eq!(error("(C4 D4):2").span, 7..9);
+        assert_eq!(error("C4*0").span, 2..4);
+        assert_eq!(error("C4*65537").span, 2..8);
+        assert_eq!(error("C4*99999999999999999999999").span, 2..26);
+        // Nested repeats are capped by what they expand to, not just by how often they repeat
+        assert_eq!(error("((C4 D4)*1024)*1024").span, 14..19);
+        assert_eq!(error("(C4*65536)*16 (D4*65536)*16 E4").span, 14..27);
+        assert_eq!(parse("(C4*1024)*1024").unwrap().len(), MAX_ITEMS);
+        assert_eq!(error("~ C4").span, 0..1);
+        assert_eq!(error("[]").span, 0..2);
+        assert_eq!(
+            error("C4 E4\nG4 Hb4").show("C4 E4\nG4 Hb4"),
+            "at 9: unexpected `H`, expected a note, rest, tie, chord or group\n  G4 Hb4\n     ^"
+        );
+    }
+}
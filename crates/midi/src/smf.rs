@@ -0,0 +1,559 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+
+/// A message of a MIDI file that instruments may play
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    /// Channels count from 1
+    NoteOn {
+        channel: u8,
+        key: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        key: u8,
+    },
+    Program {
+        channel: u8,
+        program: u8,
+    },
+    Control {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    /// 14 bits, `8192` being no bend at all
+    Bend {
+        channel: u8,
+        value: u16,
+    },
+}
+
+/// Events of instruments that play messages of MIDI files
+pub trait FromMessage: Sized {
+    /// The event that plays `message`, if the instrument plays it at all
+    fn from_message(message: Message) -> Option<Self>;
+}
+
+/// What happens at a tick of a track
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Message(Message),
+    /// Microseconds per quarter note
+    Tempo(u32),
+    /// Numerator, and the power of 2 that the denominator is
+    Signature(u8, u8),
+}
+
+/// A Standard MIDI File of format 0 or 1
+#[derive(Debug, Default)]
+pub struct Smf {
+    /// Ticks per quarter note
+    pub division: u16,
+    /// Events of every track, by the tick they happen at
+    pub tracks: Vec<Vec<(u64, Event)>>,
+}
+
+/// Reads the bytes of a chunk
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> anyhow::Result<&'a [u8]> {
+        let bytes = self
+            .bytes
+            .get(self.at..self.at + count)
+            .with_context(|| format!("unexpected end of file at byte {}", self.at))?;
+        self.at += count;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    /// A variable-length quantity, 7 bits to a byte with the high bit set on all but the last
+    fn vlq(&mut self) -> anyhow::Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(anyhow!("variable-length quantity longer than 4 bytes"))
+    }
+
+    fn chunk(&mut self) -> anyhow::Result<(&'a [u8], Reader<'a>)> {
+        let id = self.take(4)?;
+        let length = self.u32()? as usize;
+        let bytes = self.take(length)?;
+        Ok((id, Reader { bytes, at: 0 }))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.at >= self.bytes.len()
+    }
+}
+
+impl Smf {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Smf::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut file = Reader { bytes, at: 0 };
+        let (id, mut header) = file.chunk()?;
+        if id != b"MThd" {
+            return Err(anyhow!(
+                "not a MIDI file, it doesn't start with a `MThd` chunk"
+            ));
+        }
+        let format = header.u16()?;
+        let count = header.u16()?;
+        let division = header.u16()?;
+        if format > 1 {
+            return Err(anyhow!(
+                "MIDI files of format {format} aren't supported, only 0 and 1"
+            ));
+        }
+        if division & 0x8000 != 0 || division == 0 {
+            return Err(anyhow!("MIDI files timed in SMPTE frames aren't supported"));
+        }
+
+        let mut tracks = Vec::new();
+        while tracks.len() < count as usize {
+            let (id, track) = file.chunk()?;
+            // Chunks of unknown types are to be skipped
+            if id == b"MTrk" {
+                tracks.push(
+                    Smf::track(track).with_context(|| format!("in track {}", tracks.len() + 1))?,
+                );
+            }
+        }
+        Ok(Smf { division, tracks })
+    }
+
+    fn track(mut track: Reader) -> anyhow::Result<Vec<(u64, Event)>> {
+        let mut events = Vec::new();
+        let (mut tick, mut running) = (0u64, None);
+        while !track.is_empty() {
+            tick += track.vlq()? as u64;
+            let mut status = track.byte()?;
+            match status {
+                0xff => {
+                    let kind = track.byte()?;
+                    let length = track.vlq()? as usize;
+                    let data = track.take(length)?;
+                    match (kind, data) {
+                        (0x2f, _) => break,
+                        (0x51, &[a, b, c]) => {
+                            events.push((tick, Event::Tempo(u32::from_be_bytes([0, a, b, c]))))
+                        }
+                        (0x58, &[numerator, denominator, ..]) => {
+                            events.push((tick, Event::Signature(numerator, denominator)))
+                        }
+                        _ => (),
+                    }
+                    continue;
+                }
+                0xf0 | 0xf7 => {
+                    let length = track.vlq()? as usize;
+                    track.take(length)?;
+                    continue;
+                }
+                // Data bytes carry on with the status of the last message
+                0x00..=0x7f => {
+                    status = running.context("data byte without a status to run on")?;
+                    track.at -= 1;
+                }
+                _ => running = Some(status),
+            }
+            let channel = (status & 0x0f) + 1;
+            let data = match status & 0xf0 {
+                0xc0 | 0xd0 => [track.byte()?, 0],
+                _ => [track.byte()?, track.byte()?],
+            };
+            let message = match (status & 0xf0, data) {
+                (0x90, [key, velocity]) if velocity > 0 => Message::NoteOn {
+                    channel,
+                    key,
+                    velocity,
+                },
+                (0x80 | 0x90, [key, _]) => Message::NoteOff { channel, key },
+                (0xb0, [controller, value]) => Message::Control {
+                    channel,
+                    controller,
+                    value,
+                },
+                (0xc0, [program, _]) => Message::Program { channel, program },
+                (0xe0, [low, high]) => Message::Bend {
+                    channel,
+                    value: low as u16 | (high as u16) << 7,
+                },
+                _ => continue,
+            };
+            events.push((tick, Event::Message(message)));
+        }
+        Ok(events)
+    }
+
+    /// The file as written to disk, of format 1 with every track in it. Events of each track must
+    /// be in order of their ticks
+    pub fn write(&self) -> anyhow::Result<Vec<u8>> {
+        let count = u16::try_from(self.tracks.len()).map_err(|_| {
+            anyhow!(
+                "a MIDI file holds at most {} tracks, not {}",
+                u16::MAX,
+                self.tracks.len()
+            )
+        })?;
+        let mut header = 1u16.to_be_bytes().to_vec();
+        header.extend(count.to_be_bytes());
+        header.extend(self.division.to_be_bytes());
+        let mut file = chunk(b"MThd", &header);
+        for track in &self.tracks {
+            let mut data = Vec::new();
+            let mut last = 0;
+            for (tick, event) in track {
+                write_vlq(&mut data, (tick - last) as u32);
+                last = *tick;
+                match *event {
+                    Event::Message(Message::NoteOn {
+                        channel,
+                        key,
+                        velocity,
+                    }) => data.extend([0x90 | (channel - 1), key, velocity]),
+                    Event::Message(Message::NoteOff { channel, key }) => {
+                        data.extend([0x80 | (channel - 1), key, 0x40])
+                    }
+                    Event::Message(Message::Control {
+                        channel,
+                        controller,
+                        value,
+                    }) => data.extend([0xb0 | (channel - 1), controller, value]),
+                    Event::Message(Message::Program { channel, program }) => {
+                        data.extend([0xc0 | (channel - 1), program])
+                    }
+                    Event::Message(Message::Bend { channel, value }) => data.extend([
+                        0xe0 | (channel - 1),
+                        (value & 0x7f) as u8,
+                        (value >> 7) as u8 & 0x7f,
+                    ]),
+                    Event::Tempo(micros) => {
+                        data.extend([0xff, 0x51, 3]);
+                        data.extend(&micros.min(0xff_ffff).to_be_bytes()[1..]);
+                    }
+                    // A metronome click every quarter note, with 8 32nd notes to it
+                    Event::Signature(numerator, power) => {
+                        data.extend([0xff, 0x58, 4, numerator, power, 24, 8])
+                    }
+                }
+            }
+            data.extend([0x00, 0xff, 0x2f, 0x00]);
+            file.extend(chunk(b"MTrk", &data));
+        }
+        Ok(file)
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        Ok(fs::write(path, self.write()?)?)
+    }
+
+    /// Units that `tick` is into the file, quarter notes being the unit of its timing
+    pub fn units(&self, tick: u64) -> f64 {
+        tick as f64 / self.division as f64
+    }
+
+    /// Tempo and signature changes of every track, in the shape of the tempo table of `render`
+    pub fn timing(&self) -> anyhow::Result<Timing> {
+        let mut changes: Vec<(u64, &Event)> = self
+            .tracks
+            .iter()
+            .flatten()
+            .filter(|(_, event)| !matches!(event, Event::Message(_)))
+            .map(|(tick, event)| (*tick, event))
+            .collect();
+        changes.sort_by_key(|(tick, _)| *tick);
+
+        let mut timing = Timing {
+            bpm: DEFAULT_BPM,
+            signature: "4/4".to_string(),
+            unit: "1/4",
+            changes: Vec::new(),
+        };
+        // Bars counted up to the last signature, which starts at `bar_tick` and lasts `bar_length`
+        let (mut bars, mut bar_tick, mut bar_length) = (0, 0, self.division as u64 * 4);
+        for (tick, event) in changes {
+            match *event {
+                Event::Tempo(micros) => {
+                    let bpm = 60_000_000. / micros.max(1) as f64;
+                    match timing.changes.last_mut() {
+                        _ if tick == 0 => timing.bpm = bpm,
+                        Some(change) if change.tick == tick => change.bpm = Some(bpm),
+                        _ => timing
+                            .changes
+                            .push(Change::new(tick, self.units(tick), Some(bpm))),
+                    }
+                }
+                Event::Signature(0, _) => {
+                    return Err(anyhow!("time signature at tick {tick} has no beats"))
+                }
+                Event::Signature(numerator, power) => {
+                    let signature = format!("{numerator}/{}", 1u64 << power.min(6));
+                    let since = tick - bar_tick;
+                    if since % bar_length != 0 {
+                        return Err(anyhow!(
+                            "time signature {signature} at tick {tick} doesn't start a bar"
+                        ));
+                    }
+                    bars += since / bar_length;
+                    bar_tick = tick;
+                    bar_length = (self.division as u64 * 4 * numerator as u64) >> power.min(6);
+                    if tick == 0 {
+                        timing.signature = signature;
+                        continue;
+                    }
+                    // Signature changes are at the start of a bar, counting from 1
+                    timing.changes.push(Change {
+                        tick,
+                        at: At::Bar(format!("{}", bars + 1)),
+                        bpm: None,
+                        signature: Some(signature),
+                    });
+                }
+                Event::Message(_) => (),
+            }
+        }
+        Ok(timing)
+    }
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    [id, &(data.len() as u32).to_be_bytes()[..], data].concat()
+}
+
+fn write_vlq(data: &mut Vec<u8>, value: u32) {
+    let groups = (1..5)
+        .find(|groups| value >> (7 * groups) == 0)
+        .unwrap_or(5);
+    data.extend((0..groups).rev().map(|group| {
+        let byte = (value >> (7 * group)) as u8 & 0x7f;
+        match group {
+            0 => byte,
+            _ => byte | 0x80,
+        }
+    }));
+}
+
+/// Tempo of files that don't give one
+const DEFAULT_BPM: f64 = 120.;
+
+/// Tempo table of `render`, with quarter notes as units
+#[derive(Debug, Serialize)]
+pub struct Timing {
+    pub bpm: f64,
+    pub signature: String,
+    pub unit: &'static str,
+    pub changes: Vec<Change>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum At {
+    Units(f64),
+    Bar(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Change {
+    #[serde(skip)]
+    tick: u64,
+    pub at: At,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Change {
+    fn new(tick: u64, units: f64, bpm: Option<f64>) -> Self {
+        Change {
+            tick,
+            at: At::Units(units),
+            bpm,
+            signature: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(format: u16, tracks: &[&[u8]]) -> Vec<u8> {
+        let mut header = format.to_be_bytes().to_vec();
+        header.extend(u16::try_from(tracks.len()).unwrap().to_be_bytes());
+        header.extend(480u16.to_be_bytes());
+        let mut file = chunk(b"MThd", &header);
+        tracks
+            .iter()
+            .for_each(|track| file.extend(chunk(b"MTrk", track)));
+        file
+    }
+
+    #[test]
+    fn reads_notes_with_running_status() {
+        #[rustfmt::skip]
+        let track: &[u8] = &[
+            0x00, 0x90, 60, 100,
+            // Running status, and a note-on without velocity that ends the note
+            0x83, 0x60, 64, 90,
+            0x00, 60, 0,
+            0x00, 0xc1, 5,
+            0x00, 0xe1, 0x00, 0x40,
+            0x81, 0x70, 0x81, 64, 0,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let smf = Smf::parse(&file(0, &[track])).unwrap();
+        let event = |channel, key, velocity| match velocity {
+            0 => Event::Message(Message::NoteOff { channel, key }),
+            _ => Event::Message(Message::NoteOn {
+                channel,
+                key,
+                velocity,
+            }),
+        };
+        assert_eq!(
+            smf.tracks[0],
+            vec![
+                (0, event(1, 60, 100)),
+                (480, event(1, 64, 90)),
+                (480, event(1, 60, 0)),
+                (
+                    480,
+                    Event::Message(Message::Program {
+                        channel: 2,
+                        program: 5
+                    })
+                ),
+                (
+                    480,
+                    Event::Message(Message::Bend {
+                        channel: 2,
+                        value: 8192
+                    })
+                ),
+                (720, event(2, 64, 0)),
+            ]
+        );
+        assert_eq!(smf.units(720), 1.5);
+        assert!(Smf::parse(&file(2, &[track])).is_err());
+        assert!(Smf::parse(b"RIFF").is_err());
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let note = |channel, key, velocity| {
+            Event::Message(match velocity {
+                0 => Message::NoteOff { channel, key },
+                _ => Message::NoteOn {
+                    channel,
+                    key,
+                    velocity,
+                },
+            })
+        };
+        let smf = Smf {
+            division: 960,
+            tracks: vec![
+                vec![(0, Event::Signature(6, 3)), (0, Event::Tempo(500_000))],
+                vec![
+                    (0, note(1, 60, 100)),
+                    (200_000, note(1, 60, 0)),
+                    (200_000, note(16, 127, 1)),
+                    (200_127, note(16, 127, 0)),
+                    (
+                        200_127,
+                        Event::Message(Message::Control {
+                            channel: 3,
+                            controller: 64,
+                            value: 127,
+                        }),
+                    ),
+                    (
+                        200_127,
+                        Event::Message(Message::Program {
+                            channel: 3,
+                            program: 40,
+                        }),
+                    ),
+                    (
+                        200_128,
+                        Event::Message(Message::Bend {
+                            channel: 3,
+                            value: 16383,
+                        }),
+                    ),
+                ],
+            ],
+        };
+        let read = Smf::parse(&smf.write().unwrap()).unwrap();
+        assert_eq!((read.division, read.tracks), (smf.division, smf.tracks));
+        let crowded = Smf {
+            division: 480,
+            tracks: vec![Vec::new(); u16::MAX as usize + 1],
+        };
+        assert!(crowded.write().is_err());
+
+        let mut data = Vec::new();
+        [0, 0x7f, 0x80, 0x3fff, 0x0fff_ffff]
+            .iter()
+            .for_each(|value| write_vlq(&mut data, *value));
+        let mut reader = Reader {
+            bytes: &data,
+            at: 0,
+        };
+        let values: Vec<_> = (0..5).map(|_| reader.vlq().unwrap()).collect();
+        assert_eq!(values, vec![0, 0x7f, 0x80, 0x3fff, 0x0fff_ffff]);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn times_tempo_and_signature_changes() {
+        #[rustfmt::skip]
+        let tempos: &[u8] = &[
+            // 4/4 at 100 bpm, then 3/4 from the second bar and 150 bpm a beat into it
+            0x00, 0xff, 0x58, 4, 4, 2, 24, 8,
+            0x00, 0xff, 0x51, 3, 0x09, 0x27, 0xc0,
+            0x8f, 0x00, 0xff, 0x58, 4, 3, 2, 24, 8,
+            0x83, 0x60, 0xff, 0x51, 3, 0x06, 0x1a, 0x80,
+            0x00, 0xff, 0x2f, 0x00,
+        ];
+        let smf = Smf::parse(&file(1, &[tempos, &[0x00, 0xff, 0x2f, 0x00]])).unwrap();
+        assert_eq!(smf.tracks.len(), 2);
+        let timing = smf.timing().unwrap();
+        assert_eq!((timing.bpm, timing.signature.as_str()), (100., "4/4"));
+        assert!(matches!(
+            &timing.changes[..],
+            [
+                Change { at: At::Bar(bar), signature: Some(signature), .. },
+                Change { at: At::Units(units), bpm: Some(bpm), .. },
+            ] if bar == "2" && signature == "3/4" && *units == 5. && *bpm == 150.
+        ));
+    }
+}
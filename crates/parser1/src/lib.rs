@@ -4,17 +4,58 @@ use libplunder::is_event;
 use log::{info, trace};
 use mlua::prelude::*;
 
-pub struct Parser(pub(crate) Option<ParseTable>);
+pub struct Parser {
+    table: Option<ParseTable>,
+    swing: Swing,
+}
 
 impl Parser {
     pub fn new() -> Self {
-        Parser(None)
+        Parser {
+            table: None,
+            swing: Swing::default(),
+        }
+    }
+}
+
+/// Where in each pair of units the second one lands, 0.5 keeps them straight
+#[derive(Debug, Clone, Copy)]
+struct Swing(f64);
+
+impl Default for Swing {
+    fn default() -> Self {
+        Swing(0.5)
+    }
+}
+
+impl Swing {
+    /// Position of the event at unit `id`, with every other unit delayed by the swing
+    fn position(&self, id: usize) -> f64 {
+        if id % 2 == 1 {
+            (id - 1) as f64 + 2. * self.0
+        } else {
+            id as f64
+        }
+    }
+
+    fn set(&mut self, swing: f64) -> Result<(), String> {
+        if !(swing > 0. && swing < 1.) {
+            return Err(format!("swing must be between 0 and 1, not `{swing}`"));
+        }
+        self.0 = swing;
+        Ok(())
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Parser {
     fn parse(&self, pattern_str: &[char]) -> LuaResult<Vec<(usize, LuaValue)>> {
-        self.0
+        self.table
             .as_ref()
             .ok_or(LuaError::runtime(
                 "Need a parse-table to initialize the parser".to_string(),
@@ -23,9 +64,7 @@ impl Parser {
     }
 
     fn extend(&mut self, argument: LuaValue, lua: &Lua) -> Result<(), String> {
-        *self = Parser(Some(
-            ParseTable::from_lua(argument, lua).map_err(|err| err.to_string())?,
-        ));
+        self.table = Some(ParseTable::from_lua(argument, lua).map_err(|err| err.to_string())?);
         Ok(())
     }
 }
@@ -42,7 +81,11 @@ impl LuaUserData for Parser {
                 .into_iter()
                 .map(|(id, event)| {
                     let table = lua.create_table()?;
-                    table.push(id)?;
+                    // Positions off the grid are fractional units, the rest stay integers
+                    match parser.swing.position(id) {
+                        position if position.fract() == 0. => table.push(position as usize)?,
+                        position => table.push(position)?,
+                    }
                     table.push(event)?;
                     Ok(table)
                 })
@@ -50,9 +93,11 @@ impl LuaUserData for Parser {
         });
 
         methods.add_method_mut("extend", |lua, parser: &mut Parser, argument: LuaValue| {
-            parser
-                .extend(argument, lua)
-                .map_err(|err| LuaError::runtime(err))
+            parser.extend(argument, lua).map_err(LuaError::runtime)
+        });
+
+        methods.add_method_mut("swing", |_, parser: &mut Parser, swing: f64| {
+            parser.swing.set(swing).map_err(LuaError::runtime)
         });
     }
 }
@@ -94,7 +139,7 @@ impl ParseTable {
                                         emit_map.push((read, emit));
                                         Ok(())
                                     })?,
-                                v @ _ => Err(LuaError::runtime(format!(
+                                v => Err(LuaError::runtime(format!(
                                     "unexpected value in sequence of events: `{}` is not an event",
                                     v.to_string()?
                                 )))?,
@@ -103,7 +148,7 @@ impl ParseTable {
                             break;
                         }
                     }
-                    read = read + 1;
+                    read += 1;
                 }
                 Ok(emit_map)
             }
@@ -191,3 +236,34 @@ pub fn string_match(haystack: &[char], start: usize, needle: Chars, _regex: bool
 //     //     )
 //     // }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn positions(swing: &Swing) -> Vec<f64> {
+        (0..5).map(|id| swing.position(id)).collect()
+    }
+
+    #[test]
+    fn swing_delays_off_beats() {
+        let mut swing = Swing::default();
+        assert_eq!(positions(&swing), vec![0., 1., 2., 3., 4.]);
+
+        swing.set(0.75).unwrap();
+        assert_eq!(positions(&swing), vec![0., 1.5, 2., 3.5, 4.]);
+        swing.set(0.25).unwrap();
+        assert_eq!(positions(&swing), vec![0., 0.5, 2., 2.5, 4.]);
+    }
+
+    #[test]
+    fn swing_stays_inside_its_pair() {
+        let mut swing = Swing::default();
+        swing.set(0.6).unwrap();
+        for value in [0., 1., -0.5, 1.5, f64::NAN, f64::INFINITY] {
+            assert!(swing.set(value).is_err(), "{value}");
+        }
+        // Rejected swings leave the last one in place
+        assert_eq!(swing.position(1), 1.2);
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::anyhow;
+
+/// Gain, pan and the level that a sampler fades in and out at
+#[derive(Debug, Clone)]
+pub struct Amp {
+    gain: f32,
+    /// Balance of a stereo frame between its left (`-1`) and right (`1`) channels
+    pan: f32,
+    /// Frames taken to fade all the way in, and all the way out
+    pub fade_in: u64,
+    pub fade_out: u64,
+    level: f32,
+    /// Level being faded to
+    target: f32,
+    /// Change in level every frame until it reaches the target
+    step: f32,
+}
+
+impl Default for Amp {
+    fn default() -> Self {
+        Amp {
+            gain: 1.,
+            pan: 0.,
+            fade_in: 0,
+            fade_out: 0,
+            level: 1.,
+            target: 1.,
+            step: 0.,
+        }
+    }
+}
+
+impl Amp {
+    pub fn set_gain(&mut self, decibels: f64) -> anyhow::Result<()> {
+        if decibels.is_nan() || decibels == f64::INFINITY {
+            return Err(anyhow!("invalid gain `{decibels}`dB"));
+        }
+        self.gain = 10f64.powf(decibels / 20.) as f32;
+        Ok(())
+    }
+
+    pub fn set_pan(&mut self, pan: f32) -> anyhow::Result<()> {
+        if !(-1. ..=1.).contains(&pan) {
+            return Err(anyhow!("pan must be between -1 and 1, not `{pan}`"));
+        }
+        self.pan = pan;
+        Ok(())
+    }
+
+    /// Fade in to full level, from silence if `from_silence`
+    pub fn fade_in(&mut self, from_silence: bool) {
+        if from_silence {
+            self.level = 0.;
+        }
+        self.fade(1., self.fade_in);
+    }
+
+    pub fn fade_out(&mut self) {
+        self.fade(0., self.fade_out);
+    }
+
+    /// Fade out all the way in `frames` frames, however long fading out usually takes
+    pub fn fade_out_in(&mut self, frames: u64) {
+        self.fade(0., frames);
+    }
+
+    /// Fade to `target` at a rate that takes `frames` frames to fade all the way, or jump straight
+    /// to it if `frames` is 0
+    fn fade(&mut self, target: f32, frames: u64) {
+        self.target = target;
+        if frames == 0 {
+            self.level = target;
+        }
+        self.step = 1. / frames.max(1) as f32;
+    }
+
+    /// Whether it has faded out all the way
+    pub fn is_silent(&self) -> bool {
+        self.level == 0. && self.target == 0.
+    }
+
+    /// Apply to every frame of `channels` interleaved samples in `buffer`
+    pub fn process(&mut self, buffer: &mut [f32], channels: usize) {
+        if self.level == 1. && self.target == 1. && self.gain == 1. && self.pan == 0. {
+            return;
+        }
+        for frame in buffer.chunks_exact_mut(channels) {
+            if self.level < self.target {
+                self.level = (self.level + self.step).min(self.target);
+            } else if self.level > self.target {
+                self.level = (self.level - self.step).max(self.target);
+            }
+            let gain = self.gain * self.level;
+            frame.iter_mut().for_each(|sample| *sample *= gain);
+            if let [left, right] = frame {
+                *left *= (1. - self.pan).min(1.);
+                *right *= (1. + self.pan).min(1.);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fades_linearly() {
+        let mut amp = Amp {
+            fade_in: 4,
+            fade_out: 2,
+            ..Default::default()
+        };
+        amp.fade_in(true);
+        let mut buffer = [1.; 6];
+        amp.process(&mut buffer, 1);
+        assert_eq!(buffer, [0.25, 0.5, 0.75, 1., 1., 1.]);
+
+        amp.fade_out();
+        let mut buffer = [1.; 3];
+        amp.process(&mut buffer, 1);
+        assert_eq!(buffer, [0.5, 0., 0.]);
+        assert!(amp.is_silent());
+    }
+
+    #[test]
+    fn gains_and_pans() {
+        let mut amp = Amp::default();
+        amp.set_gain(-20.).unwrap();
+        amp.set_pan(-0.5).unwrap();
+        let mut buffer = [1., 1.];
+        amp.process(&mut buffer, 2);
+        assert!((buffer[0] - 0.1).abs() < 1e-6 && (buffer[1] - 0.05).abs() < 1e-6);
+        assert!(amp.set_pan(2.).is_err());
+        assert!(amp.set_gain(f64::NAN).is_err());
+    }
+}
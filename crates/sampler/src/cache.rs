@@ -0,0 +1,222 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::SystemTime,
+};
+
+use serde::Serialize;
+
+/// Every file imported, decoded once for every sampler that imports it, by where it is. Files
+/// are only kept for as long as a sampler plays them
+static CACHE: Mutex<BTreeMap<PathBuf, Entry>> = Mutex::new(BTreeMap::new());
+
+/// Audio of an entire file, decoded
+#[derive(Clone)]
+pub struct Decoded {
+    /// Interleaved samples, shared by every sampler that plays them
+    pub samples: Arc<[f32]>,
+    pub sample_rate: Option<u32>,
+    pub channels: usize,
+}
+
+struct Entry {
+    /// Samples of the file, gone once the last sampler playing them is
+    samples: Weak<[f32]>,
+    sample_rate: Option<u32>,
+    channels: usize,
+    /// When the file was modified as it was decoded, so that changes to it are decoded anew
+    modified: SystemTime,
+}
+
+/// A file in the cache, as listed to Lua
+#[derive(Debug, Serialize)]
+pub struct Cached {
+    pub path: PathBuf,
+    pub frames: usize,
+    pub channels: usize,
+    pub sample_rate: Option<u32>,
+    pub bytes: usize,
+    /// Samplers playing the file, which keep it in memory even once it's cleared
+    pub samplers: usize,
+}
+
+/// The file at `path` decoded, from the cache if it hasn't changed since, or else with `decode`
+pub fn decoded<F>(path: &Path, decode: F) -> anyhow::Result<Decoded>
+where
+    F: FnOnce() -> anyhow::Result<Decoded>,
+{
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    // Files that don't tell when they were modified can't tell whether they changed either
+    let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) else {
+        return decode();
+    };
+    if let Some(entry) = CACHE.lock().unwrap().get(&path) {
+        if let (true, Some(samples)) = (entry.modified == modified, entry.samples.upgrade()) {
+            return Ok(Decoded {
+                samples,
+                sample_rate: entry.sample_rate,
+                channels: entry.channels,
+            });
+        }
+    }
+    // Decoding may take a while, and other files can be looked up in the meantime
+    let decoded = decode()?;
+    let mut cache = CACHE.lock().unwrap();
+    // Files no sampler plays anymore are only taking up room
+    cache.retain(|_, entry| entry.samples.strong_count() > 0);
+    cache.insert(
+        path,
+        Entry {
+            samples: Arc::downgrade(&decoded.samples),
+            sample_rate: decoded.sample_rate,
+            channels: decoded.channels,
+            modified,
+        },
+    );
+    Ok(decoded)
+}
+
+/// Every file in the cache that a sampler still plays
+pub fn list() -> Vec<Cached> {
+    CACHE
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|(path, entry)| {
+            let samples = entry.samples.upgrade()?;
+            Some(Cached {
+                path: path.clone(),
+                frames: samples.len() / entry.channels.max(1),
+                channels: entry.channels,
+                sample_rate: entry.sample_rate,
+                bytes: std::mem::size_of_val(&*samples),
+                // Not counting the one just upgraded to
+                samplers: Arc::strong_count(&samples) - 1,
+            })
+        })
+        .collect()
+}
+
+/// Forget every file decoded, returning how many were still played. Samplers playing them keep
+/// their samples, but the next import decodes them anew
+pub fn clear() -> usize {
+    let mut cache = CACHE.lock().unwrap();
+    let cleared = cache
+        .values()
+        .filter(|entry| entry.samples.strong_count() > 0)
+        .count();
+    cache.clear();
+    cleared
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        fs::File,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            MutexGuard,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+
+    /// The cache is shared by every test, those that clear it must not run alongside the others
+    pub(crate) fn exclusive() -> MutexGuard<'static, ()> {
+        static EXCLUSIVE: Mutex<()> = Mutex::new(());
+        EXCLUSIVE
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn file(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(name);
+        fs::write(&path, [0; 16]).unwrap();
+        path
+    }
+
+    /// Decodes to a single frame, counting how many times it did
+    fn decode(count: &AtomicUsize) -> anyhow::Result<Decoded> {
+        count.fetch_add(1, Ordering::SeqCst);
+        Ok(Decoded {
+            samples: Arc::from([0.5]),
+            sample_rate: None,
+            channels: 1,
+        })
+    }
+
+    #[test]
+    fn hits_on_the_same_canonical_path() {
+        let _exclusive = exclusive();
+        let path = file("plunder-cache-hit.raw");
+        let count = AtomicUsize::new(0);
+        let first = decoded(&path, || decode(&count)).unwrap();
+        let dotted = path
+            .parent()
+            .unwrap()
+            .join(".")
+            .join(path.file_name().unwrap());
+        let second = decoded(&dotted, || decode(&count)).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(Arc::ptr_eq(&first.samples, &second.samples));
+    }
+
+    #[test]
+    fn misses_once_modified() {
+        let _exclusive = exclusive();
+        let path = file("plunder-cache-miss.raw");
+        let count = AtomicUsize::new(0);
+        let first = decoded(&path, || decode(&count)).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        let changed = decoded(&path, || decode(&count)).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert!(!Arc::ptr_eq(&first.samples, &changed.samples));
+        decoded(&path, || decode(&count)).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn clear_empties_the_list() {
+        let _exclusive = exclusive();
+        let path = file("plunder-cache-clear.raw");
+        let count = AtomicUsize::new(0);
+        let canonical = path.canonicalize().unwrap();
+        let _playing = decoded(&path, || decode(&count)).unwrap();
+        let listed = list();
+        let entry = listed
+            .iter()
+            .find(|cached| cached.path == canonical)
+            .unwrap();
+        assert_eq!((entry.frames, entry.bytes, entry.samplers), (1, 4, 1));
+
+        assert!(clear() >= 1);
+        // Samplers loaded by other tests in the meantime may have been cached again
+        assert!(list().iter().all(|cached| cached.path != canonical));
+        decoded(&path, || decode(&count)).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn frees_files_no_sampler_plays() {
+        let _exclusive = exclusive();
+        let path = file("plunder-cache-free.raw");
+        let canonical = path.canonicalize().unwrap();
+        let count = AtomicUsize::new(0);
+        let playing = decoded(&path, || decode(&count)).unwrap();
+        let samples = Arc::downgrade(&playing.samples);
+        drop(playing);
+        assert!(samples.upgrade().is_none());
+        assert!(list().iter().all(|cached| cached.path != canonical));
+
+        decoded(&path, || decode(&count)).unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+}
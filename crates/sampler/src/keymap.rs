@@ -0,0 +1,208 @@
+use std::{collections::HashMap, ops::RangeInclusive};
+
+use libplunder::{
+    layout::{ChannelLayout, Remix, DEFAULT_PAN_LAW},
+    prelude::instrument::{Source, SourceError},
+};
+use midi::Pitch;
+use serde::Deserialize;
+
+use crate::Sampler;
+
+/// A single key, or the lowest and highest of a range of them, each a MIDI number or a name like
+/// `"C4"` or `"F#2"`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Keys {
+    One(Pitch),
+    Range(Pitch, Pitch),
+}
+
+/// A zone of a keymap as given to `Sampler.kit`
+#[derive(Debug, Clone, Deserialize)]
+pub struct ZoneOptions {
+    pub file: String,
+    /// Keys that play the zone, every key if left out
+    pub keys: Option<Keys>,
+    /// Lowest and highest velocities that play the zone
+    pub velocity: Option<(u8, u8)>,
+    /// Key that the file is at, which other keys play it pitched relative to. Every key plays it
+    /// as it is if left out
+    pub root: Option<Pitch>,
+    /// Zones of a group that a note plays take turns playing it
+    pub group: Option<String>,
+    /// Playing a zone of a choke group cuts off every zone of it, like a closed hi-hat cutting
+    /// off an open one
+    pub choke: Option<String>,
+}
+
+/// Which notes and velocities play a zone
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    keys: RangeInclusive<i32>,
+    velocities: RangeInclusive<u8>,
+    pub root: Option<i32>,
+    group: Option<String>,
+    pub choke: Option<String>,
+}
+
+impl TryFrom<&ZoneOptions> for Mapping {
+    type Error = anyhow::Error;
+
+    fn try_from(options: &ZoneOptions) -> anyhow::Result<Self> {
+        let keys = match &options.keys {
+            None => 0..=i32::MAX,
+            Some(Keys::One(key)) => key.number()?..=key.number()?,
+            Some(Keys::Range(low, high)) => low.number()?..=high.number()?,
+        };
+        let (low, high) = options.velocity.unwrap_or((0, 127));
+        Ok(Mapping {
+            keys,
+            velocities: low..=high,
+            root: options.root.as_ref().map(Pitch::number).transpose()?,
+            group: options.group.clone(),
+            choke: options.choke.clone(),
+        })
+    }
+}
+
+/// Zones that `key` at `velocity` plays: every zone it maps to outside of a group, and the next
+/// one in turn of each group
+pub fn select(
+    mappings: &[Mapping],
+    turns: &mut HashMap<String, usize>,
+    key: i32,
+    velocity: u8,
+) -> Vec<usize> {
+    let mut selected = Vec::new();
+    let mut groups: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (zone, mapping) in mappings.iter().enumerate() {
+        if !mapping.keys.contains(&key) || !mapping.velocities.contains(&velocity) {
+            continue;
+        }
+        match &mapping.group {
+            Some(group) => groups.entry(group).or_default().push(zone),
+            None => selected.push(zone),
+        }
+    }
+    for (group, zones) in groups {
+        let turn = turns.entry(group.to_string()).or_default();
+        selected.push(zones[*turn % zones.len()]);
+        *turn += 1;
+    }
+    selected.sort();
+    selected
+}
+
+/// Files mapped to notes and velocities, each played by a sampler of its own
+pub struct Keymap {
+    pub zones: Vec<Sampler>,
+    pub mappings: Vec<Mapping>,
+    /// Zone of each group whose turn it is to play next
+    pub turns: HashMap<String, usize>,
+    /// Frames of a zone as it plays them, and of the mix in the output's channels
+    scratch: Vec<f32>,
+    frame: Vec<f64>,
+    mix: Vec<f64>,
+}
+
+impl Keymap {
+    pub fn new(zones: Vec<Sampler>, mappings: Vec<Mapping>) -> Self {
+        Keymap {
+            zones,
+            mappings,
+            turns: HashMap::new(),
+            scratch: Vec::new(),
+            frame: Vec::new(),
+            mix: Vec::new(),
+        }
+    }
+
+    /// Fill `buffer` with every zone mixed into frames of `channels` channels
+    pub fn fill(
+        &mut self,
+        buffer: &mut [f32],
+        channels: usize,
+    ) -> Result<(), SourceError<anyhow::Error>> {
+        let frames = buffer.len() / channels;
+        let remix = Remix::new(ChannelLayout::from_channels(channels), DEFAULT_PAN_LAW);
+        self.mix.clear();
+        self.mix.resize(frames * channels, 0.);
+        for zone in &mut self.zones {
+            let zone_channels = zone.channels;
+            self.scratch.resize(frames * zone_channels, 0.);
+            let filled = zone.fill_buffer(&mut self.scratch, zone_channels)?;
+            for (frame, out) in self
+                .scratch
+                .chunks_exact(zone_channels)
+                .take(filled)
+                .zip(self.mix.chunks_exact_mut(channels))
+            {
+                self.frame.clear();
+                self.frame.extend(frame.iter().map(|s| *s as f64));
+                remix.add(&self.frame, out);
+            }
+        }
+        buffer
+            .iter_mut()
+            .zip(&self.mix)
+            .for_each(|(b, m)| *b = *m as f32);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(keys: RangeInclusive<i32>, velocities: RangeInclusive<u8>, group: &str) -> Mapping {
+        Mapping {
+            keys,
+            velocities,
+            root: None,
+            group: (!group.is_empty()).then(|| group.to_string()),
+            choke: None,
+        }
+    }
+
+    #[test]
+    fn selects_layers_and_takes_turns() {
+        let mappings = [
+            mapping(36..=36, 0..=127, ""),
+            mapping(38..=40, 0..=63, ""),
+            mapping(38..=40, 64..=127, ""),
+            mapping(42..=42, 0..=127, "hat"),
+            mapping(42..=42, 0..=127, "hat"),
+            mapping(42..=42, 0..=127, "hat"),
+        ];
+        let mut turns = HashMap::new();
+        let mut select = |key, velocity| select(&mappings, &mut turns, key, velocity);
+        assert_eq!(select(36, 100), vec![0]);
+        assert_eq!(select(39, 20), vec![1]);
+        assert_eq!(select(39, 100), vec![2]);
+        assert_eq!(select(41, 100), Vec::<usize>::new());
+        let hats: Vec<_> = (0..4).map(|_| select(42, 100)).collect();
+        assert_eq!(hats, vec![vec![3], vec![4], vec![5], vec![3]]);
+    }
+
+    #[test]
+    fn maps_keys_by_number_or_name() {
+        let zone = |keys, root| ZoneOptions {
+            file: String::new(),
+            keys: Some(keys),
+            velocity: None,
+            root,
+            group: None,
+            choke: None,
+        };
+        let name = |name: &str| Pitch::Name(name.to_string());
+        let mapping = Mapping::try_from(&zone(
+            Keys::Range(Pitch::Number(30), name("C3")),
+            Some(name("F#2")),
+        ))
+        .unwrap();
+        assert_eq!((mapping.keys, mapping.root), (30..=36, Some(30)));
+        assert!(Mapping::try_from(&zone(Keys::One(name("H2")), None)).is_err());
+        assert!(Mapping::try_from(&zone(Keys::One(Pitch::Number(128)), None)).is_err());
+    }
+}
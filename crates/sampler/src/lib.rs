@@ -2,7 +2,9 @@ use std::{
     collections::VecDeque,
     fs::File,
     io::{self},
+    mem,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 use anyhow::{anyhow, Context};
@@ -10,74 +12,58 @@ use log::{info, trace};
 use serde::Deserialize;
 use symphonia::{
     core::{
-        audio::{AudioBuffer, AudioBufferRef, Signal},
+        audio::{AudioBufferRef, SampleBuffer},
         codecs::Decoder,
         errors::Error as SymphoniaError,
-        formats::{FormatReader, SeekMode, SeekTo},
+        formats::FormatReader,
         io::{MediaSource, MediaSourceStream},
         probe::Hint,
-        sample::Sample as SymphoniaSample,
     },
     default::get_codecs,
 };
 
+use amp::Amp;
+use cache::Decoded;
+use keymap::{Keymap, Mapping, ZoneOptions};
 use libplunder::prelude::instrument::*;
+use looping::{LoopMode, LoopSpec, Looping};
+use midi::{FromMessage, Message, NoteOn, Notes, DEFAULT_VELOCITY};
+use rate::{Pitch, Shifter};
+use seek::{Seek, SeekTarget};
+use slice::{Region, RegionTargets, Slice, SliceRef, SliceSpec};
+use stream::Stream;
+
+mod amp;
+pub mod cache;
+mod keymap;
+mod looping;
+mod rate;
+mod seek;
+mod slice;
+mod stream;
 
 const ERR_PREFIX: &str = "<|SAMPLER|>::Err   |";
 const _WARN_PREFIX: &str = "<|SAMPLER|>::Warn |";
 const MANUAL: &str = "<|SAMPLER|>";
+/// Tempo that seeks in beats are relative to until the sampler is told what it's rendered at
+const DEFAULT_BPM: f64 = 120.;
+/// Seconds that choking a sampler takes to fade it out
+const CHOKE_FADE: f64 = 0.005;
 
-struct ToSample<'a>(AudioBufferRef<'a>);
-
-type Channels<T> = Vec<T>;
-fn yo<T: SymphoniaSample>(abr: &AudioBuffer<T>) -> Vec<Channels<T>> {
-    let ns = abr.frames();
-    let nc = abr.spec().channels.count();
-    let mut out = Vec::with_capacity(ns * nc * size_of::<T>());
-    let mut cur: &mut Vec<T>;
-    for is in 0..ns {
-        out.push(Vec::with_capacity(nc * size_of::<T>()));
-        cur = &mut out[is];
-        for ic in 0..nc {
-            cur.push(abr.chan(ic)[is]);
-        }
-    }
-    out
-}
-
-impl From<ToSample<'_>> for Vec<Sample> {
-    fn from(ToSample(abr): ToSample) -> Self {
-        use AudioBufferRef as ABR;
-
-        match abr {
-            ABR::U8(buf) => yo(&buf).into_iter().map(Sample::U8).collect(),
-            ABR::U16(buf) => yo(&buf).into_iter().map(Sample::U16).collect(),
-            ABR::U24(buf) => yo(&buf)
-                .into_iter()
-                .map(|ss| Sample::U24(ss.iter().map(|s| s.0).collect()))
-                .collect(),
-            ABR::U32(buf) => yo(&buf).into_iter().map(Sample::U32).collect(),
-            ABR::S8(buf) => yo(&buf).into_iter().map(Sample::S8).collect(),
-            ABR::S16(buf) => yo(&buf).into_iter().map(Sample::S16).collect(),
-            ABR::S24(buf) => yo(&buf)
-                .into_iter()
-                .map(|ss| Sample::S24(ss.iter().map(|s| s.0).collect()))
-                .collect(),
-            ABR::S32(buf) => yo(&buf).into_iter().map(Sample::S32).collect(),
-            ABR::F32(buf) => yo(&buf).into_iter().map(Sample::F32).collect(),
-            ABR::F64(buf) => yo(&buf).into_iter().map(Sample::F64).collect(),
-        }
-    }
+/// Every channel of decoded audio interleaved and normalized to `-1.0..1.0`
+fn interleave(decoded: AudioBufferRef) -> Vec<f32> {
+    let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+    buffer.copy_interleaved_ref(decoded);
+    buffer.samples().to_vec()
 }
 
 enum Reader {
-    File {
-        reader: Box<dyn FormatReader>,
-        decoder: Box<dyn Decoder>,
-        buffer: VecDeque<Sample>,
-    },
+    /// Decoded ahead on a thread of its own as it's read
+    File(Stream),
     Mem {
-        samples: Box<[Sample]>,
+        /// Interleaved samples of the entire file, shared with its voices
+        samples: Arc<[f32]>,
+        /// Index of the next frame to be read
         cursor: usize,
     },
 }
@@ -88,216 +74,935 @@ pub struct Sampler {
     backward: bool,
     reader: Reader,
     path: PathBuf,
+    sample_rate: Option<u32>,
+    channels: usize,
+    /// Length of the file in frames, if known
+    frames: Option<u64>,
+    /// Tempo being rendered at, that seeks in beats are relative to
+    bpm: f64,
+    /// The part of the file that plays
+    region: Region,
+    slices: Vec<Slice>,
+    looping: Looping,
+    /// Rate and pitch being played at
+    shifter: Shifter,
+    amp: Amp,
+    /// Whether it's fading out to pause
+    pausing: bool,
+    /// Samplers of the files that notes play, if keymapped
+    keymap: Option<Keymap>,
+    /// Voices playing on top of the sampler's own playhead, each triggered by an event
+    voices: Vec<Sampler>,
+    /// Voices that can play at once, triggering while only one can moves the playhead instead
+    polyphony: usize,
+    /// Frames of a voice as it plays them
+    scratch: Vec<f32>,
 }
 
-impl Sampler {
-    pub fn load<P>(path: P, read_entire: bool) -> Result<Self, anyhow::Error>
-    where
-        P: AsRef<Path>,
-    {
+/// Decode the next packet into `decoded`, returning `false` once there are no packets left
+fn decode_next(
+    reader: &mut dyn FormatReader,
+    decoder: &mut dyn Decoder,
+    decoded: &mut VecDeque<f32>,
+) -> Result<bool, SourceError<anyhow::Error>> {
+    use SourceError::*;
+
+    loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(false);
+            }
+            // The stream changed, e.g. a new chained stream began, so the decoder starts over
+            Err(SymphoniaError::ResetRequired) => {
+                decoder.reset();
+                continue;
+            }
+            Err(err) => {
+                return Err(Fatal(anyhow!("error getting next packet: {}", err)));
+            }
+        };
+
+        let decoded_packet = match decoder.decode(&packet) {
+            Err(SymphoniaError::ResetRequired) => {
+                decoder.reset();
+                decoder.decode(&packet)
+            }
+            decoded_packet => decoded_packet,
+        };
+        return match decoded_packet {
+            Ok(decoded_packet) => {
+                decoded.extend(interleave(decoded_packet));
+                Ok(true)
+            }
+            Err(SymphoniaError::DecodeError(_) | SymphoniaError::IoError(_)) => {
+                Err(Once(anyhow!("undecodeable packet discard")))
+            }
+            Err(err) => Err(Fatal(anyhow!("error decoding packet: {}", err))),
+        };
+    }
+}
+
+/// A file probed and ready to decode
+struct Opened {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    sample_rate: Option<u32>,
+    channels: usize,
+    /// Length of the file in frames, if known
+    frames: Option<u64>,
+}
+
+impl Opened {
+    fn new(path: &Path) -> anyhow::Result<Self> {
         let mut hint = Hint::new();
-        if let Some(extension) = path.as_ref().extension() {
+        if let Some(extension) = path.extension() {
             if let Some(extension_str) = extension.to_str() {
                 hint.with_extension(extension_str);
             }
         }
 
-        let boxed_source: Box<dyn MediaSource> = Box::new(File::open(&path)?);
+        let boxed_source: Box<dyn MediaSource> = Box::new(File::open(path)?);
         let mss = MediaSourceStream::new(boxed_source, Default::default());
-        let mut probed = symphonia::default::get_probe().format(
+        let probed = symphonia::default::get_probe().format(
             &hint,
             mss,
             &Default::default(),
             &Default::default(),
         )?;
         let track = probed.format.default_track().unwrap();
-        let mut decoder = get_codecs().make(&track.codec_params, &Default::default())?;
+        let track_id = track.id;
+        let sample_rate = track.codec_params.sample_rate;
+        let frames = track.codec_params.n_frames;
+        let channels = track
+            .codec_params
+            .channels
+            .context("unknown number of channels")?
+            .count();
+        let decoder = get_codecs().make(&track.codec_params, &Default::default())?;
 
         // dont care about errors in printing info
         _ = debug::print_tracks(probed.format.tracks());
 
-        if read_entire {
-            let mut samples = Vec::new();
-            loop {
-                match probed.format.next_packet() {
-                    Ok(packet) => match decoder.decode(&packet) {
-                        Ok(abr) => samples.extend(Vec::from(ToSample(abr))),
-                        Err(SymphoniaError::IoError(_)) | Err(SymphoniaError::DecodeError(_)) => {
-                            samples.push(Sample::Empty)
-                        }
-                        // TODO reset, whatever that means
-                        Err(SymphoniaError::ResetRequired) => (),
-                        Err(err) => return Err(anyhow!("error decoding packet: {}", err)),
-                    },
-                    // TODO reset, whatever that means
-                    Err(SymphoniaError::ResetRequired) => (),
-                    Err(SymphoniaError::IoError(err))
-                        if err.kind() == io::ErrorKind::UnexpectedEof =>
-                    {
-                        break;
-                    }
-                    Err(err) => return Err(anyhow!("error getting next packet: {}", err)),
-                }
+        Ok(Opened {
+            format: probed.format,
+            decoder,
+            track_id,
+            sample_rate,
+            channels,
+            frames,
+        })
+    }
+
+    /// Decode every packet of the file
+    fn decode(mut self) -> anyhow::Result<Decoded> {
+        let mut samples = VecDeque::new();
+        loop {
+            match decode_next(self.format.as_mut(), self.decoder.as_mut(), &mut samples) {
+                Ok(true) => (),
+                Ok(false) => break,
+                // A frame of silence in place of what couldn't be decoded
+                Err(SourceError::Once(_)) => samples.extend(std::iter::repeat_n(0., self.channels)),
+                Err(SourceError::Fatal(err)) => return Err(err),
             }
-            Ok(Sampler {
-                reader: Reader::Mem {
-                    samples: Box::from(samples.as_slice()),
-                    cursor: 0,
-                },
-                outputting: false,
-                mute: false,
-                backward: false,
-                path: path.as_ref().to_path_buf(),
-            })
+        }
+        Ok(Decoded {
+            samples: Vec::from(samples).into(),
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+        })
+    }
+}
+
+impl Sampler {
+    /// A sampler of the file at `path`, decoded entirely into memory if `read_entire`, once for
+    /// every sampler that does, or else streamed from it
+    pub fn load<P>(path: P, read_entire: bool) -> Result<Self, anyhow::Error>
+    where
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        // Loop points embedded in the file, if any, are where a `points` loop loops by default
+        let looping = Looping {
+            points: File::open(path)
+                .and_then(|file| looping::smpl_loop(io::BufReader::new(file)))
+                .unwrap_or(None),
+            ..Default::default()
+        };
+
+        let mut sampler = if read_entire {
+            let decoded = cache::decoded(path, || Opened::new(path)?.decode())?;
+            let frames = Some((decoded.samples.len() / decoded.channels) as u64);
+            let reader = Reader::Mem {
+                samples: decoded.samples,
+                cursor: 0,
+            };
+            Sampler::new(reader, path, decoded.sample_rate, decoded.channels, frames)
         } else {
-            Ok(Sampler {
-                reader: Reader::File {
-                    decoder,
-                    reader: probed.format,
-                    buffer: VecDeque::new(),
-                },
-                outputting: false,
-                mute: false,
-                backward: false,
-                path: path.as_ref().to_path_buf(),
+            let opened = Opened::new(path)?;
+            let stream = Stream::new(
+                opened.format,
+                opened.decoder,
+                opened.track_id,
+                opened.channels,
+                opened.sample_rate,
+            );
+            Sampler::new(
+                Reader::File(stream),
+                path,
+                opened.sample_rate,
+                opened.channels,
+                opened.frames,
+            )
+        };
+        sampler.looping = looping;
+        Ok(sampler)
+    }
+
+    fn new(
+        reader: Reader,
+        path: &Path,
+        sample_rate: Option<u32>,
+        channels: usize,
+        frames: Option<u64>,
+    ) -> Self {
+        Sampler {
+            reader,
+            outputting: false,
+            mute: false,
+            backward: false,
+            path: path.to_path_buf(),
+            sample_rate,
+            channels,
+            frames,
+            bpm: DEFAULT_BPM,
+            region: Region::default(),
+            slices: Vec::new(),
+            looping: Looping::default(),
+            shifter: Shifter::new(channels, sample_rate),
+            amp: Amp::default(),
+            pausing: false,
+            keymap: None,
+            voices: Vec::new(),
+            polyphony: 1,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// A sampler of `audio` already in memory, as if it were an imported file
+    pub fn audio(audio: Audio) -> anyhow::Result<Self> {
+        if audio.channels == 0 || audio.samples.len() % audio.channels != 0 {
+            return Err(anyhow!(
+                "{} samples don't make up frames of {} channels",
+                audio.samples.len(),
+                audio.channels
+            ));
+        }
+        if audio.sample_rate == Some(0) {
+            return Err(anyhow!("sample rate must be positive"));
+        }
+        let frames = Some((audio.samples.len() / audio.channels) as u64);
+        let reader = Reader::Mem {
+            samples: audio.samples.into(),
+            cursor: 0,
+        };
+        Ok(Sampler::new(
+            reader,
+            Path::new("<audio>"),
+            audio.sample_rate,
+            audio.channels,
+            frames,
+        ))
+    }
+
+    /// A sampler that plays the files of `zones` on the notes they're mapped to, importing every
+    /// one of them
+    pub fn kit(zones: Vec<ZoneOptions>) -> anyhow::Result<Self> {
+        let mappings = zones
+            .iter()
+            .map(Mapping::try_from)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let zones = zones
+            .iter()
+            .map(|zone| {
+                let mut sampler = Sampler::load(&zone.file, true)
+                    .with_context(|| format!("while importing `{}`", zone.file))?;
+                // Zones only play once a note triggers them
+                sampler.control(AudioControls::Pause)?;
+                Ok(sampler)
             })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let sample_rate = zones.first().and_then(|zone| zone.sample_rate);
+        if let Some(zone) = zones.iter().find(|zone| zone.sample_rate != sample_rate) {
+            return Err(anyhow!(
+                "every file of a kit must be at the same sample rate, `{}` is at {:?} instead of \
+                    {sample_rate:?}",
+                zone.path.display(),
+                zone.sample_rate
+            ));
+        }
+        let channels = zones.iter().map(|zone| zone.channels).max().unwrap_or(1);
+        let mut sampler = Sampler::new(
+            Reader::Mem {
+                samples: Arc::new([]),
+                cursor: 0,
+            },
+            Path::new(""),
+            sample_rate,
+            channels,
+            None,
+        );
+        sampler.outputting = true;
+        sampler.keymap = Some(Keymap::new(zones, mappings));
+        Ok(sampler)
+    }
+
+    /// Play the zones of the keymap that each of the notes maps to from their start
+    fn play_note(&mut self, note: NoteOn) -> anyhow::Result<()> {
+        if note.velocity > 127 {
+            return Err(anyhow!(
+                "velocity must be at most 127, not {}",
+                note.velocity
+            ));
+        }
+        note.notes
+            .numbers()?
+            .into_iter()
+            .try_for_each(|key| self.play_key(key, note.velocity))
+    }
+
+    /// Play the zones of the keymap that `key` at `velocity` maps to from their start
+    fn play_key(&mut self, key: i32, velocity: u8) -> anyhow::Result<()> {
+        let keymap = self
+            .keymap
+            .as_mut()
+            .context("notes are played by samplers made with `kit`, that map them to files")?;
+        let zones = keymap::select(&keymap.mappings, &mut keymap.turns, key, velocity);
+        for zone in &zones {
+            if let Some(choke) = &keymap.mappings[*zone].choke {
+                keymap
+                    .zones
+                    .iter_mut()
+                    .zip(&keymap.mappings)
+                    .filter(|(_, mapping)| mapping.choke.as_ref() == Some(choke))
+                    .for_each(|(sampler, _)| sampler.choke());
+            }
+        }
+        for zone in zones {
+            let sampler = &mut keymap.zones[zone];
+            if let Some(root) = keymap.mappings[zone].root {
+                sampler.shifter.pitch = Pitch::Semitones((key - root) as f64).factor();
+            }
+            sampler.play_region(sampler.region)?;
         }
+        Ok(())
     }
 
-    pub fn next(&mut self) -> Result<Option<Sample>, SourceError<anyhow::Error>> {
-        use SourceError::*;
+    /// Resume playing, fading in from where it's at
+    fn resume(&mut self) {
+        self.pausing = false;
+        if !self.mute {
+            self.amp.fade_in(!self.outputting);
+        }
+        self.outputting = true;
+    }
 
-        match &mut self.reader {
-            Reader::File {
-                reader,
-                decoder,
-                ref mut buffer,
-            } => {
-                if !self.outputting {
-                    return Ok(Some(Sample::Empty));
-                }
+    /// Quickly fade out the playhead and every voice, and pause
+    fn choke(&mut self) {
+        let frames = (CHOKE_FADE * self.sample_rate.unwrap_or(44100) as f64) as u64;
+        if self.outputting {
+            self.pausing = true;
+            self.amp.fade_out_in(frames);
+        }
+        self.voices.iter_mut().for_each(Sampler::choke);
+    }
 
-                if buffer.is_empty() {
-                    // Re-fill buffer with next packet
-                    let packet = match reader.next_packet() {
-                        Ok(packet) => packet,
-                        Err(SymphoniaError::IoError(err))
-                            if err.kind() == io::ErrorKind::UnexpectedEof =>
-                        {
-                            return Ok(None);
-                        }
-                        // TODO reset, whatever that means
-                        Err(SymphoniaError::ResetRequired) => todo!(),
-                        Err(err) => {
-                            return Err(Fatal(anyhow!("error getting next packet: {}", err)));
-                        }
-                    };
-
-                    let decoded = match decoder.decode(&packet) {
-                        Ok(decoded) => decoded,
-                        // TODO reset, whatever that means
-                        Err(SymphoniaError::ResetRequired) => todo!(),
-                        Err(SymphoniaError::DecodeError(_) | SymphoniaError::IoError(_)) => {
-                            return Err(Once(anyhow!("undecodeable packet discard")));
-                        }
-                        Err(err) => {
-                            return Err(Fatal(anyhow!("error decoding packet: {}", err)));
-                        }
-                    };
-
-                    *buffer = Vec::from(ToSample(decoded)).into();
-
-                    if buffer.len() == 0 {
-                        return Err(Fatal(anyhow!("Packet has 0 samples")));
+    /// Play `region` from its start, or from its end when reversed, on a voice of its own if more
+    /// than one can play at once
+    fn play_region(&mut self, region: Region) -> anyhow::Result<()> {
+        if self.polyphony > 1 {
+            return self.spawn(region);
+        }
+        self.region = region;
+        match (self.backward, region.end) {
+            (true, Some(end)) => self.seek(end)?,
+            (true, None) => self.seek(self.frames.context("length of the file is unknown")?)?,
+            (false, _) => self.seek(region.start)?,
+        }
+        self.shifter.reset();
+        self.resume();
+        Ok(())
+    }
+
+    /// Play `region` on a new voice that plays the way the sampler does, cutting off the oldest
+    /// voices beyond the number that can play at once
+    fn spawn(&mut self, region: Region) -> anyhow::Result<()> {
+        let Reader::Mem { samples, .. } = &self.reader else {
+            return Err(anyhow!(
+                "cannot play voices of an opened file, import it to bring it entirely in memory"
+            ));
+        };
+        let reader = Reader::Mem {
+            samples: samples.clone(),
+            cursor: 0,
+        };
+        let mut voice = Sampler::new(
+            reader,
+            &self.path,
+            self.sample_rate,
+            self.channels,
+            self.frames,
+        );
+        voice.backward = self.backward;
+        voice.bpm = self.bpm;
+        voice.looping = self.looping;
+        voice.shifter.rate = self.shifter.rate;
+        voice.shifter.pitch = self.shifter.pitch;
+        voice.shifter.stretch = self.shifter.stretch;
+        voice.amp = self.amp.clone();
+        voice.mute = self.mute;
+        voice.play_region(region)?;
+        if voice.mute {
+            voice.amp.fade_out_in(0);
+        }
+
+        // The playhead is a voice too, if it's playing
+        let playing = self.outputting && !self.pausing;
+        let mut stealing =
+            (self.voices.iter().filter(|voice| !voice.pausing).count() + usize::from(playing) + 1)
+                .saturating_sub(self.polyphony);
+        if stealing > 0 && playing {
+            let frames = (CHOKE_FADE * self.sample_rate.unwrap_or(44100) as f64) as u64;
+            self.pausing = true;
+            self.amp.fade_out_in(frames);
+            stealing -= 1;
+        }
+        for stolen in self
+            .voices
+            .iter_mut()
+            .filter(|voice| !voice.pausing)
+            .take(stealing)
+        {
+            stolen.choke();
+        }
+        self.voices.push(voice);
+        Ok(())
+    }
+
+    /// Add every voice into `buffer`, dropping those that have finished
+    fn mix_voices(
+        &mut self,
+        buffer: &mut [f32],
+        channels: usize,
+    ) -> Result<(), SourceError<anyhow::Error>> {
+        let frames = buffer.len() / channels;
+        self.scratch.resize(buffer.len(), 0.);
+        let mut voices = mem::take(&mut self.voices);
+        let mut finished = Vec::new();
+        for (i, voice) in voices.iter_mut().enumerate() {
+            let filled = voice.fill_buffer(&mut self.scratch, channels)?;
+            buffer
+                .iter_mut()
+                .zip(&self.scratch[..filled * channels])
+                .for_each(|(b, s)| *b += s);
+            if filled < frames || !voice.outputting {
+                finished.push(i);
+            }
+        }
+        finished.into_iter().rev().for_each(|i| {
+            voices.remove(i);
+        });
+        self.voices = voices;
+        Ok(())
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<Sample>, SourceError<anyhow::Error>> {
+        if self.keymap.is_some() || self.polyphony > 1 || !self.voices.is_empty() {
+            let mut frame = vec![0.; self.channels];
+            self.fill_buffer(&mut frame, self.channels)?;
+            return Ok(Some(Sample::F32(frame)));
+        }
+        // Paused and muted samplers play silence, the same as they do a block at a time
+        let mut frame = vec![0.; self.channels];
+        if !self.outputting {
+            return Ok(Some(Sample::F32(frame)));
+        }
+        Ok(match self.sound_frames(&mut frame)? {
+            0 => None,
+            _ => Some(Sample::F32(frame)),
+        })
+    }
+
+    /// [`play_frames`](Self::play_frames) at the gain, pan and level being faded to, pausing once
+    /// faded out to pause
+    fn sound_frames(&mut self, buffer: &mut [f32]) -> Result<usize, SourceError<anyhow::Error>> {
+        let read = self.play_frames(buffer)?;
+        self.amp
+            .process(&mut buffer[..read * self.channels], self.channels);
+        if self.pausing && self.amp.is_silent() {
+            self.pausing = false;
+            self.outputting = false;
+        }
+        Ok(read)
+    }
+
+    /// Fill `buffer` from the playhead, with silence while paused
+    fn fill_playhead(
+        &mut self,
+        buffer: &mut [f32],
+        channels: usize,
+    ) -> Result<usize, SourceError<anyhow::Error>> {
+        if channels != self.channels {
+            // Frames of any other size are filled a sample at a time by the default
+            let mut frame = vec![0.; self.channels];
+            for (read, out) in buffer.chunks_exact_mut(channels).enumerate() {
+                out.fill(0.);
+                if self.outputting {
+                    if self.sound_frames(&mut frame)? == 0 {
+                        return Ok(read);
                     }
+                    out.iter_mut().zip(&frame).for_each(|(o, s)| *o = *s);
                 }
+            }
+            return Ok(buffer.len() / channels);
+        }
+        // A paused sampler keeps outputting silence
+        if !self.outputting {
+            buffer.fill(0.);
+            return Ok(buffer.len() / channels);
+        }
+        self.sound_frames(buffer)
+    }
 
-                Ok(Some(if !self.mute {
-                    buffer.pop_front().expect("cannot be empty, just checked")
-                } else {
-                    Sample::Empty
-                }))
+    /// [`read_frames`](Self::read_frames) at the rate and pitch being played at
+    fn play_frames(&mut self, buffer: &mut [f32]) -> Result<usize, SourceError<anyhow::Error>> {
+        if self.shifter.is_idle() {
+            self.shifter.reset();
+            return self.read_frames(buffer);
+        }
+        let idle = Shifter::new(self.channels, self.sample_rate);
+        let mut shifter = mem::replace(&mut self.shifter, idle);
+        let played = shifter.fill(buffer, |buffer| self.read_frames(buffer));
+        self.shifter = shifter;
+        played
+    }
+
+    /// Read as many frames as fit in `buffer`, returning the number of frames read. Reads fewer
+    /// only once there's nothing left to read in the region and it isn't looping
+    fn read_frames(&mut self, buffer: &mut [f32]) -> Result<usize, SourceError<anyhow::Error>> {
+        let channels = self.channels;
+        let frames = buffer.len() / channels;
+        let (mut read, mut empty) = (0, false);
+        loop {
+            let position = self.position();
+            // Reading stops at the edge of the loop once inside of it
+            let (start, end) = match self.loop_span() {
+                Some((_, end)) if !self.backward && position < end => (self.region.start, end),
+                Some((start, _)) if self.backward && position > start => (start, self.region_end()),
+                _ => (self.region.start, self.region_end()),
+            };
+            let buffer = &mut buffer[read * channels..frames * channels];
+            let pass = self.read_between(buffer, start, end)?;
+            self.crossfade(&mut buffer[..pass * channels], position);
+            read += pass;
+            // Looping over nothing would never fill the buffer
+            if read == frames || (empty && pass == 0) || !self.wrap().map_err(SourceError::Fatal)? {
+                return Ok(read);
+            }
+            empty = pass == 0;
+        }
+    }
+
+    /// Read frames between `start` and `end` into `buffer`, returning the number of frames read
+    fn read_between(
+        &mut self,
+        buffer: &mut [f32],
+        start: u64,
+        end: u64,
+    ) -> Result<usize, SourceError<anyhow::Error>> {
+        let channels = self.channels;
+        match &mut self.reader {
+            Reader::File(stream) => {
+                let position = stream.position();
+                let left = match self.backward {
+                    true => position.saturating_sub(start),
+                    false => end.saturating_sub(position),
+                };
+                let frames = (buffer.len() / channels).min(left as usize);
+                stream.read(&mut buffer[..frames * channels], self.backward)
             }
 
             Reader::Mem {
                 samples,
                 ref mut cursor,
             } => {
-                if !self.outputting {
-                    return Ok(Some(Sample::Empty));
-                }
-
-                if !self.backward {
-                    trace!("!! cursor at {cursor}, sample len is {}", samples.len());
-                    if *cursor >= samples.len() {
-                        return Ok(None);
-                    }
-                    *cursor += 1;
-                    trace!("Outputting samples: {:?}", samples[*cursor - 1]);
-                    Ok(Some(if !self.mute {
-                        samples[*cursor - 1].clone()
-                    } else {
-                        Sample::Empty
-                    }))
+                let frames = buffer.len() / channels;
+                let total = samples.len() / channels;
+                trace!("!! cursor at {cursor}, sample len is {total}");
+                let read = if !self.backward {
+                    let end = total.min(end as usize);
+                    let read = frames.min(end.saturating_sub(*cursor));
+                    buffer[..read * channels]
+                        .copy_from_slice(&samples[*cursor * channels..(*cursor + read) * channels]);
+                    *cursor += read;
+                    read
                 } else {
-                    if *cursor == 0 {
-                        return Ok(None);
-                    }
-                    *cursor -= 1;
-                    Ok(Some(if !self.mute {
-                        samples[*cursor].clone()
-                    } else {
-                        Sample::Empty
-                    }))
-                }
+                    let start = start as usize;
+                    let read = frames.min(cursor.saturating_sub(start));
+                    buffer
+                        .chunks_exact_mut(channels)
+                        .take(read)
+                        .for_each(|frame| {
+                            *cursor -= 1;
+                            frame.copy_from_slice(
+                                &samples[*cursor * channels..(*cursor + 1) * channels],
+                            );
+                        });
+                    read
+                };
+                Ok(read)
+            }
+        }
+    }
+
+    /// End of the region, or of the file when the region doesn't end
+    fn region_end(&self) -> u64 {
+        self.region.end.or(self.frames).unwrap_or(u64::MAX)
+    }
+
+    /// Start and end of the part of the file being looped over, if looping
+    fn loop_span(&self) -> Option<(u64, u64)> {
+        match self.looping.mode {
+            LoopMode::Off => None,
+            LoopMode::Forward | LoopMode::PingPong => Some((self.region.start, self.region_end())),
+            LoopMode::Points => self.looping.points.map(|points| {
+                (
+                    points.start,
+                    points.end.unwrap_or_else(|| self.region_end()),
+                )
+            }),
+        }
+    }
+
+    /// Carry on from the edge of the loop that reading stopped at, returning `false` if there's
+    /// no loop to carry on in
+    fn wrap(&mut self) -> anyhow::Result<bool> {
+        let Some((start, end)) = self.loop_span() else {
+            return Ok(false);
+        };
+        if !(start..=end).contains(&self.position()) {
+            return Ok(false);
+        }
+        match (self.looping.mode, self.backward) {
+            (LoopMode::PingPong, _) => self.backward = !self.backward,
+            (_, false) => self.seek(start)?,
+            // The end of a file of unknown length can't be seeked to
+            (_, true) if end != u64::MAX => self.seek(end)?,
+            (_, true) => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Blend the frames just read from `position` that lead up to the seam of a forward loop with
+    /// the frames leading up to where the loop picks up again, so that the seam doesn't click
+    fn crossfade(&self, buffer: &mut [f32], position: u64) {
+        let (Some((start, end)), Reader::Mem { samples, .. }) = (self.loop_span(), &self.reader)
+        else {
+            return;
+        };
+        if self.looping.mode == LoopMode::PingPong || self.looping.crossfade == 0 {
+            return;
+        }
+        let channels = self.channels;
+        let total = (samples.len() / channels) as u64;
+        let end = end.min(total);
+        let length = end.saturating_sub(start);
+        // There must be as many frames on the other side of the seam to fade into
+        let fade =
+            self.looping
+                .crossfade
+                .min(length)
+                .min(if self.backward { total - end } else { start });
+        for (i, frame) in buffer.chunks_exact_mut(channels).enumerate() {
+            // Frames from the seam, counting from 1, and the frame to fade into
+            let (distance, other) = if !self.backward {
+                let at = position + i as u64;
+                (end.saturating_sub(at), at.wrapping_sub(length))
+            } else {
+                let at = position - 1 - i as u64;
+                ((at + 1).saturating_sub(start), at + length)
+            };
+            if (1..=fade).contains(&distance) {
+                let kept = distance as f32 / fade as f32;
+                let other = &samples[other as usize * channels..(other as usize + 1) * channels];
+                frame
+                    .iter_mut()
+                    .zip(other)
+                    .for_each(|(s, o)| *s = *s * kept + o * (1. - kept));
+            }
+        }
+    }
+
+    /// Loop the way `spec` asks, keeping the loop points and crossfade already set if it doesn't
+    /// give them
+    fn set_loop(&mut self, spec: LoopSpec) -> anyhow::Result<()> {
+        let (mode, start, stop, crossfade) = match spec {
+            LoopSpec::Mode(mode) => (mode, None, None, None),
+            LoopSpec::Options {
+                mode,
+                start,
+                stop,
+                crossfade,
+            } => (mode, start, stop, crossfade),
+        };
+        if let (Reader::File(_), Some(_)) = (&self.reader, &crossfade) {
+            return Err(anyhow!(
+                "cannot crossfade an opened file, import it to bring it entirely in memory"
+            ));
+        }
+        if start.is_some() || stop.is_some() {
+            let points = Region {
+                start: start
+                    .map(|start| self.frame_of(start))
+                    .transpose()?
+                    .unwrap_or(0),
+                end: stop.map(|stop| self.frame_of(stop)).transpose()?,
+            };
+            if points.end.is_some_and(|end| end <= points.start) {
+                return Err(anyhow!("loop ends before it starts: {points:?}"));
             }
+            self.looping.points = Some(points);
+        }
+        if let Some(crossfade) = crossfade {
+            self.looping.crossfade = self.frame_of(crossfade)?;
+        }
+        if mode == LoopMode::Points && self.looping.points.is_none() {
+            return Err(anyhow!(
+                "no loop points were given and `{}` has none embedded",
+                self.path.display()
+            ));
+        }
+        self.looping.mode = mode;
+        Ok(())
+    }
+
+    /// Frame of the file that a seek target points at
+    fn frame_of(&self, target: SeekTarget) -> anyhow::Result<u64> {
+        Seek::try_from(target)?.frame(self.sample_rate, self.frames, self.bpm)
+    }
+
+    fn seek(&mut self, frame: u64) -> anyhow::Result<()> {
+        info!("Seeking to frame {frame}");
+        match &mut self.reader {
+            Reader::File(stream) => stream.seek(frame),
+            Reader::Mem { cursor, .. } => *cursor = frame as usize,
         }
+        Ok(())
+    }
+
+    fn position(&self) -> u64 {
+        match &self.reader {
+            Reader::File(stream) => stream.position(),
+            Reader::Mem { cursor, .. } => *cursor as u64,
+        }
+    }
+
+    /// Play only `region` of the file, moving into it if outside of it
+    fn set_region(&mut self, region: Region) -> anyhow::Result<()> {
+        if region.end.is_some_and(|end| end <= region.start) {
+            return Err(anyhow!("region ends before it starts: {region:?}"));
+        }
+        self.region = region;
+        let position = self.position();
+        if position < region.start || region.end.is_some_and(|end| position > end) {
+            self.seek(region.start)?;
+        }
+        Ok(())
+    }
+
+    fn slice(&mut self, spec: SliceSpec) -> anyhow::Result<Vec<Slice>> {
+        Ok(match spec {
+            SliceSpec::Divide { divide } => {
+                if divide == 0 {
+                    return Err(anyhow!("cannot divide into 0 slices"));
+                }
+                slice::divide(
+                    self.frames.context("length of the file is unknown")?,
+                    divide,
+                )
+            }
+            SliceSpec::Transients { transients } => match &self.reader {
+                Reader::Mem { samples, .. } => slice::transients(
+                    samples,
+                    self.channels,
+                    self.sample_rate
+                        .context("sample rate of the file is unknown")?,
+                    &transients,
+                ),
+                Reader::File(_) => {
+                    return Err(anyhow!(
+                        "cannot find the transients of an opened file, import it to bring it \
+                            entirely in memory"
+                    ))
+                }
+            },
+            SliceSpec::List(list) => {
+                let starts = list
+                    .iter()
+                    .map(|targets| self.frame_of(targets.start.clone()))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                list.into_iter()
+                    .enumerate()
+                    .map(|(i, targets)| {
+                        Ok(Slice {
+                            name: targets.name,
+                            region: Region {
+                                start: starts[i],
+                                end: match targets.stop {
+                                    Some(stop) => Some(self.frame_of(stop)?),
+                                    None => starts.get(i + 1).copied().or(self.frames),
+                                },
+                            },
+                        })
+                    })
+                    .collect::<anyhow::Result<_>>()?
+            }
+        })
+    }
+
+    /// Play a slice from its start, or from its end when reversed
+    fn trigger(&mut self, slice: SliceRef) -> anyhow::Result<()> {
+        let found = match &slice {
+            SliceRef::Index(index) => index.checked_sub(1).and_then(|i| self.slices.get(i)),
+            SliceRef::Name(name) => self
+                .slices
+                .iter()
+                .find(|slice| slice.name.as_ref() == Some(name)),
+        };
+        let region = found
+            .with_context(|| {
+                format!(
+                    "no slice {slice:?} among the sampler's {} slices",
+                    self.slices.len()
+                )
+            })?
+            .region;
+        self.play_region(region)
     }
 
     pub fn control(&mut self, event: AudioControls) -> Result<(), anyhow::Error> {
+        if let AudioControls::Note(note) = event {
+            return self.play_note(note);
+        }
+        // Anything else is for every zone of a keymap
+        if let Some(keymap) = &mut self.keymap {
+            return keymap
+                .zones
+                .iter_mut()
+                .try_for_each(|zone| zone.control(event.clone()));
+        }
+        // Voices go quiet along with the playhead, but otherwise play the way they started
+        if let AudioControls::Pause
+        | AudioControls::Mute
+        | AudioControls::Unmute
+        | AudioControls::Gain(_)
+        | AudioControls::Pan(_) = event
+        {
+            self.voices
+                .iter_mut()
+                .try_for_each(|voice| voice.control(event.clone()))?;
+        }
         match event {
-            AudioControls::Seek(duration) => match &mut self.reader {
-                Reader::File { reader, .. } => {
-                    let duration = duration_str::parse(duration)
-                        .map_err(|err| anyhow!("error parsing duration: {err}"))?;
-                    let _ = reader.seek(
-                        SeekMode::Accurate,
-                        SeekTo::Time {
-                            time: duration.into(),
-                            track_id: None,
-                        },
-                    )?;
+            AudioControls::Seek(target) => {
+                let frame = self.frame_of(target)?;
+                self.seek(frame)?;
+                self.shifter.reset();
+            }
+            AudioControls::Region(targets) => {
+                let region = Region {
+                    start: match targets.start {
+                        Some(start) => self.frame_of(start)?,
+                        None => 0,
+                    },
+                    end: targets.stop.map(|stop| self.frame_of(stop)).transpose()?,
+                };
+                self.set_region(region)?;
+                self.shifter.reset();
+            }
+            AudioControls::Slices(spec) => {
+                self.slices = self.slice(spec)?;
+                info!("Sliced into {} slices", self.slices.len());
+            }
+            AudioControls::Slice(slice) => self.trigger(slice)?,
+            AudioControls::Trigger => self.play_region(self.region)?,
+            AudioControls::Choke => self.choke(),
+            AudioControls::Voices(voices) => {
+                if voices == 0 {
+                    return Err(anyhow!("at least 1 voice must be able to play"));
                 }
-                Reader::Mem {
-                    samples: _samples,
-                    cursor: _cursor,
-                } => todo!(),
-            },
+                self.polyphony = voices;
+            }
+            AudioControls::Loop(spec) => self.set_loop(spec)?,
             AudioControls::Pause => {
                 info!("Pausing sample");
-                self.outputting = false;
+                self.pausing = true;
+                self.amp.fade_out();
+                if self.amp.is_silent() {
+                    self.pausing = false;
+                    self.outputting = false;
+                }
             }
             AudioControls::Resume => {
                 info!("Resuming sample");
-                self.outputting = true;
+                self.resume();
             }
             AudioControls::Reverse => {
-                if let Reader::File { .. } = self.reader {
+                self.backward = !self.backward;
+                self.shifter.reset();
+            }
+            AudioControls::Rate(rate) => {
+                if !(rate.is_finite() && rate > 0.) {
                     return Err(anyhow!(
-                        "cannot reverse an opened file, import it to bring it entirely in \
-                            memory so that it can be reversed"
+                        "rate must be positive, not {rate}. `reverse` plays backwards"
                     ));
                 }
-                self.backward = !self.backward;
+                self.shifter.rate = rate;
+            }
+            AudioControls::Pitch(pitch) => self.shifter.pitch = pitch.factor(),
+            AudioControls::Stretch(stretch) => self.shifter.stretch = stretch,
+            AudioControls::Fit(target) => {
+                let end = (self.region.end)
+                    .or(self.frames)
+                    .context("length of the file is unknown")?;
+                let length = end.saturating_sub(self.region.start);
+                let fit = self.frame_of(target)?;
+                if fit == 0 {
+                    return Err(anyhow!("cannot fit the region into no time"));
+                }
+                self.shifter.rate = length as f64 / fit as f64;
+            }
+            AudioControls::Mute => {
+                self.mute = true;
+                self.amp.fade_out();
+            }
+            AudioControls::Unmute => {
+                self.mute = false;
+                if self.outputting && !self.pausing {
+                    self.amp.fade_in(false);
+                }
             }
-            AudioControls::Mute => todo!(),
-            AudioControls::Unmute => todo!(),
+            AudioControls::Gain(decibels) => self.amp.set_gain(decibels)?,
+            AudioControls::Pan(pan) => self.amp.set_pan(pan)?,
+            AudioControls::FadeIn(target) => self.amp.fade_in = self.frame_of(target)?,
+            AudioControls::FadeOut(target) => self.amp.fade_out = self.frame_of(target)?,
+            AudioControls::Note(_) => unreachable!("notes are played before anything else"),
         }
         Ok(())
     }
 
     pub fn package(lua: &mlua::Lua) -> mlua::Result<mlua::Value> {
-        package_instrument::<Sampler, String, AudioControls>(lua, MANUAL.to_string())
+        package_instrument::<Sampler, SamplerArguments, AudioControls>(lua, MANUAL.to_string())
     }
 }
 
@@ -399,22 +1104,171 @@ impl Source for Sampler {
     type Err = anyhow::Error;
 
     fn next_sample(&mut self) -> Result<Option<Sample>, SourceError<anyhow::Error>> {
-        self.next()
+        self.next_frame()
+    }
+
+    fn sample_rate(&self) -> Option<u32> {
+        self.sample_rate
+    }
+
+    fn prepare(&mut self, sample_rate: u32) -> anyhow::Result<()> {
+        // Audio that doesn't tell its rate plays at the one being rendered at
+        if self.sample_rate.is_none() {
+            self.sample_rate = Some(sample_rate);
+            self.shifter.set_sample_rate(sample_rate);
+        }
+        self.voices
+            .iter_mut()
+            .try_for_each(|voice| voice.prepare(sample_rate))?;
+        if let Some(keymap) = &mut self.keymap {
+            keymap
+                .zones
+                .iter_mut()
+                .try_for_each(|zone| zone.prepare(sample_rate))?;
+        }
+        Ok(())
+    }
+
+    fn tempo(&mut self, bpm: f64) {
+        self.bpm = bpm;
+        if let Some(keymap) = &mut self.keymap {
+            keymap.zones.iter_mut().for_each(|zone| zone.tempo(bpm));
+        }
+    }
+
+    fn channels(&self) -> Option<usize> {
+        Some(self.channels)
+    }
+
+    fn fill_buffer(
+        &mut self,
+        buffer: &mut [f32],
+        channels: usize,
+    ) -> Result<usize, SourceError<anyhow::Error>> {
+        // Keymaps play for as long as there may be notes to play
+        if let Some(keymap) = &mut self.keymap {
+            keymap.fill(buffer, channels)?;
+            return Ok(buffer.len() / channels);
+        }
+        let filled = self.fill_playhead(buffer, channels)?;
+        if self.polyphony == 1 && self.voices.is_empty() {
+            return Ok(filled);
+        }
+        // Samplers with voices play on for as long as there may be voices to play
+        buffer[filled * channels..].fill(0.);
+        self.mix_voices(buffer, channels)?;
+        Ok(buffer.len() / channels)
     }
 }
 
+/// What a sampler is made from: the path of a file to `open` or `import`, the zones of a `kit`,
+/// or the `audio` it plays
 #[derive(Deserialize)]
+#[serde(untagged)]
+pub enum SamplerArguments {
+    Path(String),
+    Zones(Vec<ZoneOptions>),
+    /// Mono samples played at the sample rate being rendered at
+    Samples(Vec<f32>),
+    Audio(Audio),
+}
+
+/// Audio in memory, as given to `Sampler.audio` or rendered by `render` to no file
+#[derive(Debug, Deserialize)]
+pub struct Audio {
+    /// Interleaved samples of every frame
+    pub samples: Vec<f32>,
+    #[serde(default = "Audio::mono")]
+    pub channels: usize,
+    /// Sample rate that the samples are at, the one being rendered at if left out
+    pub sample_rate: Option<u32>,
+}
+
+impl Audio {
+    fn mono() -> usize {
+        1
+    }
+}
+
+/// Notes of a `Midi` pattern
+impl From<midi::Note> for AudioControls {
+    fn from(note: midi::Note) -> Self {
+        AudioControls::Note(NoteOn {
+            notes: Notes::One(midi::Pitch::Number(note.number())),
+            velocity: DEFAULT_VELOCITY,
+            channel: 1,
+            duration: None,
+        })
+    }
+}
+
+/// Notes of a MIDI file. Samples play out once triggered, so there's nothing to release, and
+/// there are no presets or controllers to change either
+impl FromMessage for AudioControls {
+    fn from_message(message: Message) -> Option<Self> {
+        match message {
+            Message::NoteOn {
+                channel,
+                key,
+                velocity,
+            } => Some(AudioControls::Note(NoteOn {
+                notes: Notes::One(midi::Pitch::Number(key as i32)),
+                velocity,
+                channel,
+                duration: None,
+            })),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum AudioControls {
-    Seek(String),
+    Seek(SeekTarget),
     Pause,
     Resume,
     Reverse,
     Mute,
     Unmute,
+    /// Play only between a start and a stop point
+    Region(RegionTargets),
+    /// Cut the sampler up into slices
+    Slices(SliceSpec),
+    /// Play a slice, by its number or name
+    Slice(SliceRef),
+    /// Keep playing once the end is reached
+    #[serde(rename = "loop")]
+    Loop(LoopSpec),
+    /// Speed of playback, which also changes the pitch unless stretching
+    Rate(f64),
+    /// Semitones, or semitones and cents, that the pitch is raised by
+    Pitch(Pitch),
+    /// Whether rate changes only the duration and pitch changes only the pitch
+    Stretch(bool),
+    /// Set the rate so that the region lasts as long as a seek target, like `"4 beats"`
+    Fit(SeekTarget),
+    /// Decibels of gain
+    Gain(f64),
+    /// Balance between the left (`-1`) and right (`1`) channels of stereo files
+    Pan(f32),
+    /// How long resuming and unmuting take to fade in
+    #[serde(rename = "fade_in")]
+    FadeIn(SeekTarget),
+    /// How long pausing and muting take to fade out
+    #[serde(rename = "fade_out")]
+    FadeOut(SeekTarget),
+    /// Play the zones of a kit that a note maps to
+    Note(NoteOn),
+    /// Play the region from its start, on a voice of its own if more than one can play
+    Trigger,
+    /// Quickly fade out and pause the playhead and every voice
+    Choke,
+    /// How many voices can play at once
+    Voices(usize),
 }
 
-impl State<String, AudioControls> for Sampler {
+impl State<SamplerArguments, AudioControls> for Sampler {
     type TErr = anyhow::Error;
     type IErr = anyhow::Error;
 
@@ -422,25 +1276,574 @@ impl State<String, AudioControls> for Sampler {
         self.control(event)
     }
 
-    fn initialize(route: &str, arguments: String) -> Result<Self, anyhow::Error> {
-        match route {
-            "open" => Self::load(&arguments, false)
-                .context(format!("{ERR_PREFIX} while opening `{arguments}`")),
-            "import" => Self::load(&arguments, true)
-                .context(format!("{ERR_PREFIX} while importing `{arguments}`")),
+    fn initialize(route: &str, arguments: SamplerArguments) -> Result<Self, anyhow::Error> {
+        match (route, arguments) {
+            ("open", SamplerArguments::Path(path)) => {
+                Self::load(&path, false).context(format!("{ERR_PREFIX} while opening `{path}`"))
+            }
+            ("import", SamplerArguments::Path(path)) => {
+                Self::load(&path, true).context(format!("{ERR_PREFIX} while importing `{path}`"))
+            }
+            ("kit", SamplerArguments::Zones(zones)) => {
+                Self::kit(zones).context(format!("{ERR_PREFIX} while making a kit"))
+            }
+            ("audio", SamplerArguments::Samples(samples)) => Self::audio(Audio {
+                samples,
+                channels: 1,
+                sample_rate: None,
+            })
+            .context(format!("{ERR_PREFIX} while reading audio")),
+            ("audio", SamplerArguments::Audio(audio)) => {
+                Self::audio(audio).context(format!("{ERR_PREFIX} while reading audio"))
+            }
+            ("open" | "import" | "kit" | "audio", _) => Err(anyhow!(
+                "{ERR_PREFIX} `open` and `import` take the path of a file, `kit` a list of zones \
+                    like `{{ file = <path>, keys = {{ <low>, <high> }} }}`, and `audio` a list of \
+                    samples or `{{ samples = {{ .. }}, channels = <n>, sample_rate = <hz> }}`, \
+                    like `render` returns when given no file"
+            )),
             _ => Err(anyhow!(
-                "{ERR_PREFIX} invalid route `{route}`. available: `open` and `import`"
+                "{ERR_PREFIX} invalid route `{route}`. available: `open`, `import`, `kit` and \
+                    `audio`"
             )),
         }
     }
 }
 
-impl Instrument<String, AudioControls> for Sampler {
+impl Instrument<SamplerArguments, AudioControls> for Sampler {
     fn help(&self) -> String {
+        if let Some(keymap) = &self.keymap {
+            return format!(
+                "<|SAMPLER|> A kit of {} files played by notes\n\
+                Events: `{{ note = <note> | {{ <note>, .. }} }}` or \
+                `{{ note = {{ notes, velocity = <0..127> }} }}`, the same as a synth's `on`, and \
+                any other sampler event for every file at once. Files play out once triggered, \
+                so channels and durations are ignored",
+                keymap.zones.len()
+            );
+        }
         format!(
             "<|SAMPLER|> An instrument for Plunder that can read & manipulate digital audio\n\
+            Events: `pause`, `resume`, `reverse` and `{{ seek = <frame> | \"1.5s\" | \"2 beats\" | \"50%\" }}`\n\
+            Regions & slices: `{{ region = {{ start = <seek>, stop = <seek> }} }}`, \
+            `{{ slices = {{ divide = <n> }} | {{ transients = {{}} }} | {{ {{ name, start, stop }}, .. }} }}` \
+            and `{{ slice = <n> | \"<name>\" }}`\n\
+            Looping: `{{ loop = \"off\" | \"forward\" | \"pingpong\" | \"points\" }}` or \
+            `{{ loop = {{ mode, start = <seek>, stop = <seek>, crossfade = <seek> }} }}`\n\
+            Speed: `{{ rate = <n> }}`, `{{ pitch = <semitones> | {{ semitones, cents }} }}`, \
+            `{{ stretch = true | false }}` and `{{ fit = \"4 beats\" }}`\n\
+            Level: `mute`, `unmute`, `{{ gain = <dB> }}`, `{{ pan = <-1..1> }}`, \
+            `{{ fade_in = <seek> }}` and `{{ fade_out = <seek> }}`\n\
+            Voices: `{{ voices = <n> }}` so that `trigger` and slices play over each other, and \
+            `choke`\n\
             This sampler contains `{}`",
             self.path.display()
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        str::FromStr,
+        time::{Duration, SystemTime},
+    };
+
+    use crate::slice::SliceTargets;
+
+    fn one(read_entire: bool) -> Sampler {
+        let mut sampler = Sampler::load(
+            concat!(env!("CARGO_MANIFEST_DIR"), "/../../one.wav"),
+            read_entire,
+        )
+        .unwrap();
+        sampler.control(AudioControls::Resume).unwrap();
+        sampler
+    }
+
+    /// Every frame of the sampler, filled `block` frames at a time
+    fn frames(mut sampler: Sampler, block: usize) -> Vec<f32> {
+        let channels = sampler.channels().unwrap();
+        let mut out = Vec::new();
+        let mut buffer = vec![0.; block * channels];
+        loop {
+            let filled = sampler.fill_buffer(&mut buffer, channels).unwrap();
+            out.extend_from_slice(&buffer[..filled * channels]);
+            if filled < block {
+                return out;
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_match_samples() {
+        let mut sampler = one(true);
+        let mut samples = Vec::new();
+        while let Some(sample) = sampler.next_sample().unwrap() {
+            samples.extend(sample.to_f32().unwrap());
+        }
+        assert!(!samples.is_empty());
+        assert_eq!(samples, frames(one(true), 1));
+        assert_eq!(samples, frames(one(true), 1000));
+        assert_eq!(samples, frames(one(false), 333));
+    }
+
+    #[test]
+    fn reversed_blocks() {
+        let forward = frames(one(true), 512);
+        // Opened files decode backward a block at a time, far more frames than are read at once
+        for (read_entire, block) in [(true, 100), (false, 100), (false, 20000)] {
+            let mut sampler = one(read_entire);
+            let channels = sampler.channels().unwrap();
+            sampler.fill_buffer(&mut forward.clone(), channels).unwrap();
+            sampler.control(AudioControls::Reverse).unwrap();
+            let backward = frames(sampler, block);
+            assert_eq!(
+                backward
+                    .chunks(channels)
+                    .rev()
+                    .flatten()
+                    .collect::<Vec<_>>(),
+                forward.iter().collect::<Vec<_>>(),
+                "read_entire: {read_entire}"
+            );
+        }
+    }
+
+    #[test]
+    fn seeks_land_on_the_same_frame() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        for (target, frame) in [
+            (SeekTarget::Frame(1000), 1000),
+            (SeekTarget::Text("0s".to_string()), 0),
+            (SeekTarget::Text("10ms".to_string()), 441),
+            (
+                SeekTarget::Text("50%".to_string()),
+                all.len() / channels / 2,
+            ),
+        ] {
+            for read_entire in [true, false] {
+                let mut sampler = one(read_entire);
+                sampler
+                    .fill_buffer(&mut vec![0.; 300 * channels], channels)
+                    .unwrap();
+                sampler
+                    .control(AudioControls::Seek(target.clone()))
+                    .unwrap();
+                assert_eq!(
+                    frames(sampler, 512),
+                    all[frame * channels..],
+                    "seeking to {target:?} with read_entire: {read_entire}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn regions_stop_early() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        for read_entire in [true, false] {
+            let mut sampler = one(read_entire);
+            sampler
+                .control(AudioControls::Region(RegionTargets {
+                    start: Some(SeekTarget::Frame(1000)),
+                    stop: Some(SeekTarget::Frame(3000)),
+                }))
+                .unwrap();
+            assert_eq!(
+                frames(sampler, 512),
+                all[1000 * channels..3000 * channels],
+                "read_entire: {read_entire}"
+            );
+        }
+    }
+
+    #[test]
+    fn slices_trigger_by_number_and_name() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        let total = all.len() / channels;
+
+        let mut sampler = one(true);
+        sampler
+            .control(AudioControls::Slices(SliceSpec::Divide { divide: 4 }))
+            .unwrap();
+        sampler
+            .control(AudioControls::Slice(SliceRef::Index(3)))
+            .unwrap();
+        assert_eq!(
+            frames(sampler, 512),
+            all[total / 2 * channels..total * 3 / 4 * channels]
+        );
+
+        let mut sampler = one(false);
+        sampler
+            .control(AudioControls::Slices(SliceSpec::List(vec![
+                SliceTargets {
+                    name: Some("kick".to_string()),
+                    start: SeekTarget::Frame(0),
+                    stop: None,
+                },
+                SliceTargets {
+                    name: Some("snare".to_string()),
+                    start: SeekTarget::Frame(2000),
+                    stop: Some(SeekTarget::Frame(2500)),
+                },
+            ])))
+            .unwrap();
+        sampler
+            .control(AudioControls::Slice(SliceRef::Name("kick".to_string())))
+            .unwrap();
+        assert_eq!(frames(sampler, 300), all[..2000 * channels]);
+    }
+
+    /// The first `frames` frames the sampler plays, filled `block` frames at a time
+    fn take(mut sampler: Sampler, frames: usize, block: usize) -> Vec<f32> {
+        let channels = sampler.channels().unwrap();
+        let mut out = vec![0.; frames * channels];
+        for buffer in out.chunks_mut(block * channels) {
+            let filled = sampler.fill_buffer(buffer, channels).unwrap();
+            assert_eq!(filled * channels, buffer.len());
+        }
+        out
+    }
+
+    #[test]
+    fn loops_forward_and_between_points() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        let region = &all[1000 * channels..1500 * channels];
+        for read_entire in [true, false] {
+            let mut sampler = one(read_entire);
+            sampler
+                .control(AudioControls::Region(RegionTargets {
+                    start: Some(SeekTarget::Frame(1000)),
+                    stop: Some(SeekTarget::Frame(1500)),
+                }))
+                .unwrap();
+            sampler
+                .control(AudioControls::Loop(LoopSpec::Mode(LoopMode::Forward)))
+                .unwrap();
+            assert_eq!(
+                take(sampler, 1200, 333),
+                region.repeat(3)[..1200 * channels]
+            );
+        }
+
+        // Played into, then looped between the points
+        let mut sampler = one(true);
+        sampler
+            .control(AudioControls::Loop(LoopSpec::Options {
+                mode: LoopMode::Points,
+                start: Some(SeekTarget::Frame(1000)),
+                stop: Some(SeekTarget::Frame(1500)),
+                crossfade: None,
+            }))
+            .unwrap();
+        let expected = [&all[..1500 * channels], region, region].concat();
+        assert_eq!(take(sampler, 2500, 256), expected);
+    }
+
+    #[test]
+    fn ping_pongs() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        let forward = &all[200 * channels..300 * channels];
+        let backward: Vec<f32> = forward.chunks(channels).rev().flatten().copied().collect();
+        for read_entire in [true, false] {
+            let mut sampler = one(read_entire);
+            sampler
+                .control(AudioControls::Region(RegionTargets {
+                    start: Some(SeekTarget::Frame(200)),
+                    stop: Some(SeekTarget::Frame(300)),
+                }))
+                .unwrap();
+            sampler
+                .control(AudioControls::Loop(LoopSpec::Mode(LoopMode::PingPong)))
+                .unwrap();
+            assert_eq!(
+                take(sampler, 300, 64),
+                [forward, &backward, forward].concat(),
+                "read_entire: {read_entire}"
+            );
+        }
+    }
+
+    #[test]
+    fn crossfades_into_the_loop() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        let mut sampler = one(true);
+        sampler
+            .control(AudioControls::Loop(LoopSpec::Options {
+                mode: LoopMode::Points,
+                start: Some(SeekTarget::Frame(1000)),
+                stop: Some(SeekTarget::Frame(1500)),
+                crossfade: Some(SeekTarget::Frame(100)),
+            }))
+            .unwrap();
+        let played = take(sampler, 2000, 128);
+        let frame =
+            |samples: &[f32], at: usize| samples[at * channels..(at + 1) * channels].to_vec();
+        // Untouched before the fade, fading into what's before the loop start up to the seam
+        assert_eq!(frame(&played, 1399), frame(&all, 1399));
+        let blended: Vec<f32> = frame(&all, 1499)
+            .iter()
+            .zip(frame(&all, 999))
+            .map(|(s, o)| s * 0.01 + o * 0.99)
+            .collect();
+        assert_eq!(frame(&played, 1499), blended);
+        assert_eq!(frame(&played, 1500), frame(&all, 1000));
+    }
+
+    #[test]
+    fn rate_and_pitch_speed_up_together() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        let every_other: Vec<f32> = all.chunks(channels).step_by(2).flatten().copied().collect();
+        for (control, read_entire) in [
+            (AudioControls::Rate(2.), true),
+            (AudioControls::Pitch(Pitch::Semitones(12.)), false),
+        ] {
+            let mut sampler = one(read_entire);
+            sampler.control(control).unwrap();
+            assert_eq!(frames(sampler, 300), every_other);
+        }
+    }
+
+    #[test]
+    fn fits_the_region_to_beats() {
+        let mut sampler = one(true);
+        let frames = sampler.frames.unwrap();
+        sampler.tempo(60.);
+        sampler
+            .control(AudioControls::Fit(SeekTarget::Text("1 beat".to_string())))
+            .unwrap();
+        // A beat at 60bpm is a second
+        let rate = frames as f64 / sampler.sample_rate.unwrap() as f64;
+        assert!((sampler.shifter.rate - rate).abs() < 1e-9);
+        assert!(sampler.control(AudioControls::Rate(-1.)).is_err());
+    }
+
+    #[test]
+    fn pauses_and_mutes_without_clicking() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        let mut sampler = one(true);
+        sampler
+            .control(AudioControls::FadeOut(SeekTarget::Frame(100)))
+            .unwrap();
+        sampler.control(AudioControls::Pause).unwrap();
+        let faded = take(sampler, 200, 64);
+        faded[..100 * channels]
+            .chunks(channels)
+            .zip(all.chunks(channels))
+            .enumerate()
+            .for_each(|(i, (faded, frame))| {
+                let level = 1. - (i + 1) as f32 / 100.;
+                faded
+                    .iter()
+                    .zip(frame)
+                    .for_each(|(f, s)| assert!((f - s * level).abs() < 1e-6));
+            });
+        assert!(faded[100 * channels..].iter().all(|s| *s == 0.));
+
+        // Muted samplers keep playing, silently
+        let mut sampler = one(false);
+        sampler.control(AudioControls::Mute).unwrap();
+        let channels = sampler.channels;
+        let mut muted = vec![0.; 300 * channels];
+        sampler.fill_buffer(&mut muted, channels).unwrap();
+        assert!(muted.iter().all(|s| *s == 0.));
+        sampler.control(AudioControls::Unmute).unwrap();
+        assert_eq!(take(sampler, 300, 100), all[300 * channels..600 * channels]);
+
+        // Paused and muted samplers are silent a frame at a time too, not out of samples
+        for control in [AudioControls::Pause, AudioControls::Mute] {
+            let mut sampler = one(true);
+            sampler.control(control).unwrap();
+            let silent: Vec<f32> = (0..1000)
+                .flat_map(|_| match sampler.next_sample().unwrap() {
+                    Some(Sample::F32(frame)) => frame,
+                    sample => panic!("expected a frame of silence, not {sample:?}"),
+                })
+                .collect();
+            assert!(silent.iter().all(|s| *s == 0.));
+        }
+    }
+
+    #[test]
+    fn kits_play_notes() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        let zone = |keys, root| ZoneOptions {
+            file: concat!(env!("CARGO_MANIFEST_DIR"), "/../../one.wav").to_string(),
+            keys: Some(keys),
+            velocity: None,
+            root,
+            group: None,
+            choke: None,
+        };
+        let mut kit = Sampler::kit(vec![
+            zone(keymap::Keys::One(midi::Pitch::Number(36)), None),
+            zone(
+                keymap::Keys::Range(midi::Pitch::Number(40), midi::Pitch::Name("C5".to_string())),
+                Some(midi::Pitch::Number(40)),
+            ),
+        ])
+        .unwrap();
+        assert_eq!(kit.channels, channels);
+        // Silent until a note plays
+        assert!(take_kit(&mut kit, 100).iter().all(|s| *s == 0.));
+        kit.control(AudioControls::from(midi::Note::from_str("C3").unwrap()))
+            .unwrap();
+        assert_eq!(take_kit(&mut kit, 1000), all[..1000 * channels]);
+
+        // An octave above the root plays twice as fast
+        let mut kit = Sampler::kit(vec![zone(
+            keymap::Keys::One(midi::Pitch::Number(52)),
+            Some(midi::Pitch::Number(40)),
+        )])
+        .unwrap();
+        kit.control(AudioControls::Note(NoteOn {
+            notes: Notes::One(midi::Pitch::Number(52)),
+            velocity: 10,
+            channel: 1,
+            duration: None,
+        }))
+        .unwrap();
+        let every_other: Vec<f32> = all.chunks(channels).step_by(2).flatten().copied().collect();
+        assert_eq!(take_kit(&mut kit, 1000), every_other[..1000 * channels]);
+    }
+
+    #[test]
+    fn voices_overlap_steal_and_choke() {
+        let all = frames(one(true), 4096);
+        let channels = one(true).channels;
+        let mut sampler = one(true);
+        sampler.control(AudioControls::Voices(2)).unwrap();
+        sampler.control(AudioControls::Pause).unwrap();
+        sampler.control(AudioControls::Trigger).unwrap();
+        assert_eq!(take_kit(&mut sampler, 100), all[..100 * channels]);
+        sampler.control(AudioControls::Trigger).unwrap();
+        let overlapped: Vec<f32> = all[100 * channels..200 * channels]
+            .iter()
+            .zip(&all[..100 * channels])
+            .map(|(a, b)| a + b)
+            .collect();
+        assert_eq!(take_kit(&mut sampler, 100), overlapped);
+
+        // A third voice cuts off the oldest
+        sampler.control(AudioControls::Trigger).unwrap();
+        take_kit(&mut sampler, 1000);
+        assert_eq!(sampler.voices.len(), 2);
+        sampler.control(AudioControls::Choke).unwrap();
+        take_kit(&mut sampler, 1000);
+        assert!(sampler.voices.is_empty());
+        assert!(take_kit(&mut sampler, 100).iter().all(|s| *s == 0.));
+
+        let mut sampler = one(false);
+        sampler.control(AudioControls::Voices(4)).unwrap();
+        assert!(sampler.control(AudioControls::Trigger).is_err());
+        assert!(sampler.control(AudioControls::Voices(0)).is_err());
+    }
+
+    #[test]
+    fn imports_share_decoded_samples() {
+        let _exclusive = cache::tests::exclusive();
+        let samples = |sampler: &Sampler| match &sampler.reader {
+            Reader::Mem { samples, .. } => samples.clone(),
+            Reader::File(_) => unreachable!("imported"),
+        };
+        let path = std::env::temp_dir().join("plunder-cached.wav");
+        std::fs::copy(concat!(env!("CARGO_MANIFEST_DIR"), "/../../one.wav"), &path).unwrap();
+        let first = Sampler::load(&path, true).unwrap();
+        let second = Sampler::load(&path, true).unwrap();
+        assert!(Arc::ptr_eq(&samples(&first), &samples(&second)));
+        let cached = cache::list();
+        let entry = cached
+            .iter()
+            .find(|cached| cached.path == path.canonicalize().unwrap())
+            .unwrap();
+        assert!(entry.samplers >= 2);
+
+        // Changed files are decoded anew
+        let modified = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        let changed = Sampler::load(&path, true).unwrap();
+        assert!(!Arc::ptr_eq(&samples(&first), &samples(&changed)));
+        assert_eq!(samples(&first), samples(&changed));
+        assert!(cache::clear() > 0);
+        let cleared = Sampler::load(&path, true).unwrap();
+        assert!(!Arc::ptr_eq(&samples(&changed), &samples(&cleared)));
+    }
+
+    #[test]
+    fn plays_audio_from_memory() {
+        let ramp: Vec<f32> = (0..2000).map(|i| i as f32 / 2000.).collect();
+        let mut sampler = Sampler::audio(Audio {
+            samples: ramp.clone(),
+            channels: 2,
+            sample_rate: Some(48000),
+        })
+        .unwrap();
+        sampler.control(AudioControls::Resume).unwrap();
+        sampler
+            .control(AudioControls::Seek(SeekTarget::Text("10ms".to_string())))
+            .unwrap();
+        assert_eq!(frames(sampler, 64), ramp[480 * 2..]);
+        assert!(Sampler::audio(Audio {
+            samples: ramp,
+            channels: 3,
+            sample_rate: None,
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn audio_without_a_rate_seeks_and_loops() {
+        let ramp: Vec<f32> = (0..1000).map(|i| i as f32 / 1000.).collect();
+        let mut sampler = Sampler::audio(Audio {
+            samples: ramp.clone(),
+            channels: 1,
+            sample_rate: None,
+        })
+        .unwrap();
+        sampler.control(AudioControls::Resume).unwrap();
+        sampler
+            .control(AudioControls::Seek(SeekTarget::Frame(100)))
+            .unwrap();
+        sampler
+            .control(AudioControls::Loop(LoopSpec::Options {
+                mode: LoopMode::Points,
+                start: Some(SeekTarget::Text("20%".to_string())),
+                stop: Some(SeekTarget::Frame(300)),
+                crossfade: None,
+            }))
+            .unwrap();
+        // Seeks in time wait for the rate being rendered at
+        let seconds = || AudioControls::Seek(SeekTarget::Text("1ms".to_string()));
+        assert!(sampler.control(seconds()).is_err());
+
+        sampler.prepare(48000).unwrap();
+        assert_eq!(sampler.sample_rate(), Some(48000));
+        sampler.control(seconds()).unwrap();
+        let expected = [&ramp[48..300], &ramp[200..300], &ramp[200..248]].concat();
+        assert_eq!(take(sampler, 400, 64), expected);
+    }
+
+    fn take_kit(kit: &mut Sampler, frames: usize) -> Vec<f32> {
+        let channels = kit.channels;
+        let mut buffer = vec![0.; frames * channels];
+        assert_eq!(kit.fill_buffer(&mut buffer, channels).unwrap(), frames);
+        buffer
+    }
+}
@@ -0,0 +1,139 @@
+use std::io::{self, Read, SeekFrom};
+
+use serde::Deserialize;
+
+use crate::{seek::SeekTarget, slice::Region};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoopMode {
+    /// Stop at the end of the region
+    #[default]
+    Off,
+    /// Jump back to the start of the region at its end
+    Forward,
+    /// Turn around at either end of the region
+    PingPong,
+    /// Play into the loop points and then keep looping between them
+    Points,
+}
+
+/// Options of a `loop` event: just a mode, or a mode with loop points and a crossfade
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum LoopSpec {
+    Mode(LoopMode),
+    Options {
+        mode: LoopMode,
+        /// Loop points, defaulting to those embedded in the file
+        start: Option<SeekTarget>,
+        stop: Option<SeekTarget>,
+        /// Length of the crossfade at the seam of a forward loop
+        crossfade: Option<SeekTarget>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Looping {
+    pub mode: LoopMode,
+    /// Where `LoopMode::Points` loops between
+    pub points: Option<Region>,
+    /// Frames crossfaded at the seam
+    pub crossfade: u64,
+}
+
+/// Loop points of the first loop in the `smpl` chunk of a WAV file, if it has one
+pub fn smpl_loop<R: Read + io::Seek>(mut wav: R) -> io::Result<Option<Region>> {
+    let mut header = [0; 12];
+    wav.read_exact(&mut header)?;
+    if &header[..4] != b"RIFF" || &header[8..] != b"WAVE" {
+        return Ok(None);
+    }
+    let mut chunk = [0; 8];
+    loop {
+        match wav.read_exact(&mut chunk) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let size = u32::from_le_bytes(chunk[4..].try_into().unwrap());
+        if &chunk[..4] != b"smpl" {
+            // Chunks are padded to an even length
+            wav.seek(SeekFrom::Current(size as i64 + (size & 1) as i64))?;
+            continue;
+        }
+        let mut smpl = vec![0; size as usize];
+        wav.read_exact(&mut smpl)?;
+        let word = |at: usize| {
+            smpl.get(at..at + 4)
+                .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        };
+        // The loop count follows 7 words of header, and each loop holds its cue-point id and
+        // type before its start and its inclusive end
+        return Ok(match (word(28), word(36 + 8), word(36 + 12)) {
+            (Some(1..), Some(start), Some(end)) if end >= start => Some(Region {
+                start: start as u64,
+                end: Some(end as u64 + 1),
+            }),
+            _ => None,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn wav(chunks: &[(&[u8; 4], Vec<u8>)]) -> Cursor<Vec<u8>> {
+        let mut body = b"WAVE".to_vec();
+        for (id, data) in chunks {
+            body.extend_from_slice(*id);
+            body.extend((data.len() as u32).to_le_bytes());
+            body.extend(data);
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        let mut file = b"RIFF".to_vec();
+        file.extend((body.len() as u32).to_le_bytes());
+        file.extend(body);
+        Cursor::new(file)
+    }
+
+    fn smpl(loops: &[(u32, u32)]) -> Vec<u8> {
+        let mut smpl = vec![0; 28];
+        smpl.extend((loops.len() as u32).to_le_bytes());
+        smpl.extend([0; 4]);
+        for (start, end) in loops {
+            [0, 0, *start, *end, 0, 0]
+                .iter()
+                .for_each(|word: &u32| smpl.extend(word.to_le_bytes()));
+        }
+        smpl
+    }
+
+    #[test]
+    fn reads_the_first_loop() {
+        let file = wav(&[
+            (b"fmt ", vec![0; 16]),
+            (b"junk", vec![0; 3]),
+            (b"smpl", smpl(&[(100, 199), (300, 400)])),
+            (b"data", vec![0; 8]),
+        ]);
+        assert_eq!(
+            smpl_loop(file).unwrap(),
+            Some(Region {
+                start: 100,
+                end: Some(200)
+            })
+        );
+    }
+
+    #[test]
+    fn no_loops() {
+        assert_eq!(smpl_loop(wav(&[(b"data", vec![0; 8])])).unwrap(), None);
+        assert_eq!(smpl_loop(wav(&[(b"smpl", smpl(&[]))])).unwrap(), None);
+        assert_eq!(smpl_loop(Cursor::new(b"fLaC".repeat(4))).unwrap(), None);
+    }
+}
@@ -0,0 +1,333 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use serde::Deserialize;
+
+/// Seconds that the grains overlapped when time-stretching last
+const GRAIN: f64 = 0.05;
+/// Sample rate that grains are timed at for files with an unknown one
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+/// Pitch as given in a `pitch` event, in semitones or in semitones and cents
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(untagged)]
+pub enum Pitch {
+    Semitones(f64),
+    Detune {
+        #[serde(default)]
+        semitones: f64,
+        #[serde(default)]
+        cents: f64,
+    },
+}
+
+impl Pitch {
+    /// Factor that frequencies are multiplied by
+    pub fn factor(self) -> f64 {
+        let semitones = match self {
+            Pitch::Semitones(semitones) => semitones,
+            Pitch::Detune { semitones, cents } => semitones + cents / 100.,
+        };
+        2f64.powf(semitones / 12.)
+    }
+}
+
+/// Cubic Hermite interpolation at `x` between `y1` and `y2`
+fn hermite(x: f32, [y0, y1, y2, y3]: [f32; 4]) -> f32 {
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2. * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+    ((c3 * x + c2) * x + c1) * x + y1
+}
+
+/// Plays a stream of frames at a different rate or pitch, by interpolating between them or, when
+/// time-stretching, by overlapping windowed grains of them
+#[derive(Debug)]
+pub struct Shifter {
+    channels: usize,
+    /// Frames that a grain lasts
+    grain: usize,
+    /// Frames read per frame played, over time
+    pub rate: f64,
+    /// Factor that the pitch is raised by
+    pub pitch: f64,
+    /// Whether the rate changes only the duration and the pitch only the pitch, instead of both
+    /// changing the speed
+    pub stretch: bool,
+    /// Interleaved frames read but not yet played past
+    input: VecDeque<f32>,
+    /// Position in `input` of the next frame played, or of the next grain
+    phase: f64,
+    /// Overlap-added grains, the first `ready` frames of which no more grains overlap
+    output: VecDeque<f32>,
+    ready: usize,
+    /// Whether reading has run out
+    exhausted: bool,
+    scratch: Vec<f32>,
+}
+
+/// Frames that a grain lasts at `sample_rate`
+fn grain(sample_rate: Option<u32>) -> usize {
+    let grain = (GRAIN * sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE) as f64) as usize;
+    // Grains overlap by half, so they last an even number of frames
+    grain.max(2) & !1
+}
+
+impl Shifter {
+    pub fn new(channels: usize, sample_rate: Option<u32>) -> Self {
+        Shifter {
+            channels,
+            grain: grain(sample_rate),
+            rate: 1.,
+            pitch: 1.,
+            stretch: false,
+            input: VecDeque::new(),
+            phase: 0.,
+            output: VecDeque::new(),
+            ready: 0,
+            exhausted: false,
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Size grains for frames at `sample_rate`, forgetting every frame read
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.grain = grain(Some(sample_rate));
+        self.reset();
+    }
+
+    /// Whether frames can be read as they are, without going through the shifter, which is once
+    /// every frame it read has been played
+    pub fn is_idle(&self) -> bool {
+        !self.stretch
+            && self.rate == 1.
+            && self.pitch == 1.
+            && self.output.is_empty()
+            && self.input.len() / self.channels.max(1) <= self.phase as usize
+    }
+
+    /// Forget every frame read, for when reading jumps elsewhere
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.output.clear();
+        self.phase = 0.;
+        self.ready = 0;
+        self.exhausted = false;
+    }
+
+    /// Read with `read` until `input` holds `frames` frames or there's nothing left to read
+    fn pull<E, R>(&mut self, frames: usize, read: &mut R) -> Result<(), E>
+    where
+        R: FnMut(&mut [f32]) -> Result<usize, E>,
+    {
+        let missing = frames.saturating_sub(self.input.len() / self.channels);
+        if self.exhausted || missing == 0 {
+            return Ok(());
+        }
+        self.scratch.resize(missing * self.channels, 0.);
+        let read = read(&mut self.scratch)?;
+        self.input.extend(&self.scratch[..read * self.channels]);
+        self.exhausted = read < missing;
+        Ok(())
+    }
+
+    /// Channel `channel` of frame `index` of `input`, the first frame before it and silence after
+    fn at(&self, index: isize, channel: usize) -> f32 {
+        self.input
+            .get(index.max(0) as usize * self.channels + channel)
+            .copied()
+            .unwrap_or(0.)
+    }
+
+    /// Channel `channel` of `input` interpolated at `position`
+    fn interpolate(&self, position: f64, channel: usize) -> f32 {
+        let (index, x) = (position.floor() as isize, position.fract() as f32);
+        if x == 0. {
+            return self.at(index, channel);
+        }
+        hermite(
+            x,
+            [-1, 0, 1, 2].map(|offset| self.at(index + offset, channel)),
+        )
+    }
+
+    /// Drop the frames of `input` before `phase`, keeping the one just before it to interpolate
+    fn advance(&mut self) {
+        while self.phase >= 2. && !self.input.is_empty() {
+            self.input.drain(..self.channels);
+            self.phase -= 1.;
+        }
+    }
+
+    /// Fill `buffer` with frames read with `read`, returning the number of frames filled. Fills
+    /// less than the whole buffer only once there's nothing left to read
+    pub fn fill<E, R>(&mut self, buffer: &mut [f32], mut read: R) -> Result<usize, E>
+    where
+        R: FnMut(&mut [f32]) -> Result<usize, E>,
+    {
+        let channels = self.channels;
+        for (filled, frame) in buffer.chunks_exact_mut(channels).enumerate() {
+            if self.stretch {
+                if self.ready == 0 && !self.overlap(&mut read)? {
+                    return Ok(filled);
+                }
+                frame
+                    .iter_mut()
+                    .zip(self.output.drain(..channels))
+                    .for_each(|(f, s)| *f = s);
+                self.ready -= 1;
+                continue;
+            }
+
+            let index = self.phase.floor() as usize;
+            // Frames played as they are need no neighbours to interpolate between
+            let frames = if self.phase.fract() == 0. { 1 } else { 3 };
+            self.pull(index + frames, &mut read)?;
+            if index >= self.input.len() / channels {
+                return Ok(filled);
+            }
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                *sample = self.interpolate(self.phase, channel);
+            }
+            self.phase += self.rate * self.pitch;
+            self.advance();
+        }
+        Ok(buffer.len() / channels)
+    }
+
+    /// Overlap-add the next grain, returning `false` once there's nothing left to play
+    fn overlap<E, R>(&mut self, read: &mut R) -> Result<bool, E>
+    where
+        R: FnMut(&mut [f32]) -> Result<usize, E>,
+    {
+        let (channels, grain) = (self.channels, self.grain);
+        let index = self.phase.floor() as usize;
+        self.pull(
+            index + (grain as f64 * self.pitch).ceil() as usize + 3,
+            read,
+        )?;
+        if index >= self.input.len() / channels {
+            // The tail of the last grain fades out
+            self.ready = self.output.len() / channels;
+            return Ok(self.ready > 0);
+        }
+
+        self.output.resize(grain * channels, 0.);
+        for j in 0..grain {
+            // Hann windows overlapping by half add up to 1
+            let window = 0.5 - 0.5 * (2. * PI * j as f64 / grain as f64).cos();
+            let position = self.phase + j as f64 * self.pitch;
+            for channel in 0..channels {
+                self.output[j * channels + channel] +=
+                    window as f32 * self.interpolate(position, channel);
+            }
+        }
+        self.ready = grain / 2;
+        self.phase += (grain / 2) as f64 * self.rate;
+        self.advance();
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every frame of mono `input` through `shifter`
+    fn shift(mut shifter: Shifter, input: &[f32]) -> Vec<f32> {
+        let mut input = input.iter();
+        let mut output = vec![0.; 64];
+        let mut shifted = Vec::new();
+        loop {
+            let filled = shifter
+                .fill(&mut output, |buffer| {
+                    Ok::<_, ()>(
+                        buffer
+                            .iter_mut()
+                            .zip(&mut input)
+                            .map(|(b, i)| *b = *i)
+                            .count(),
+                    )
+                })
+                .unwrap();
+            shifted.extend_from_slice(&output[..filled]);
+            if filled < output.len() {
+                return shifted;
+            }
+        }
+    }
+
+    #[test]
+    fn varispeed_interpolates() {
+        let ramp: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        let mut shifter = Shifter::new(1, None);
+        shifter.rate = 2.;
+        let even: Vec<f32> = (0..50).map(|i| 2. * i as f32).collect();
+        assert_eq!(shift(shifter, &ramp), even);
+
+        // An octave down plays twice as long, and a ramp interpolates to the same ramp
+        let mut shifter = Shifter::new(1, None);
+        shifter.pitch = Pitch::Semitones(-12.).factor();
+        let halves = shift(shifter, &ramp);
+        assert_eq!(halves.len(), 200);
+        halves[2..196]
+            .iter()
+            .enumerate()
+            .for_each(|(i, s)| assert!((s - (i + 2) as f32 / 2.).abs() < 1e-4, "{s}"));
+    }
+
+    #[test]
+    fn unity_passes_through() {
+        let noise: Vec<f32> = (0..1000)
+            .map(|i| ((i * 7919) % 113) as f32 / 113.)
+            .collect();
+        let mut shifter = Shifter::new(1, None);
+        assert_eq!(shift(Shifter::new(1, None), &noise), noise);
+        // Back to idle once the frames played at another rate have been played past
+        shifter.rate = 1.5;
+        shifter
+            .fill(&mut [0.; 10], |b| Ok::<_, ()>(b.len()))
+            .unwrap();
+        shifter.rate = 1.;
+        shifter
+            .fill(&mut [0.; 10], |b| Ok::<_, ()>(b.len()))
+            .unwrap();
+        assert!(shifter.is_idle());
+    }
+
+    #[test]
+    fn stretches_without_changing_pitch() {
+        let tone = |frames: usize, frequency: f64| -> Vec<f32> {
+            (0..frames)
+                .map(|i| (2. * PI * frequency * i as f64 / 44100.).sin() as f32 * 0.5)
+                .collect()
+        };
+        let crossings = |samples: &[f32]| {
+            samples
+                .windows(2)
+                .filter(|pair| (pair[0] < 0.) != (pair[1] < 0.))
+                .count()
+        };
+        let input = tone(44100, 441.);
+
+        let mut shifter = Shifter::new(1, Some(44100));
+        shifter.stretch = true;
+        shifter.rate = 0.5;
+        let stretched = shift(shifter, &input);
+        assert!(
+            stretched.len().abs_diff(88200) < 2205,
+            "{}",
+            stretched.len()
+        );
+        // As many zero-crossings a second, so the same pitch
+        let per_second = crossings(&stretched[..88200]) as f64 / 2.;
+        assert!((per_second - 882.).abs() < 882. * 0.05, "{per_second}");
+
+        let mut shifter = Shifter::new(1, Some(44100));
+        shifter.stretch = true;
+        shifter.pitch = Pitch::Semitones(12.).factor();
+        let shifted = shift(shifter, &input);
+        assert!(shifted.len().abs_diff(44100) < 2205, "{}", shifted.len());
+        let per_second = crossings(&shifted[..44100]) as f64;
+        assert!((per_second - 1764.).abs() < 1764. * 0.05, "{per_second}");
+    }
+}
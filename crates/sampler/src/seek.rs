@@ -0,0 +1,147 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+/// Where to seek to, as given in a `seek` event: a frame, or a string like `"1.5s"`, `"2 beats"`
+/// or `"50%"`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SeekTarget {
+    Frame(u64),
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Seek {
+    Frame(u64),
+    Seconds(f64),
+    /// Beats at the tempo being rendered at
+    Beats(f64),
+    /// Percentage of the length of the file
+    Percent(f64),
+}
+
+impl FromStr for Seek {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let number = |n: &str| -> anyhow::Result<f64> {
+            let n: f64 = n
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid seek `{s}`"))?;
+            if n.is_finite() && n >= 0. {
+                Ok(n)
+            } else {
+                Err(anyhow!("seek `{s}` must be positive"))
+            }
+        };
+
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent = number(percent)?;
+            if percent > 100. {
+                return Err(anyhow!("cannot seek beyond 100% in `{s}`"));
+            }
+            return Ok(Seek::Percent(percent));
+        }
+        for suffix in ["beats", "beat", "b"] {
+            if let Some(beats) = s.strip_suffix(suffix) {
+                return Ok(Seek::Beats(number(beats)?));
+            }
+        }
+        if let Ok(frame) = s.parse() {
+            return Ok(Seek::Frame(frame));
+        }
+        let duration =
+            duration_str::parse(s).map_err(|err| anyhow!("error parsing duration: {err}"))?;
+        Ok(Seek::Seconds(duration.as_secs_f64()))
+    }
+}
+
+impl TryFrom<SeekTarget> for Seek {
+    type Error = anyhow::Error;
+
+    fn try_from(target: SeekTarget) -> Result<Self, Self::Error> {
+        match target {
+            SeekTarget::Frame(frame) => Ok(Seek::Frame(frame)),
+            SeekTarget::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl Seek {
+    /// Frame to seek to in a file of `frames` frames at `sample_rate`, while rendering at `bpm`
+    pub fn frame(
+        self,
+        sample_rate: Option<u32>,
+        frames: Option<u64>,
+        bpm: f64,
+    ) -> anyhow::Result<u64> {
+        let rate = || {
+            sample_rate
+                .map(f64::from)
+                .context("sample rate of the file is unknown")
+        };
+        let frame = match self {
+            Seek::Frame(frame) => frame,
+            Seek::Seconds(seconds) => (seconds * rate()?).round() as u64,
+            Seek::Beats(beats) => (beats * 60. / bpm * rate()?).round() as u64,
+            Seek::Percent(percent) => {
+                let frames = frames.context("length of the file is unknown")?;
+                (percent / 100. * frames as f64).round() as u64
+            }
+        };
+        Ok(frames.map_or(frame, |frames| frame.min(frames)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets() {
+        let seek = |s: &str| s.parse::<Seek>().unwrap();
+        assert_eq!(seek("0s"), Seek::Seconds(0.));
+        assert_eq!(seek("1500ms"), Seek::Seconds(1.5));
+        assert_eq!(seek("4410"), Seek::Frame(4410));
+        assert_eq!(seek("2 beats"), Seek::Beats(2.));
+        assert_eq!(seek("0.5b"), Seek::Beats(0.5));
+        assert_eq!(seek("25%"), Seek::Percent(25.));
+        assert!("150%".parse::<Seek>().is_err());
+        assert!("-1b".parse::<Seek>().is_err());
+        assert!("soon".parse::<Seek>().is_err());
+    }
+
+    #[test]
+    fn resolves_frames() {
+        assert_eq!(
+            Seek::Seconds(0.5).frame(Some(44100), None, 120.).unwrap(),
+            22050
+        );
+        // A beat at 120bpm is half a second
+        assert_eq!(
+            Seek::Beats(1.).frame(Some(48000), None, 120.).unwrap(),
+            24000
+        );
+        assert_eq!(
+            Seek::Percent(50.)
+                .frame(Some(44100), Some(1000), 120.)
+                .unwrap(),
+            500
+        );
+        assert!(Seek::Percent(50.).frame(Some(44100), None, 120.).is_err());
+        // Only seeks in time need to know the rate
+        assert_eq!(Seek::Frame(10).frame(None, None, 120.).unwrap(), 10);
+        assert_eq!(Seek::Percent(50.).frame(None, Some(10), 120.).unwrap(), 5);
+        assert!(Seek::Seconds(1.).frame(None, None, 120.).is_err());
+        assert_eq!(
+            Seek::Frame(5000)
+                .frame(Some(44100), Some(1000), 120.)
+                .unwrap(),
+            1000
+        );
+    }
+}
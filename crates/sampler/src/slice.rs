@@ -0,0 +1,179 @@
+use serde::Deserialize;
+
+use crate::seek::SeekTarget;
+
+/// Frames per window that transient detection compares the energy of
+const HOP: usize = 256;
+/// Windows before the current one whose mean energy it's compared against
+const HISTORY: usize = 4;
+
+/// Bounds of the part of a file that plays, in frames
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    /// `None` plays to the end of the file
+    pub end: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slice {
+    pub name: Option<String>,
+    pub region: Region,
+}
+
+/// Start and end points as given in a `region` event, the whole file when left out
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RegionTargets {
+    pub start: Option<SeekTarget>,
+    pub stop: Option<SeekTarget>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SliceTargets {
+    pub name: Option<String>,
+    pub start: SeekTarget,
+    /// Defaults to the start of the next slice, or the end of the file
+    pub stop: Option<SeekTarget>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TransientOptions {
+    /// How many times louder than just before it a window must be to start a slice
+    pub sensitivity: f64,
+    /// Windows quieter than this many decibels below full-scale never start a slice
+    pub floor: f64,
+    /// Seconds that slices are at least apart
+    pub min_gap: f64,
+}
+
+impl Default for TransientOptions {
+    fn default() -> Self {
+        TransientOptions {
+            sensitivity: 4.,
+            floor: -50.,
+            min_gap: 0.05,
+        }
+    }
+}
+
+/// How a `slices` event cuts up a sampler
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SliceSpec {
+    Divide { divide: usize },
+    Transients { transients: TransientOptions },
+    List(Vec<SliceTargets>),
+}
+
+/// Which slice a `slice` event triggers, counting from 1 like Lua does, or by name
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SliceRef {
+    Index(usize),
+    Name(String),
+}
+
+/// `divisions` slices of equal length covering `frames` frames
+pub fn divide(frames: u64, divisions: usize) -> Vec<Slice> {
+    let divisions = divisions as u64;
+    (0..divisions)
+        .map(|i| Slice {
+            name: None,
+            region: Region {
+                start: frames * i / divisions,
+                end: Some(frames * (i + 1) / divisions),
+            },
+        })
+        .collect()
+}
+
+/// Slices starting where the energy of `samples`, frames of `channels` interleaved samples,
+/// jumps up
+pub fn transients(
+    samples: &[f32],
+    channels: usize,
+    sample_rate: u32,
+    options: &TransientOptions,
+) -> Vec<Slice> {
+    let floor = 10f64.powf(options.floor / 10.);
+    let min_gap = (options.min_gap * sample_rate as f64) as u64;
+    let energies: Vec<f64> = samples
+        .chunks(HOP * channels)
+        .map(|window| window.iter().map(|s| (*s as f64).powi(2)).sum::<f64>() / window.len() as f64)
+        .collect();
+
+    let mut starts: Vec<u64> = Vec::new();
+    for (i, energy) in energies.iter().enumerate() {
+        let before = &energies[i.saturating_sub(HISTORY)..i];
+        let mean = before.iter().sum::<f64>() / before.len().max(1) as f64;
+        let start = (i * HOP) as u64;
+        if *energy > floor
+            && *energy > mean * options.sensitivity
+            && starts.last().is_none_or(|last| start - last >= min_gap)
+        {
+            starts.push(start);
+        }
+    }
+
+    let frames = (samples.len() / channels) as u64;
+    starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| Slice {
+            name: None,
+            region: Region {
+                start: *start,
+                end: Some(starts.get(i + 1).copied().unwrap_or(frames)),
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divides_evenly() {
+        let slices = divide(1000, 3);
+        assert_eq!(slices.len(), 3);
+        assert_eq!(
+            slices[0].region,
+            Region {
+                start: 0,
+                end: Some(333)
+            }
+        );
+        assert_eq!(
+            slices[2].region,
+            Region {
+                start: 666,
+                end: Some(1000)
+            }
+        );
+    }
+
+    #[test]
+    fn finds_hits() {
+        // Three decaying hits in stereo, the first right at the start
+        let mut samples = vec![0f32; 44100 * 2];
+        for hit in [0, 10000, 30000] {
+            (0..5000).for_each(|i| {
+                let s = (1. - i as f32 / 5000.) * if i % 2 == 0 { 0.8 } else { -0.8 };
+                samples[(hit + i) * 2] = s;
+                samples[(hit + i) * 2 + 1] = s;
+            });
+        }
+        let slices = transients(&samples, 2, 44100, &TransientOptions::default());
+        let starts: Vec<_> = slices.iter().map(|slice| slice.region.start).collect();
+        // Hits are found within a window of where they are
+        assert_eq!(starts.len(), 3, "{starts:?}");
+        starts
+            .iter()
+            .zip([0, 10000, 30000])
+            .for_each(|(start, hit)| assert!(start.abs_diff(hit) < HOP as u64));
+        assert_eq!(slices[2].region.end, Some(44100));
+    }
+}
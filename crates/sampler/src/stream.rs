@@ -0,0 +1,352 @@
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use anyhow::anyhow;
+use libplunder::prelude::instrument::SourceError;
+use symphonia::core::{
+    codecs::Decoder,
+    formats::{FormatReader, SeekMode, SeekTo},
+};
+
+use crate::decode_next;
+
+/// Seconds of audio decoded ahead of where it's being read
+const READ_AHEAD: f64 = 1.;
+/// Sample rate that reading ahead is timed at for files with an unknown one
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+/// Frames decoded at a time when decoding backward, each block seeked to from the end of the last
+const BACKWARD_BLOCK: u64 = 8192;
+
+/// Where and which way to decode from
+#[derive(Debug, Clone, Copy)]
+struct Request {
+    frame: u64,
+    backward: bool,
+}
+
+/// Decoded blocks and the requests for them, shared between a stream and its decoding thread
+struct State {
+    request: Request,
+    /// Counts requests, so that the decoding thread knows when it's asked to start over
+    generation: u64,
+    /// Interleaved samples of each block decoded, in the order they play, or why it couldn't be
+    blocks: VecDeque<Result<Vec<f32>, SourceError<anyhow::Error>>>,
+    /// Frames held in `blocks`
+    buffered: usize,
+    /// Whether decoding ran out for the latest request
+    done: bool,
+    closed: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Notified whenever blocks are decoded or taken, and whenever the request changes
+    changed: Condvar,
+}
+
+/// A file decoded ahead of where it's read on a thread of its own, forward or backward
+pub struct Stream {
+    shared: Arc<Shared>,
+    decoding: Option<JoinHandle<()>>,
+    channels: usize,
+    /// Interleaved samples of the block being read, in the order they play
+    block: VecDeque<f32>,
+    /// Frame that reading is at. Reading backward reads the frame just before it
+    position: u64,
+    backward: bool,
+}
+
+impl Stream {
+    pub fn new(
+        reader: Box<dyn FormatReader>,
+        decoder: Box<dyn Decoder>,
+        track_id: u32,
+        channels: usize,
+        sample_rate: Option<u32>,
+    ) -> Self {
+        let request = Request {
+            frame: 0,
+            backward: false,
+        };
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                request,
+                generation: 0,
+                blocks: VecDeque::new(),
+                buffered: 0,
+                done: false,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let decoder = Decoding {
+            reader,
+            decoder,
+            track_id,
+            channels,
+            capacity: (READ_AHEAD * sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE) as f64) as usize,
+            at: 0,
+            backward: false,
+            skip: 0,
+            decoded: VecDeque::new(),
+        };
+        let decoding = thread::spawn({
+            let shared = shared.clone();
+            move || decoder.run(&shared)
+        });
+        Stream {
+            shared,
+            decoding: Some(decoding),
+            channels,
+            block: VecDeque::new(),
+            position: 0,
+            backward: false,
+        }
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Start reading from `frame` in the direction being read in. Returns at once, and decoding
+    /// catches up in the background
+    pub fn seek(&mut self, frame: u64) {
+        self.position = frame;
+        self.block.clear();
+        let mut state = self.shared.state.lock().unwrap();
+        state.request = Request {
+            frame,
+            backward: self.backward,
+        };
+        state.generation += 1;
+        state.blocks.clear();
+        state.buffered = 0;
+        state.done = false;
+        self.shared.changed.notify_all();
+    }
+
+    /// Read as many frames as fit in `buffer`, backward if `backward`, returning the number of
+    /// frames read. Reads fewer only once there's nothing left to read that way
+    pub fn read(
+        &mut self,
+        buffer: &mut [f32],
+        backward: bool,
+    ) -> Result<usize, SourceError<anyhow::Error>> {
+        if backward != self.backward {
+            self.backward = backward;
+            self.seek(self.position);
+        }
+        let channels = self.channels;
+        let frames = buffer.len() / channels;
+        let mut read = 0;
+        while read < frames {
+            if self.block.is_empty() {
+                match self.next_block()? {
+                    Some(block) => self.block = block.into(),
+                    None => break,
+                }
+            }
+            let taken = (frames - read).min(self.block.len() / channels);
+            buffer[read * channels..(read + taken) * channels]
+                .iter_mut()
+                .zip(self.block.drain(..taken * channels))
+                .for_each(|(b, s)| *b = s);
+            read += taken;
+            match backward {
+                true => self.position -= taken as u64,
+                false => self.position += taken as u64,
+            }
+        }
+        Ok(read)
+    }
+
+    /// Wait for the next block decoded, if there's any left
+    fn next_block(&mut self) -> Result<Option<Vec<f32>>, SourceError<anyhow::Error>> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(block) = state.blocks.pop_front() {
+                if let Ok(samples) = &block {
+                    state.buffered -= samples.len() / self.channels;
+                }
+                self.shared.changed.notify_all();
+                return block.map(Some);
+            }
+            if state.done {
+                return Ok(None);
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+        if let Some(decoding) = self.decoding.take() {
+            _ = decoding.join();
+        }
+    }
+}
+
+/// What the decoding thread decodes with, and where it's at
+struct Decoding {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    channels: usize,
+    /// Frames decoded ahead before waiting for them to be read
+    capacity: usize,
+    /// Frame that decoding is at, which decoding backward decodes the block just before
+    at: u64,
+    backward: bool,
+    /// Frames still to be dropped to land exactly where a seek asked for
+    skip: u64,
+    decoded: VecDeque<f32>,
+}
+
+impl Decoding {
+    fn run(mut self, shared: &Shared) {
+        let mut generation = 0;
+        loop {
+            let request = {
+                let mut state = shared.state.lock().unwrap();
+                loop {
+                    if state.closed {
+                        return;
+                    }
+                    if state.generation != generation {
+                        generation = state.generation;
+                        break Some(state.request);
+                    }
+                    if !state.done && state.buffered < self.capacity {
+                        break None;
+                    }
+                    state = shared.changed.wait(state).unwrap();
+                }
+            };
+
+            let block = match request {
+                Some(request) => self.start(request).map(|()| Some(Vec::new())),
+                None if self.backward => self.previous_block(),
+                None => self.next_block(),
+            };
+
+            let mut state = shared.state.lock().unwrap();
+            // Anything decoded for an earlier request is of no use anymore
+            if state.generation != generation {
+                continue;
+            }
+            match block {
+                Ok(Some(samples)) if samples.is_empty() => continue,
+                Ok(Some(samples)) => {
+                    state.buffered += samples.len() / self.channels;
+                    state.blocks.push_back(Ok(samples));
+                }
+                Ok(None) => state.done = true,
+                Err(err) => {
+                    // Packets that can't be decoded are skipped going forward, but anything else
+                    // leaves nowhere to carry on from
+                    state.done =
+                        self.backward || request.is_some() || matches!(err, SourceError::Fatal(_));
+                    state.blocks.push_back(Err(err));
+                }
+            }
+            shared.changed.notify_all();
+        }
+    }
+
+    fn start(&mut self, request: Request) -> Result<(), SourceError<anyhow::Error>> {
+        self.at = request.frame;
+        self.backward = request.backward;
+        // Decoding backward seeks to every block anyway
+        if !self.backward {
+            self.seek(request.frame)?;
+        }
+        Ok(())
+    }
+
+    fn seek(&mut self, frame: u64) -> Result<(), SourceError<anyhow::Error>> {
+        // Timestamps of audio tracks count frames
+        let seeked = self
+            .reader
+            .seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: frame,
+                    track_id: self.track_id,
+                },
+            )
+            .map_err(|err| SourceError::Once(anyhow!("error seeking to frame {frame}: {err}")))?;
+        self.decoder.reset();
+        self.decoded.clear();
+        self.skip = seeked.required_ts.saturating_sub(seeked.actual_ts);
+        Ok(())
+    }
+
+    /// Decode at least a frame on from `at`, unless there are none left
+    fn next_block(&mut self) -> Result<Option<Vec<f32>>, SourceError<anyhow::Error>> {
+        let channels = self.channels;
+        loop {
+            if !decode_next(
+                self.reader.as_mut(),
+                self.decoder.as_mut(),
+                &mut self.decoded,
+            )? {
+                return Ok(None);
+            }
+            let dropped = self.skip.min((self.decoded.len() / channels) as u64);
+            self.decoded.drain(..dropped as usize * channels);
+            self.skip -= dropped;
+            if !self.decoded.is_empty() {
+                self.at += (self.decoded.len() / channels) as u64;
+                return Ok(Some(Vec::from(mem::take(&mut self.decoded))));
+            }
+        }
+    }
+
+    /// Decode the block of frames leading up to `at`, last frame first, unless there are none
+    fn previous_block(&mut self) -> Result<Option<Vec<f32>>, SourceError<anyhow::Error>> {
+        let channels = self.channels;
+        if self.at == 0 {
+            return Ok(None);
+        }
+        let start = self.at.saturating_sub(BACKWARD_BLOCK);
+        self.seek(start)?;
+        let wanted = (self.at - start) as usize * channels;
+        let mut block = Vec::with_capacity(wanted);
+        while block.len() < wanted {
+            match decode_next(
+                self.reader.as_mut(),
+                self.decoder.as_mut(),
+                &mut self.decoded,
+            ) {
+                Ok(true) => (),
+                Ok(false) => break,
+                // A frame of silence in place of what couldn't be decoded
+                Err(SourceError::Once(_)) => self.decoded.extend(std::iter::repeat_n(0., channels)),
+                Err(err) => return Err(err),
+            }
+            let dropped = self.skip.min((self.decoded.len() / channels) as u64);
+            self.decoded.drain(..dropped as usize * channels);
+            self.skip -= dropped;
+            let taken = self.decoded.len().min(wanted - block.len());
+            block.extend(self.decoded.drain(..taken));
+        }
+        // Frames past the end of the file, if it's shorter than it claimed, play as silence
+        block.resize(wanted, 0.);
+        self.at = start;
+        Ok(Some(
+            block
+                .chunks_exact(channels)
+                .rev()
+                .flatten()
+                .copied()
+                .collect(),
+        ))
+    }
+}
@@ -12,6 +12,11 @@ bitrate = 44100 -- bits per second
 loop = Sampler.open './one.wav' --- small file, copy entirely into memory
 -- long   = Sampler.open '/example-long-song.wav'   --- large file, don't copy entirely into memory
 piano = Synth.open './TimGM6mb.sf2'
+--- each channel can start on a preset of its own, by program number, `{ bank, program }` or name
+-- band = Synth.open {
+--   file = './TimGM6mb.sf2',
+--   presets = { 'Acoustic Bass', 24, { preset = { bank = 128, program = 0 }, channel = 10 } },
+-- }
 
 --- `help` can be used on an instrument to print the help/usage written by the instrument author
 help(loop)
@@ -24,25 +29,75 @@ beat:extend {
   [')'] = loop.pause,
   ['['] = { loop[{ seek = "0s" }], loop.resume }, -- seek to start & resume
 }
+beat:swing(0.6) -- every other unit lands 60% of the way into its pair instead of halfway
 beat = beat:parse '(...,...)   (...)   *   [...,...)'
 
 --- `Debug` can help identify plunder's internal types (hidden inside Lua UserData) as well as Lua
 --- types and provide debug information for them
 Debug(beat)
 
+--- Filters sit between an instrument and the mix, every sample of the instrument passes through them
+--- They take events just like instruments do, e.g. `echo[{ mix = 0.5 }]`
+echo = Delay.new { time = 0.375, feedback = 0.4, mix = 0.25 }
+filter(Biquad.lowpass { freq = 2000 }, { piano })
+filter(echo, { piano })
+
+--- `Sampler.kit` maps files to notes, so that `Midi` patterns can play drum kits too
+-- kit = Sampler.kit {
+--   { file = './kick.wav', keys = 'C3' },
+--   { file = './hat1.wav', keys = 'F#3', group = 'hat' }, -- hats in a group take turns
+--   { file = './hat2.wav', keys = 'F#3', group = 'hat' },
+--   { file = './bass.wav', keys = { 'C2', 'B2' }, root = 'E2', velocity = { 64, 127 } },
+-- }
+-- drums = Midi(kit):parse 'C3 F#3 C3 F#3'
+
+--- synth events play chords and overlapping notes, on any of the 16 MIDI channels
+-- chords = {
+--   { 0, piano[{ on = { notes = { 'C4', 'E4', 'G4' }, velocity = 80, duration = 4 } }] },
+--   { 2, piano[{ on = { notes = 'C2', channel = 2 } }] },
+--   { 4, piano[{ off = { notes = 'C2', channel = 2 } }] },
+-- }
+
+--- and switch presets and move controllers as they go
+-- changes = {
+--   { 0, piano[{ program = { preset = 'Strings', channel = 2 } }] },
+--   { 0, piano[{ sustain = true }] },
+--   { 2, piano[{ bend = { value = -0.5, channel = 2 } }] },
+--   { 4, piano[{ pan = 32 }] },
+-- }
+
+--- `Midi(...):file` imports a MIDI file as its tempo, in quarter notes, and a stream of events per track
+-- song = Midi(piano):file './song.mid'
+-- render("song.wav", { piano }, bitrate, song.tempo, "17:1", { walk(song.tracks[1]), walk(song.tracks[2]) })
+
 melody = Midi(piano)
 melody = melody:parse 'A5 C6 E6 C6 F5 A5 C6 A5 C5 E5 G5 E5 G5 B5 D6 B5'
 
+--- patterns also take rests (`-` or `.`), durations (`:2`, `:1/2`), ties (`~`), octaves carried from the
+--- note before and moved with `'` or `,`, chords (`[C4 E G]`), velocities (`!80`) and repeats (`*2`)
+-- progression = Midi(piano):parse [[!90 [A4 C5 E5]:2 ~ - (F4 A C5)*2 [G4 B D']!110:4]]
+
 --- `render` invokes the primary plunder engine on the event stream (see below)
 render(
   "out.wav",
   { piano }, -- list of instruments whose output will be rendered
   bitrate,
-  bitrate / 4,  -- every unit is one-fourth of a second
-  bitrate * 8, -- render 8 seconds of audio
-  { walk(melody) }
+  { bpm = 120, signature = "4/4", unit = "1/8" }, -- every unit is an eighth note, one-fourth of a second
+  "5:1", -- render 4 bars, 8 seconds of audio
+  { walk(melody) },
+  { master = { Reverb.new { room = 0.6, mix = 0.2 }, Gain.db(-3) } }
 )
 
+--- `export` writes the synth events of the same event-streams to a MIDI file, for any DAW to open
+-- export("out.mid", bitrate, { bpm = 120, signature = "4/4", unit = "1/8" }, "5:1", { walk(melody) })
+
+--- `play` takes the same arguments as `render` (minus the path) and plays them as they are rendered
+-- play({ piano }, bitrate, bitrate / 4, bitrate * 8, { walk(melody) }) -- raw sample counts work too
+
+--- rendering to no file returns the audio instead, which `Sampler.audio` resamples without touching disk
+-- bounce = Sampler.audio(render(nil, { piano }, bitrate, { bpm = 120 }, "2:1", { walk(melody) }))
+-- noise = Sampler.audio { 0.5, -0.5, 0.25, -0.25 } -- plain lists of numbers are mono samples
+
 --- the last argument to render just needs to be an iterator of the following format:
 --- you may forego the parser and directly use it in this way
 -- {
@@ -72,9 +127,3 @@ render(
 -- `instrument.seek` returns this value that can be coerced as
 -- `InstrumentAndEvent(instrument, "seek")`, but when it's called once more, i.e.,
 -- `instrument.seek('0s')`, it transforms into a value that can be coerced as `InstrumentAndEvent(instrument, {seek = '0s'})
-
---- TODO Scopes for filters
--- filter('reverb') { sample, bt, kick } --- attaches filters to the respective PackagedInstruments
---- <or>
--- reverb_group = { sample, bt, kick }
--- filter('reverb', reverb_group)
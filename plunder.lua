@@ -22,17 +22,103 @@ end
 ---@alias event_stream_iter [fun(table: V[], i?: integer):integer, V, T, integer]
 
 ---
----Render the given set of `instruments` with the given `event-stream iterator` by spacing each unit with `interval` no. of samples and stopping after `duration` no. of samples. Write as .wav to `path`
+---Where units, bars and beats of event-streams fall in time
+--- - `bpm`: beats per minute at the start
+--- - `signature`: time signature at the start, e.g. `"6/8"` (default: `"4/4"`)
+--- - `unit`: the note value an integer event position counts, e.g. `"1/16"` (default: a beat of `signature`)
+--- - `changes`: list of tempo and signature changes, in order, each `at` a number of units or a `"bar:beat:tick"` position (bars and beats count from 1, there are 960 ticks to a quarter note)
+---   - `bpm`: the new tempo
+---   - `ramp`: ramp the tempo linearly from the previous change, arriving at `bpm` at `at`, instead of jumping to it
+---   - `signature`: the new time signature, only at the start of a bar
+---@alias tempo { bpm: number, signature?: string, unit?: string, changes?: { at: integer|string, bpm?: number, ramp?: boolean, signature?: string }[] }
+
+---
+---Render the given set of `instruments` with the given `event-stream iterator` by spacing each unit with `interval` no. of samples and stopping after `duration` no. of samples. Write to `path`
+---
+---`interval` can instead be a `tempo` table, e.g. `{ bpm = 120, signature = "4/4" }`, and `duration` a `"bar:beat:tick"` position to stop at. Event positions may be a number of units, fractional ones landing between units, a `"bar:beat:tick"` position or `{ sample = n }` to emit at exactly the `n`th sample
+---
+---The optional `options` table picks the encoder and shapes the mix:
+--- - `format`: `"wav"` or `"flac"` (default: guessed from the extension of `path`, else `"wav"`)
+--- - `bits`: bits per sample (default: 32 for wav, 24 for flac)
+--- - `sample_format`: `"int"` or `"float"` (default: `"int"`, only wav can store `"float"`)
+--- - `channels`: number of channels of the output, every instrument is up/down-mixed to it (default: 2)
+--- - `pan_law`: decibels a mono instrument is attenuated by in each side of a stereo output (default: 3)
+--- - `headroom`: decibels the sum of all instruments is attenuated by (default: 0)
+--- - `clip`: `"soft"` to saturate smoothly or `"limit"` to duck the output just below full-scale (default: none, samples beyond full-scale are clipped hard)
+--- - `release`: seconds the `"limit"`er takes to recover (default: 0.05)
+--- - `resampler`: `"sinc"` or `"linear"`, how instruments at a different sample-rate than `bitrate` are resampled (default: `"sinc"`)
+--- - `master`: list of filters the mixed-down output passes through, in order
+--- - `block`: render instruments this many frames at a time, in parallel, instead of one sample at a time. The output is the same either way (default: none)
+--- - `threads`: number of threads `block`s are rendered on (default: one per core)
 ---
 ---@generic T: table, V
 ---@param path string
 ---@param instruments table
 ---@param bitrate integer
----@param interval integer
----@param duration integer
+---@param interval integer|tempo
+---@param duration integer|string
+---@param event_streams table<any, event_stream_iter>
+---@param options? { format?: "wav"|"flac", bits?: integer, sample_format?: "int"|"float", channels?: integer, pan_law?: number, headroom?: number, clip?: "soft"|"limit", release?: number, resampler?: "sinc"|"linear", master?: table, block?: integer, threads?: integer }
+plunder.render  = function(path, instruments, bitrate, interval, duration, event_streams, options)
+  return libplunder.render(path, instruments, bitrate, interval, duration, event_streams, options)
+end
+
+---
+---Play the given set of `instruments` with the given `event-stream iterator` while they are being rendered, exactly like `render` does but without writing to a file. Returns the number of frames played
+---
+---The optional `options` table picks the audio sink and shapes the mix:
+--- - `backend`: `"device"` for the system's default output device (needs plunder built with the `device` feature) or `"null"` to discard the audio (default: `"device"` when built with the `device` feature, `"null"` otherwise)
+--- - `realtime`: pace the `"null"` backend as if it were a device
+--- - `latency`: seconds of audio that may be queued ahead of the device (default: 0.1)
+--- - `channels`: number of channels of the output, every instrument is up/down-mixed to it (default: 2)
+--- - `pan_law`: decibels a mono instrument is attenuated by in each side of a stereo output (default: 3)
+--- - `headroom`: decibels the sum of all instruments is attenuated by (default: 0)
+--- - `clip`: `"soft"` to saturate smoothly or `"limit"` to duck the output just below full-scale (default: none, samples beyond full-scale are clipped hard)
+--- - `release`: seconds the `"limit"`er takes to recover (default: 0.05)
+--- - `resampler`: `"sinc"` or `"linear"`, how instruments at a different sample-rate than `bitrate` are resampled (default: `"sinc"`)
+--- - `master`: list of filters the mixed-down output passes through, in order
+---
+---@generic T: table, V
+---@param instruments table
+---@param bitrate integer
+---@param interval integer|tempo
+---@param duration integer|string
 ---@param event_streams table<any, event_stream_iter>
-plunder.render  = function(path, instruments, bitrate, interval, duration, event_streams)
-  return libplunder.render(path, instruments, bitrate, interval, duration, event_streams)
+---@param options? { backend?: "device"|"null", realtime?: boolean, latency?: number, channels?: integer, pan_law?: number, headroom?: number, clip?: "soft"|"limit", release?: number, resampler?: "sinc"|"linear", master?: table }
+---@return integer
+plunder.play    = function(instruments, bitrate, interval, duration, event_streams, options)
+  return libplunder.play(instruments, bitrate, interval, duration, event_streams, options)
+end
+
+---
+---Write the events of `Synth`s in the given `event-stream iterator` to the MIDI file at `path`, with a track for every synth after one for the tempo. Takes the same arguments as `render` minus the instruments and options, and events of other instruments are left out
+---
+---@generic T: table, V
+---@param path string
+---@param bitrate integer
+---@param interval integer|tempo
+---@param duration integer|string
+---@param event_streams table<any, event_stream_iter>
+plunder.export  = function(path, bitrate, interval, duration, event_streams)
+  libplunder.export(path, bitrate, interval, duration, event_streams)
+end
+
+---
+---Attach `filter` to the end of the filter-chain of every one of `instruments`. Every instrument gets its own instance of the filter, events on `filter` reach all of them. Once an instrument has finished, its filters keep ringing out (think delay & reverb tails) until they've decayed. Filters like `Pan` up-mix mono instruments to stereo, at the `pan_law` of the `render` or `play` (default: 3)
+---
+---@param filter userdata
+---@param instruments table
+plunder.filter  = function(filter, instruments)
+  libplunder.filter(filter, instruments)
+end
+
+---
+---Files imported by `Sampler`s are decoded once and shared by every sampler importing them, until the file changes, and freed once no sampler plays them anymore. With no `action`, list every file in the cache with its `path`, `frames`, `channels`, `sample_rate`, `bytes` and no. of `samplers` playing it. With `action` `"clear"`, forget every file and return how many there were, samplers playing them keep their samples but the next import decodes them anew
+---
+---@param action? "clear"
+---@return { path: string, frames: integer, channels: integer, sample_rate?: integer, bytes: integer, samplers: integer }[]|integer
+plunder.cache   = function(action)
+  return libplunder.cache(action)
 end
 
 plunder.walk    = function(value)
@@ -49,6 +135,11 @@ plunder.Sampler = libplunder.Sampler
 plunder.Parser  = libplunder.Parser
 plunder.Synth   = libplunder.Synth
 plunder.Midi    = libplunder.Midi
+plunder.Gain    = libplunder.Gain
+plunder.Pan     = libplunder.Pan
+plunder.Biquad  = libplunder.Biquad
+plunder.Delay   = libplunder.Delay
+plunder.Reverb  = libplunder.Reverb
 
 ---
 ---Add all plunder items to the global scope
@@ -56,11 +147,22 @@ plunder.Midi    = libplunder.Midi
 plunder.global  = function()
   -- core
   _G.render = plunder.render
+  _G.play = plunder.play
+  _G.export = plunder.export
+  _G.filter = plunder.filter
+  _G.cache = plunder.cache
 
   -- instruments
   _G.Sampler = plunder.Sampler
   _G.Synth = plunder.Synth
 
+  -- filters
+  _G.Gain = plunder.Gain
+  _G.Pan = plunder.Pan
+  _G.Biquad = plunder.Biquad
+  _G.Delay = plunder.Delay
+  _G.Reverb = plunder.Reverb
+
   -- parsers
   _G.Parser = plunder.Parser
   _G.Midi = plunder.Midi
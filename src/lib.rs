@@ -1,17 +1,27 @@
 use std::{
+    any::Any,
     cmp::Ordering,
     iter::{Map, Peekable},
+    path::Path,
+    sync::Arc,
 };
 
+use filters::{Biquad, Delay, Gain, Pan, Reverb};
 use itertools::Itertools;
-use libplunder::prelude::instrument::*;
+use libplunder::{
+    prelude::{filter::*, instrument::*},
+    tempo::{At, Position, TempoMap, Tick},
+};
 use log::{info, warn};
-use midi::{MidiParser, Synth};
-use mlua::prelude::*;
+use midi::{Export, MidiParser, Synth};
+use mlua::{prelude::*, serde::de::Options as DeserializeOptions};
 use parser1::Parser;
-use render::EventStreamPair;
-use sampler::Sampler;
+use play::PlayOptions;
+use render::{EventStreamPair, RenderOptions};
+use sampler::{AudioControls, Sampler, SamplerArguments};
+use serde::de::DeserializeOwned;
 
+mod play;
 mod render;
 
 #[mlua::lua_module]
@@ -19,23 +29,38 @@ fn libplunder(lua: &Lua) -> LuaResult<LuaTable> {
     env_logger::init();
 
     let exports = lua.create_table()?;
-    exports.set(
-        "Debug",
-        lua.create_function(|lua, value| debug(lua, value))?,
-    )?;
+    exports.set("Debug", lua.create_function(debug)?)?;
 
     exports.set("help", lua.create_function(help)?)?;
 
     exports.set("Sampler", Sampler::package(lua)?)?;
 
+    exports.set("cache", lua.create_function(cache)?)?;
+
     exports.set("render", lua.create_function(render)?)?;
 
+    exports.set("play", lua.create_function(play)?)?;
+
+    exports.set("export", lua.create_function(export)?)?;
+
     exports.set("Parser", lua.create_function(|_, _: ()| Ok(Parser::new()))?)?;
 
     exports.set("Synth", Synth::package(lua)?)?;
 
     exports.set("Midi", lua.create_function(midi_parser)?)?;
 
+    exports.set("Gain", Gain::package(lua)?)?;
+
+    exports.set("Pan", Pan::package(lua)?)?;
+
+    exports.set("Biquad", Biquad::package(lua)?)?;
+
+    exports.set("Delay", Delay::package(lua)?)?;
+
+    exports.set("Reverb", Reverb::package(lua)?)?;
+
+    exports.set("filter", lua.create_function(filter)?)?;
+
     Ok(exports)
 }
 
@@ -46,8 +71,70 @@ pub fn midi_parser(lua: &Lua, instrument: LuaValue) -> LuaResult<MidiParser> {
     {
         warn!("passed value to Midi is not Userdata containing PackagedInstrument");
     }
-    Ok(MidiParser::new(
-        LuaUserDataRef::<PackagedInstrument>::from_lua(instrument, lua)?.clone(),
+    let instrument = LuaUserDataRef::<PackagedInstrument>::from_lua(instrument, lua)?.clone();
+    // Kits of the sampler play notes too
+    let any: Arc<dyn Any + Send + Sync> = instrument.factory.0.clone();
+    if any.is::<ToPlunderInstrument<SamplerArguments, AudioControls, Sampler>>() {
+        return Ok(MidiParser::with_events::<
+            Sampler,
+            SamplerArguments,
+            AudioControls,
+        >(instrument));
+    }
+    Ok(MidiParser::new(instrument))
+}
+
+/// The files imported by samplers, each decoded once for all of them, or `"clear"` to forget them
+pub fn cache(lua: &Lua, action: Option<String>) -> LuaResult<LuaValue> {
+    match action.as_deref() {
+        None => lua.to_value(&sampler::cache::list()),
+        Some("clear") => Ok(LuaValue::Integer(sampler::cache::clear() as i64)),
+        Some(action) => Err(LuaError::runtime(format!(
+            "invalid cache action `{action}`. available: `clear`"
+        ))),
+    }
+}
+
+/// Attach a filter to the end of the filter-chain of every given instrument
+pub fn filter(
+    _: &Lua,
+    (filter, instruments): (
+        LuaUserDataRef<PackagedFilter>,
+        Vec<LuaUserDataRef<PackagedInstrument>>,
+    ),
+) -> LuaResult<()> {
+    instruments.iter().try_for_each(|instrument| {
+        instrument
+            .filters
+            .push(filter.attach().map_err(LuaError::runtime)?);
+        Ok(())
+    })
+}
+
+/// Deserialize the options table of `render` & `play`, splitting out the filters of its `master`
+/// field that are attached to the mixed-down output
+fn options_and_master<T>(lua: &Lua, options: Option<LuaValue>) -> LuaResult<(T, FilterChain)>
+where
+    T: DeserializeOwned + Default,
+{
+    let Some(options) = options else {
+        return Ok((T::default(), FilterChain::default()));
+    };
+    let master = match options.as_table() {
+        Some(table) => table
+            .get::<Option<Vec<LuaUserDataRef<PackagedFilter>>>>("master")?
+            .unwrap_or_default()
+            .iter()
+            .map(|filter| filter.attach().map_err(LuaError::runtime))
+            .collect::<LuaResult<_>>()?,
+        None => Vec::new(),
+    };
+    Ok((
+        lua.from_value_with(
+            options,
+            DeserializeOptions::new().deny_unsupported_types(false),
+        )?,
+        FilterChain::new(master),
     ))
 }
 
@@ -113,29 +200,31 @@ pub fn debug(lua: &Lua, value: LuaValue) -> LuaResult<String> {
 /// An iterator that takes a collection of iterators assumed to be sorted and a fallible function to
 /// order their items, and yields another iterator that returns the items from the two iterators sorted **in
 /// ascending-order**
-pub struct SortIterator<I, E>
+pub struct SortIterator<I, F>
 where
     I: Iterator,
 {
     its: Vec<Peekable<I>>,
     // a: Peekable<I>,
     // b: Peekable<I>,
-    cmp: fn(&I::Item, &I::Item) -> Result<Ordering, E>,
+    cmp: F,
 }
 
-impl<I, E> SortIterator<I, E>
+impl<I, E, F> SortIterator<I, F>
 where
     I: Iterator,
+    F: Fn(&I::Item, &I::Item) -> Result<Ordering, E>,
 {
-    pub fn new(its: Vec<I>, cmp: fn(&I::Item, &I::Item) -> Result<Ordering, E>) -> Self {
+    pub fn new(its: Vec<I>, cmp: F) -> Self {
         let its = its.into_iter().map(I::peekable).collect();
         SortIterator { its, cmp }
     }
 }
 
-impl<I, E> Iterator for SortIterator<I, E>
+impl<I, E, F> Iterator for SortIterator<I, F>
 where
     I: Iterator,
+    F: Fn(&I::Item, &I::Item) -> Result<Ordering, E>,
 {
     type Item = Result<I::Item, E>;
 
@@ -213,21 +302,16 @@ impl Iterator for LuaIterator {
 //     Err(None)
 // }
 
-pub fn render(
-    _lua: &Lua,
-    (path, instruments, bitrate, interval, sample_bound, event_streams): (
-        String,
-        Vec<LuaUserDataRef<PackagedInstrument>>,
-        u32,
-        usize,
-        usize,
-        LuaTable,
-        // (LuaFunction, LuaValue, LuaValue),
-    ),
-) -> LuaResult<()> {
+/// Merge the event-streams of a Lua table into a single stream sorted by position, and hand it to
+/// `f`
+fn with_sorted_event_stream<R>(
+    event_streams: LuaTable,
+    tempo: &TempoMap,
+    f: impl FnOnce(&mut dyn Iterator<Item = EventStreamPair>) -> anyhow::Result<R>,
+) -> LuaResult<R> {
     use std::ops::Deref;
 
-    // Collection of event-streams, each of which yields LuaResult<(usize, EmittableUserData)>
+    // Collection of event-streams, each of which yields LuaResult<(Position, EmittableUserData)>
     let valid_event_streams: Vec<_> = event_streams
         .pairs::<LuaValue, LuaTable>()
         .map(|pair| -> LuaResult<_> {
@@ -243,9 +327,9 @@ pub fn render(
                             "expected event-stream to be an iterator \
                             that yields events, which are tables",
                         ))?;
-                        let idx = table.get(1)?;
+                        let idx = position(tempo, table.get(1)?)?;
                         let event: LuaUserDataRef<EmittableUserData> = table.get(2)?;
-                        info!("Popped next even in sorted event stream with id: `{idx}`");
+                        info!("Popped next even in sorted event stream at: `{idx:?}`");
                         Ok((idx, event.deref().clone()))
                     },
                 ),
@@ -253,29 +337,145 @@ pub fn render(
         })
         .collect::<Result<Vec<Map<_, _>>, _>>()?;
 
+    // Positions in ticks and in samples only compare once they're both in samples
     SortIterator::new(valid_event_streams, |event_a, event_b| -> LuaResult<_> {
-        Ok(event_a
-            .as_ref()
-            .map_err(LuaError::clone)?
-            .0
-            .cmp(&event_b.as_ref().map_err(LuaError::clone)?.0))
+        let sample_at = |event: &LuaResult<EventStreamPair>| -> LuaResult<_> {
+            Ok(tempo.sample_at(event.as_ref().map_err(LuaError::clone)?.0))
+        };
+        Ok(sample_at(event_a)?.cmp(&sample_at(event_b)?))
     })
     .map(|next| match next {
         Ok(next) => next,
         Err(e) => Err(e),
     })
-    .process_results(|sorted_event_stream| {
+    .process_results(|mut sorted_event_stream| {
+        f(&mut sorted_event_stream).map_err(|err| LuaError::runtime(format!("{err:#}")))
+    })?
+}
+
+/// Position in an event-stream: a number of units, a `bar:beat:tick` string or `{ sample = n }`
+fn position(tempo: &TempoMap, position: LuaValue) -> LuaResult<Position> {
+    let at = lua_from_value::<At>(position)?;
+    tempo
+        .position(&at)
+        .map_err(|err| LuaError::runtime(format!("invalid position `{at:?}`: {err:#}")))
+}
+
+fn lua_from_value<T: DeserializeOwned>(value: LuaValue) -> LuaResult<T> {
+    T::deserialize(mlua::serde::Deserializer::new(value))
+}
+
+/// Either the number of samples in a unit, or a table describing a [`TempoMap`](TempoMap)
+fn tempo_map(lua: &Lua, tempo: LuaValue, bitrate: u32) -> LuaResult<TempoMap> {
+    match tempo {
+        LuaValue::Table(_) => TempoMap::new(&lua.from_value(tempo)?, bitrate),
+        interval => TempoMap::from_interval(usize::from_lua(interval, lua)?, bitrate),
+    }
+    .map_err(|err| LuaError::runtime(format!("invalid tempo: {err:#}")))
+}
+
+/// Either a number of samples, or a `bar:beat:tick` position to stop at
+fn sample_bound(lua: &Lua, tempo: &TempoMap, bound: LuaValue) -> LuaResult<usize> {
+    match bound {
+        LuaValue::String(_) => Ok(tempo.sample_at(position(tempo, bound)?)),
+        samples => usize::from_lua(samples, lua),
+    }
+}
+
+/// Arguments of `render`: the file rendered to, or none to render to memory, then the same as
+/// those of `play`
+type RenderArguments = (
+    Option<String>,
+    Vec<LuaUserDataRef<PackagedInstrument>>,
+    u32,
+    LuaValue,
+    LuaValue,
+    LuaTable,
+    // (LuaFunction, LuaValue, LuaValue),
+    Option<LuaValue>,
+);
+
+pub fn render(
+    lua: &Lua,
+    (path, instruments, bitrate, tempo, sample_bound, event_streams, options): RenderArguments,
+) -> LuaResult<LuaValue> {
+    let options = options_and_master::<RenderOptions>(lua, options)?;
+    let tempo = tempo_map(lua, tempo, bitrate)?;
+    let sample_bound = self::sample_bound(lua, &tempo, sample_bound)?;
+
+    let rendered = with_sorted_event_stream(event_streams, &tempo, |sorted_event_stream| {
         render::render_single_event_stream(
             path,
             instruments,
             sorted_event_stream,
             bitrate,
-            interval,
+            tempo.clone(),
+            sample_bound,
+            options,
+        )
+    })?;
+    // Rendered to memory when there's no file to render to, ready for `Sampler.audio`
+    lua.to_value(&rendered)
+}
+
+pub fn play(
+    lua: &Lua,
+    (instruments, bitrate, tempo, sample_bound, event_streams, options): (
+        Vec<LuaUserDataRef<PackagedInstrument>>,
+        u32,
+        LuaValue,
+        LuaValue,
+        LuaTable,
+        Option<LuaValue>,
+    ),
+) -> LuaResult<usize> {
+    let options = options_and_master::<PlayOptions>(lua, options)?;
+    let tempo = tempo_map(lua, tempo, bitrate)?;
+    let sample_bound = self::sample_bound(lua, &tempo, sample_bound)?;
+
+    with_sorted_event_stream(event_streams, &tempo, |sorted_event_stream| {
+        play::play_single_event_stream(
+            instruments,
+            sorted_event_stream,
+            bitrate,
+            tempo.clone(),
             sample_bound,
+            options,
         )
     })
 }
 
+/// Write the synth events of the event-streams to a MIDI file, taking the same arguments as
+/// `render` minus the instruments and options
+pub fn export(
+    lua: &Lua,
+    (path, bitrate, tempo, sample_bound, event_streams): (
+        String,
+        u32,
+        LuaValue,
+        LuaValue,
+        LuaTable,
+    ),
+) -> LuaResult<()> {
+    let tempo = tempo_map(lua, tempo, bitrate)?;
+    let sample_bound = self::sample_bound(lua, &tempo, sample_bound)?;
+    let end = tempo.ticks_at(Position::Sample(sample_bound)).round() as Tick;
+
+    let smf = with_sorted_event_stream(event_streams, &tempo, |sorted_event_stream| {
+        let mut export = Export::new(tempo.unit());
+        for (position, event) in sorted_event_stream {
+            if tempo.sample_at(position) >= sample_bound {
+                break;
+            }
+            let tick = (tempo.ticks_at(position).round() as Tick).min(end);
+            export.add(tick, &event)?;
+        }
+        export.finish(&tempo, end)
+    })?;
+    smf.save(Path::new(&path))
+        .map_err(|err| LuaError::runtime(format!("error writing `{path}`: {err:#}")))
+}
+
 pub fn help(lua: &Lua, value: LuaValue) -> LuaResult<()> {
     // Packaged Instrument
     if let Ok(packaged_instrument) =
@@ -284,6 +484,13 @@ pub fn help(lua: &Lua, value: LuaValue) -> LuaResult<()> {
         println!("Instrument: {}", packaged_instrument);
         println!("Manual: {}", packaged_instrument.manual);
     }
+    // Packaged Filter
+    else if let Ok(packaged_filter) =
+        LuaUserDataRefMut::<PackagedFilter>::from_lua(value.clone(), lua)
+    {
+        println!("Filter: {}", packaged_filter);
+        println!("Manual: {}", packaged_filter.manual);
+    }
     // Instrument & Event
     else if let Ok(instrument_and_event) =
         LuaUserDataRefMut::<EmittableUserData>::from_lua(value.clone(), lua)
@@ -291,11 +498,7 @@ pub fn help(lua: &Lua, value: LuaValue) -> LuaResult<()> {
         println!("Event from Instrument: {}", (*instrument_and_event).help());
     }
     // TODO Packaged Parser
-    else if false {
-    }
     // TODO Packaged Parser Factory
-    else if false {
-    }
     // Any other value (includes Packaged Instrument Factory)
     else {
         println!("Value: {}", value.to_string()?);
@@ -0,0 +1,68 @@
+use log::info;
+use mlua::prelude::*;
+use serde::Deserialize;
+
+use libplunder::{
+    encoder::EncoderSpec,
+    mixdown::Mixdown,
+    mixer::MixerOptions,
+    player::{self, PlayerOptions},
+    prelude::{filter::FilterChain, instrument::*},
+    tempo::TempoMap,
+};
+
+use crate::render::EventStreamPair;
+
+/// Optional last argument of `play`, except for the `master` filter-chain which can't be
+/// deserialized
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PlayOptions {
+    #[serde(flatten)]
+    pub player: PlayerOptions,
+    #[serde(flatten)]
+    pub mixer: MixerOptions,
+}
+
+/// Play the engine's output to an audio sink while it is being rendered, returning the number of
+/// frames played
+pub fn play_single_event_stream<I>(
+    instruments: Vec<LuaUserDataRef<PackagedInstrument>>,
+    sorted_event_stream: I,
+    bitrate: u32,
+    tempo: TempoMap,
+    sample_bound: usize,
+    (options, master): (PlayOptions, FilterChain),
+) -> anyhow::Result<usize>
+where
+    I: Iterator<Item = EventStreamPair>,
+{
+    let mut mixdown = Mixdown::new(
+        instruments
+            .iter()
+            .map(|instrument| (*instrument).clone())
+            .collect(),
+        (master, &options.mixer),
+        sorted_event_stream,
+        bitrate,
+        tempo,
+        sample_bound,
+    )?;
+
+    let mut sink = player::open(
+        EncoderSpec {
+            channels: mixdown.num_channels() as u16,
+            sample_rate: bitrate,
+        },
+        &options.player,
+    )?;
+    let mut frames = 0;
+    mixdown.by_ref().try_for_each(|frame| {
+        frames += 1;
+        sink.write_frame(&frame?)
+    })?;
+    sink.drain()?;
+
+    info!("played `{frames}` frames, hash: {}", mixdown.hash());
+    Ok(frames)
+}
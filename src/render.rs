@@ -1,90 +1,94 @@
-use std::hash::DefaultHasher;
-
-use anyhow::Context;
-use log::{info, trace};
+use log::info;
 use mlua::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use libplunder::{
+    encoder::{self, EncoderOptions, EncoderSpec},
+    mixdown::Mixdown,
+    mixer::MixerOptions,
+    prelude::{filter::FilterChain, instrument::*},
+    tempo::{Position, TempoMap},
+};
+
+pub type EventStreamPair = (Position, EmittableUserData);
 
-use libplunder::{combine_i32, prelude::instrument::*, Engine};
+/// Optional last argument of `render`, except for the `master` filter-chain which can't be
+/// deserialized
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RenderOptions {
+    #[serde(flatten)]
+    pub encoder: EncoderOptions,
+    #[serde(flatten)]
+    pub mixer: MixerOptions,
+    /// Render instruments this many frames at a time in parallel instead of one sample at a time
+    pub block: Option<usize>,
+    /// Threads that blocks are rendered on, defaults to one per core
+    pub threads: Option<usize>,
+}
 
-pub type EventStreamPair = (usize, EmittableUserData);
+/// Audio rendered to memory instead of to a file, in the shape that `Sampler.audio` takes
+#[derive(Debug, Serialize)]
+pub struct Rendered {
+    /// Interleaved samples of every frame
+    pub samples: Vec<f32>,
+    pub channels: usize,
+    pub sample_rate: u32,
+}
 
+/// Render to the file at `path`, or to memory if there's none
 pub fn render_single_event_stream<I>(
-    path: String,
+    path: Option<String>,
     instruments: Vec<LuaUserDataRef<PackagedInstrument>>,
     sorted_event_stream: I,
     bitrate: u32,
-    interval: usize,
+    tempo: TempoMap,
     sample_bound: usize,
-) where
+    (options, master): (RenderOptions, FilterChain),
+) -> anyhow::Result<Option<Rendered>>
+where
     I: Iterator<Item = EventStreamPair>,
 {
-    use std::hash::{Hash, Hasher};
-
-    let engine = Engine::new(
+    let mut mixdown = Mixdown::new(
         instruments
             .iter()
             .map(|instrument| (*instrument).clone())
             .collect(),
+        (master, &options.mixer),
         sorted_event_stream,
-        interval,
+        bitrate,
+        tempo,
         sample_bound,
-    );
+    )?;
+    if let Some(frames) = options.block {
+        mixdown = mixdown.in_blocks(frames, options.threads)?;
+    }
 
-    let mut hasher = DefaultHasher::new();
-    let mut samples = engine.map(|i| match i {
-        Ok(i) => match combine_i32(&i) {
-            Ok(Some(s)) => {
-                // for si in &s {
-                //     print!("{si} ");
-                // }
-                // println!();
-                trace!("combined samples into `{s:?}`");
-                s.hash(&mut hasher);
-                Some(Ok(s))
-            }
-            Ok(None) => None,
-            Err(err) => Some(Err(err)),
-        },
-        Err(err) => Some(Err(anyhow::anyhow!("engine error: {err}"))),
-    });
-
-    let first_sample = samples
-        .next()
-        .unwrap()
-        // We unwrap the Option that combine_i32 returns because we will have no information about
-        // the number of channels if the first sample is empty
-        .unwrap()
-        .unwrap();
-
-    let num_channels = first_sample.len();
-    println!("num of channels: `{num_channels}`");
-    let spec = hound::WavSpec {
-        channels: num_channels as u16,
-        sample_rate: bitrate,
-        bits_per_sample: 32,
-        sample_format: hound::SampleFormat::Int,
+    let Some(path) = path else {
+        let mut samples = Vec::new();
+        for frame in mixdown.by_ref() {
+            samples.extend(frame?.into_iter().map(|s| s as f32));
+        }
+        info!("hash: {}", mixdown.hash());
+        return Ok(Some(Rendered {
+            samples,
+            channels: mixdown.num_channels(),
+            sample_rate: bitrate,
+        }));
     };
+    let mut encoder = encoder::create(
+        path,
+        EncoderSpec {
+            channels: mixdown.num_channels() as u16,
+            sample_rate: bitrate,
+        },
+        &options.encoder,
+    )?;
+    mixdown
+        .by_ref()
+        .try_for_each(|frame| encoder.write_frame(&frame?))?;
+    encoder.finalize()?;
 
-    let mut writer = hound::WavWriter::create(path, spec).unwrap();
-    first_sample
-        .iter()
-        .map(|s| writer.write_sample(*s))
-        .collect::<Result<(), _>>()
-        .unwrap();
-    samples
-        .map(|s| {
-            s.transpose()?
-                // because we received the number of channels from that first-sample, we can replace future empty samples with a collection of empty samples in each channel
-                .unwrap_or(vec![0; num_channels])
-                .iter()
-                .map(|s| writer.write_sample(*s))
-                .collect::<Result<(), _>>()
-                .context("wav write error")
-        })
-        .collect::<Result<(), _>>()
-        .unwrap();
-
-    writer.finalize().unwrap();
-
-    info!("hash: {}", hasher.finish());
+    info!("hash: {}", mixdown.hash());
+    Ok(None)
 }